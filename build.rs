@@ -1,4 +1,10 @@
 fn main() {
+    git_hash();
+    // Host builds only run the unit tests, with the host's own linker setup
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("none") {
+        return;
+    }
+
     linker_be_nice();
     // Add defmt linker script
     println!("cargo:rustc-link-arg=-Tdefmt.x");
@@ -6,6 +12,32 @@ fn main() {
     println!("cargo:rustc-link-arg=-Tlinkall.x");
 }
 
+/// Exposes the short hash of the commit being built as `GIT_HASH`, part of the
+/// firmware version reported to Home Assistant.
+fn git_hash() {
+    let hash = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=GIT_HASH={}", hash);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    // A commit on the current branch only touches the ref HEAD points to, and
+    // `git gc` moves refs into `packed-refs`.
+    println!("cargo:rerun-if-changed=.git/packed-refs");
+    if let Some(reference) = std::fs::read_to_string(".git/HEAD")
+        .ok()
+        .and_then(|head| head.strip_prefix("ref: ").map(|r| r.trim().to_string()))
+    {
+        println!("cargo:rerun-if-changed=.git/{}", reference);
+    }
+}
+
 fn linker_be_nice() {
     let args: Vec<String> = std::env::args().collect();
     if args.len() > 1 {
@@ -7,24 +7,53 @@
 )]
 
 use defmt_rtt as _;
-use log::info;
+use log::{info, warn};
 
-use static_cell::StaticCell;
+use heapless::Vec;
+use static_cell::{ConstStaticCell, StaticCell};
 
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, select3, select4, Either, Either3, Either4};
 use embassy_net::{Stack, StackResources};
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 
 use esp_backtrace as _;
 use esp_hal::clock::CpuClock;
+use esp_hal::efuse::Efuse;
+use esp_hal::gpio::{AnyPin, Level, Output, OutputConfig};
+use esp_hal::pcnt::Pcnt;
 use esp_hal::timer::timg::TimerGroup;
 
-use watering_system::home_assistant::{HomeAssistantFacade, HomeAssistantFacadeConfig};
-use watering_system::mdns::MdnsFacade;
-use watering_system::mqtt::{MqttFacade, MqttFacadeConfig};
-use watering_system::pump::PumpFacade;
-use watering_system::sensors::{SensorsFacade, SensorsValues};
+use watering_system::clock::{self, TimeZoneConfig};
+use watering_system::controller::{
+    self, ControllerAction, ControllerCall, ControllerReply, ControllerRequest, ControllerSetting,
+    ControllerSettingError, ControllerSettings, WateringController, CONTROLLER_REPLIES,
+    CONTROLLER_REQUESTS, SOIL_MOISTURE,
+};
+use watering_system::diagnostics::{self, DeviceDiagnostics};
+use watering_system::flow::{FlowMeter, FlowMeterConfig, FlowRateMeter, FlowSensorFacade};
+use watering_system::home_assistant::{
+    DeviceButton, DeviceHardware, HomeAssistantFacade, HomeAssistantFacadeConfig,
+    MAX_DISCOVERY_DOCUMENT, SENSORS_STATE_REQUESTED, ZONES_STATE_REQUESTED,
+};
+use watering_system::logs;
+use watering_system::mqtt::{MqttBrokerConfig, MqttCredentials, MqttFacade, MqttFacadeConfig};
+#[cfg(feature = "tls")]
+use watering_system::mqtt::MqttTlsConfig;
+use watering_system::output::OutputPolarity;
+use watering_system::pump::{self, PumpBudgetConfig, PumpCommand, PumpFacade, PumpStopReason};
+use watering_system::rpc::{RpcFacade, RpcFacadeConfig};
+use watering_system::schedule::{ScheduleEngine, ScheduleEntry, ScheduleTable, MAX_SCHEDULES};
+use watering_system::sensors::{
+    self, CalibrationPoint, SensorsFacade, SensorsValues, TankLevelConfig, TankLevelSensor,
+};
+use watering_system::sntp::{SntpFacade, SntpFacadeConfig};
+use watering_system::storage::{StorageFacade, StorageSlot};
 use watering_system::wifi::{WiFiFacade, WiFiFacadeConfig};
+use watering_system::zones::{
+    Valve, ZoneEvent, ZoneManager, ZoneManagerConfig, ZoneRequest, ZoneState, MAX_ZONES,
+    STOP_ALL_ZONES, ZONE_REQUESTS,
+};
 
 extern crate alloc;
 
@@ -35,12 +64,23 @@ esp_bootloader_esp_idf::esp_app_desc!();
 static WIFI_INIT: StaticCell<esp_wifi::EspWifiController> = StaticCell::new();
 static RESOURCES: StaticCell<StackResources<5>> = StaticCell::new();
 static NET_STACK: StaticCell<Stack<'static>> = StaticCell::new();
+static DISCOVERY_DOCUMENT: ConstStaticCell<[u8; MAX_DISCOVERY_DOCUMENT]> =
+    ConstStaticCell::new([0; MAX_DISCOVERY_DOCUMENT]);
+
+/// Longest random delay before answering Home Assistant coming online.
+const BIRTH_MAX_DELAY_MS: u32 = 5000;
+/// Period at which the zones state is published while a zone runs, so the
+/// remaining run time counts down.
+const ZONE_STATE_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Output driving the pump and valve relays.
+type Relay = Output<'static>;
 
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) {
     // generator version: 0.5.0
 
-    esp_println::logger::init_logger_from_env();
+    logs::init_logger_from_env();
 
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
@@ -72,8 +112,6 @@ async fn main(spawner: Spawner) {
         esp_alloc::HEAP.used()
     );
 
-    let mdns = MdnsFacade::new();
-
     info!("Wifi and MQTT facades initialized. Connecting to Wifi..");
     wifi_facade
         .connect()
@@ -81,48 +119,204 @@ async fn main(spawner: Spawner) {
         .expect("Failed to connect to WiFi");
     spawner.spawn(net_task(_runner)).unwrap();
 
-    info!("Wifi connected! Fetching broker using mDNS...");
-    let (ip, port) = mdns.query_service(env!("MQTT_SERVICE"), stack).await;
-    info!("Got IP: {} and Port: {}", ip, port);
+    info!("Wifi connected!");
 
-    let home_assistant_config = HomeAssistantFacadeConfig::new_from_env();
+    let home_assistant_config = HomeAssistantFacadeConfig::new_from_env()
+        .with_mac_address(Efuse::mac_address());
     let home_assistant: HomeAssistantFacade = HomeAssistantFacade::new(home_assistant_config);
-    let pump_topic = home_assistant.get_pump_topic();
-    let mqtt_facade_config = MqttFacadeConfig::new(ip, port, "MyDevice", &pump_topic);
+    let availability_topic = home_assistant.get_availability_topic();
+    // A second client connecting with the same id would take over the session
+    let mut mqtt_facade_config = MqttFacadeConfig::new(
+        MqttBrokerConfig::from_env(),
+        home_assistant.device_id(),
+        &availability_topic,
+    );
+    if let Some(credentials) = MqttCredentials::from_env() {
+        mqtt_facade_config = mqtt_facade_config.with_credentials(credentials);
+    }
+    #[cfg(feature = "tls")]
+    let mqtt_facade_config = mqtt_facade_config.with_tls(MqttTlsConfig::from_env(rng));
+    spawner
+        .spawn(mqtt_task(mqtt_facade_config.clone(), stack))
+        .unwrap();
+
+    info!("MQTT worker started..");
+
     spawner
-        .spawn(mqtt_publisher_task(mqtt_facade_config.clone(), stack))
+        .spawn(sntp_task(SntpFacade::new(SntpFacadeConfig::from_env(), *stack)))
         .unwrap();
     spawner
-        .spawn(mqtt_receiver_task(mqtt_facade_config.clone(), stack))
+        .spawn(rpc_task(RpcFacade::new(
+            RpcFacadeConfig::new(&home_assistant.get_rpc_topic()),
+            mqtt_facade_config.clone(),
+        )))
         .unwrap();
 
-    info!("IP Fetched! MQTT worker started..");
+    // Reservoir sensors are optional and enabled from the build environment
+    let float_switch_pin: Option<AnyPin> = option_env!("TANK_FLOAT_SWITCH_FITTED")
+        .map(|_| peripherals.GPIO14.into());
+    let tank_level_sensor = match (
+        option_env!("TANK_EMPTY_DISTANCE_CM").and_then(|value| value.parse().ok()),
+        option_env!("TANK_FULL_DISTANCE_CM").and_then(|value| value.parse().ok()),
+    ) {
+        (Some(empty_distance_cm), Some(full_distance_cm)) => {
+            let empty_level_percent = option_env!("TANK_EMPTY_LEVEL_PERCENT")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(5.0);
+            Some(TankLevelSensor::new(
+                TankLevelConfig::new(empty_distance_cm, full_distance_cm, empty_level_percent),
+                peripherals.GPIO13,
+                peripherals.GPIO34,
+            ))
+        }
+        _ => None,
+    };
+    let sensors_facade: SensorsFacade = SensorsFacade::new(
+        peripherals.GPIO35,
+        peripherals.ADC1,
+        peripherals.GPIO33,
+        float_switch_pin,
+        tank_level_sensor,
+    );
+    let pump_polarity = option_env!("PUMP_OUTPUT_POLARITY")
+        .and_then(OutputPolarity::parse)
+        .unwrap_or(OutputPolarity::ActiveLow);
+    let valve_polarity = option_env!("VALVE_OUTPUT_POLARITY")
+        .and_then(OutputPolarity::parse)
+        .unwrap_or(OutputPolarity::ActiveLow);
+    let pump_pin = Output::new(
+        peripherals.GPIO27,
+        inactive_level(pump_polarity),
+        OutputConfig::default(),
+    );
+
+    // The flow meter is optional: without a K-factor no sensor is assumed to be fitted
+    let flow_meter = option_env!("FLOW_METER_K_FACTOR")
+        .and_then(|k_factor| k_factor.parse().ok())
+        .map(|k_factor| FlowMeter::new(FlowMeterConfig::new(k_factor)));
+    if flow_meter.is_some() {
+        spawner
+            .spawn(flow_counter_task(FlowSensorFacade::new(
+                Pcnt::new(peripherals.PCNT).unit0,
+                peripherals.GPIO32,
+            )))
+            .unwrap();
+    }
+
+    let pump_power_w = option_env!("PUMP_POWER_W")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(5.0);
+    let mut pump_facade: PumpFacade<Relay> = PumpFacade::new(
+        pump_pin,
+        pump_polarity,
+        PumpBudgetConfig::from_env(),
+        flow_meter,
+        pump_power_w,
+    );
+    if let Some(statistics) = StorageFacade::new().load(StorageSlot::PumpStatistics) {
+        info!("Restored pump statistics: {:?}", statistics);
+        pump_facade.restore_statistics(statistics);
+    }
 
-    let sensors_facade: SensorsFacade =
-        SensorsFacade::new(peripherals.GPIO35, peripherals.ADC1, peripherals.GPIO33);
-    let pump_facade: PumpFacade = PumpFacade::new(peripherals.GPIO27);
+    // Zones are wired to these pins in order, the first ZONE_COUNT (2 by default) are used
+    let zone_count = option_env!("ZONE_COUNT")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(2);
+    let valve_pins: [AnyPin; MAX_ZONES] = [
+        peripherals.GPIO26.into(),
+        peripherals.GPIO25.into(),
+        peripherals.GPIO18.into(),
+        peripherals.GPIO19.into(),
+    ];
+    let mut valves: Vec<Valve<Relay>, MAX_ZONES> = Vec::new();
+    for valve_pin in valve_pins.into_iter().take(zone_count) {
+        let valve_pin = Output::new(valve_pin, inactive_level(valve_polarity), OutputConfig::default());
+        let _ = valves.push(Valve::new(valve_pin, valve_polarity));
+    }
+    let zone_manager: ZoneManager<Relay, Relay> =
+        ZoneManager::new(ZoneManagerConfig::from_env(), pump_facade, valves);
+
+    let components = home_assistant.get_component_registry(DeviceHardware {
+        zone_count: zone_manager.zone_count(),
+        float_switch: sensors_facade.has_float_switch(),
+        tank_level_sensor: sensors_facade.has_tank_level_sensor(),
+        flow_meter: flow_meter.is_some(),
+    });
+    let discovery_message = home_assistant
+        .get_discovery_mqtt_message(&components, DISCOVERY_DOCUMENT.take())
+        .expect("Discovery document too large");
+    let mut mqtt_facade = MqttFacade::new(mqtt_facade_config.clone());
+    mqtt_facade.send_message(discovery_message.clone());
+    let mut home_assistant_status = mqtt_facade
+        .subscribe(&home_assistant.get_status_topic())
+        .expect("Too many MQTT subscriptions");
 
     spawner
         .spawn(sensors_loop(
             sensors_facade,
+            flow_meter,
+            home_assistant_config,
+            mqtt_facade_config.clone(),
+        ))
+        .unwrap();
+    spawner
+        .spawn(zones_loop(
+            zone_manager,
             home_assistant_config,
             mqtt_facade_config.clone(),
         ))
         .unwrap();
     spawner
-        .spawn(pump_loop(
-            pump_facade,
+        .spawn(controller_loop(
+            home_assistant_config,
+            mqtt_facade_config.clone(),
+        ))
+        .unwrap();
+    spawner
+        .spawn(schedule_loop(
+            TimeZoneConfig::from_env(),
+            home_assistant_config,
+            mqtt_facade_config.clone(),
+        ))
+        .unwrap();
+    spawner
+        .spawn(buttons_loop(
+            home_assistant_config,
+            mqtt_facade_config.clone(),
+        ))
+        .unwrap();
+    spawner
+        .spawn(diagnostics_loop(
+            wifi_facade,
+            stack,
             home_assistant_config,
             mqtt_facade_config.clone(),
         ))
         .unwrap();
 
-    // Keep the main function alive
+    // Home Assistant forgets the device when it restarts without the retained
+    // discovery document, so send it again along with the state once it's back
     loop {
-        Timer::after(Duration::from_secs(60)).await;
+        let message = home_assistant_status.receive().await;
+        if !home_assistant.is_online_status(&message) {
+            continue;
+        }
+
+        // Spread the load when many devices answer the same announcement
+        let delay = Duration::from_millis((rng.random() % BIRTH_MAX_DELAY_MS) as u64);
+        info!("Home Assistant online, publishing discovery in {} ms", delay.as_millis());
+        Timer::after(delay).await;
+        mqtt_facade.send_message(discovery_message.clone());
+        ZONES_STATE_REQUESTED.signal(());
+        SENSORS_STATE_REQUESTED.signal(());
     }
 }
 
+/// Level at which a relay pin has to be created so its load stays off at boot.
+fn inactive_level(polarity: OutputPolarity) -> Level {
+    Level::from(bool::from(polarity.inactive_level()))
+}
+
 #[embassy_executor::task]
 async fn net_task(
     mut runner: embassy_net::Runner<'static, esp_wifi::wifi::WifiDevice<'static>>,
@@ -131,43 +325,47 @@ async fn net_task(
 }
 
 #[embassy_executor::task]
-async fn mqtt_publisher_task(
+async fn mqtt_task(
     mqtt_facade_config: MqttFacadeConfig,
     stack: &'static Stack<'static>,
 ) -> ! {
     MqttFacade::new(mqtt_facade_config)
-        .run_publisher_worker(stack)
+        .run_worker(stack)
         .await
 }
 
 #[embassy_executor::task]
-async fn mqtt_receiver_task(
-    mqtt_facade_config: MqttFacadeConfig,
-    stack: &'static Stack<'static>,
-) -> ! {
-    MqttFacade::new(mqtt_facade_config)
-        .run_receiver_worker(stack)
-        .await
+async fn sntp_task(mut sntp_facade: SntpFacade<'static>) -> ! {
+    sntp_facade.run().await
+}
+
+#[embassy_executor::task]
+async fn rpc_task(mut rpc_facade: RpcFacade) -> ! {
+    rpc_facade.run().await
+}
+
+#[embassy_executor::task]
+async fn flow_counter_task(mut flow_sensor_facade: FlowSensorFacade<'static, 0>) -> ! {
+    flow_sensor_facade.run_counter().await
 }
 
 #[embassy_executor::task]
 async fn sensors_loop(
     mut sensors_facade: SensorsFacade<'static>,
+    flow_meter: Option<FlowMeter>,
     home_assistant_config: HomeAssistantFacadeConfig,
     mqtt_facade_config: MqttFacadeConfig,
 ) -> ! {
     let home_assistant: HomeAssistantFacade = HomeAssistantFacade::new(home_assistant_config);
     let mut mqtt_facade = MqttFacade::new(mqtt_facade_config);
+
+    if let Some(calibration) = StorageFacade::new().load(StorageSlot::SoilMoistureCalibration) {
+        info!("Soil moisture calibration: {:?}", calibration);
+        sensors::set_soil_moisture_calibration(calibration);
+    }
     
-    // Send discovery messages
-    mqtt_facade.send_message(home_assistant.get_discovery_message_temperature().unwrap());
-    mqtt_facade.send_message(home_assistant.get_discovery_message_humidity().unwrap());
-    mqtt_facade.send_message(
-        home_assistant
-            .get_discovery_message_soil_moisture()
-            .unwrap(),
-    );
-    mqtt_facade.send_message(home_assistant.get_discovery_message_pump().unwrap());
+    let mut flow_rate_meter = flow_meter.map(FlowRateMeter::new);
+    let mut published_calibration = None;
 
     loop {
         let sensors_values: SensorsValues = sensors_facade.read_values().await;
@@ -178,49 +376,529 @@ async fn sensors_loop(
             sensors_values.humidity
         );
 
+        SOIL_MOISTURE.signal(sensors_values.soil_moisture_sensor_value);
+
         let message = home_assistant.get_sensors_state_mqtt_message(sensors_values);
         mqtt_facade.send_message(message.unwrap());
 
-        Timer::after(Duration::from_secs(10)).await;
+        if let (Some(flow_meter), Some(flow_rate_meter)) = (flow_meter, flow_rate_meter.as_mut()) {
+            let message = home_assistant.get_flow_state_mqtt_message(
+                flow_rate_meter.read_litres_per_minute(),
+                flow_meter.total_litres(),
+            );
+            mqtt_facade.send_message(message.unwrap());
+        }
+
+        // The calibration changes from the RPC interface or Home Assistant
+        let calibration = sensors::soil_moisture_calibration();
+        if published_calibration != Some(calibration) {
+            let message = home_assistant.get_calibration_state_mqtt_message(calibration);
+            mqtt_facade.send_message(message.unwrap());
+            published_calibration = Some(calibration);
+        }
+
+        // Home Assistant may ask for the readings early, after a restart
+        let sample_interval = controller::current_settings().sample_interval();
+        if let Either::Second(()) = select(
+            Timer::after(sample_interval),
+            SENSORS_STATE_REQUESTED.wait(),
+        )
+        .await
+        {
+            published_calibration = None;
+        }
     }
 }
 
 
 #[embassy_executor::task]
-async fn pump_loop(
-    mut pump_facade: PumpFacade<'static>,
+async fn diagnostics_loop(
+    wifi_facade: WiFiFacade<'static>,
+    stack: &'static Stack<'static>,
     home_assistant_config: HomeAssistantFacadeConfig,
     mqtt_facade_config: MqttFacadeConfig,
 ) -> ! {
     let home_assistant: HomeAssistantFacade = HomeAssistantFacade::new(home_assistant_config);
     let mut mqtt_facade = MqttFacade::new(mqtt_facade_config);
-    
-    // Send discovery messages
-    mqtt_facade.send_message(home_assistant.get_discovery_message_pump().unwrap());
-    pump_facade.turn_off();
 
     loop {
-        match mqtt_facade.poll_message() {
-            Some(message) => {
-                info!("Received message: {:?}", message.content);
-                if pump_facade.is_on() == true {
-                    info!("Pump is on, turning off..");
-                    pump_facade.turn_off();
+        let diagnostics = DeviceDiagnostics::read(*stack, wifi_facade.rssi());
+        let message = home_assistant.get_device_diagnostics_mqtt_message(diagnostics);
+        mqtt_facade.send_message(message.unwrap());
+
+        let message = home_assistant.get_mqtt_metrics_mqtt_message(mqtt_facade.metrics());
+        mqtt_facade.send_message(message.unwrap());
+
+        Timer::after(Duration::from_secs(60)).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn buttons_loop(
+    home_assistant_config: HomeAssistantFacadeConfig,
+    mqtt_facade_config: MqttFacadeConfig,
+) -> ! {
+    let home_assistant: HomeAssistantFacade = HomeAssistantFacade::new(home_assistant_config);
+    let mut mqtt_facade = MqttFacade::new(mqtt_facade_config);
+    let storage = StorageFacade::new();
+
+    let mut presses = mqtt_facade
+        .subscribe(&home_assistant.get_button_command_topic_filter())
+        .expect("Too many MQTT subscriptions");
+
+    loop {
+        let message = presses.receive().await;
+        let Some(button) = home_assistant.parse_button_command_topic(message.topic.as_str()) else {
+            warn!("Ignoring message on unexpected topic {:?}", message.topic);
+            continue;
+        };
+        info!("Button {:?} pressed", button);
+
+        match button {
+            DeviceButton::WaterNow => {
+                // The zones task applies the mode and the pump safety limits
+                let settings = controller::current_settings();
+                let command = PumpCommand::On {
+                    duration_s: Some(settings.pulse_s),
+                    volume_l: None,
+                };
+                ZONE_REQUESTS.send(ZoneRequest::new(settings.zone, command)).await;
+            }
+            DeviceButton::CalibrateDry => calibrate_soil_moisture(CalibrationPoint::Dry, &storage),
+            DeviceButton::CalibrateWet => calibrate_soil_moisture(CalibrationPoint::Wet, &storage),
+            DeviceButton::Reboot => diagnostics::reboot().await,
+        }
+    }
+}
+
+/// Moves `point` of the calibration to the latest soil moisture reading.
+fn calibrate_soil_moisture(point: CalibrationPoint, storage: &StorageFacade) {
+    match sensors::calibrate_soil_moisture(point, None) {
+        Ok(calibration) => {
+            info!("Soil moisture calibration: {:?}", calibration);
+            if let Err(e) = storage.save(StorageSlot::SoilMoistureCalibration, &calibration) {
+                warn!("Failed to save the calibration: {:?}", e);
+            }
+            // Publishes the readings and the calibration again
+            SENSORS_STATE_REQUESTED.signal(());
+        }
+        Err(e) => {
+            warn!("Rejected {:?} calibration: {:?}", point, e);
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn zones_loop(
+    mut zone_manager: ZoneManager<Relay, Relay>,
+    home_assistant_config: HomeAssistantFacadeConfig,
+    mqtt_facade_config: MqttFacadeConfig,
+) -> ! {
+    let home_assistant: HomeAssistantFacade = HomeAssistantFacade::new(home_assistant_config);
+    let mut mqtt_facade = MqttFacade::new(mqtt_facade_config);
+
+    let mut commands = mqtt_facade
+        .subscribe(&home_assistant.get_zone_command_topic_filter())
+        .expect("Too many MQTT subscriptions");
+    // Automations written for the first firmware still command the pump
+    let mut pump_commands = mqtt_facade
+        .subscribe(&home_assistant.get_pump_topic())
+        .expect("Too many MQTT subscriptions");
+    zone_manager.stop_all().await;
+    send_zones_state(&home_assistant, &mut mqtt_facade, &zone_manager);
+
+    let storage = StorageFacade::new();
+    let mut saved_statistics = zone_manager.pump().statistics();
+    let message = home_assistant.get_pump_statistics_mqtt_message(saved_statistics);
+    mqtt_facade.send_message(message.unwrap());
+
+    loop {
+        if let Some(event) = zone_manager.set_tank_empty(sensors::is_tank_empty()).await {
+            handle_zone_event(event, &home_assistant, &mut mqtt_facade);
+            send_zones_state(&home_assistant, &mut mqtt_facade, &zone_manager);
+        }
+
+        while let Some(event) = zone_manager.check_deadlines().await {
+            handle_zone_event(event, &home_assistant, &mut mqtt_facade);
+            send_zones_state(&home_assistant, &mut mqtt_facade, &zone_manager);
+        }
+
+        // Save and publish the statistics once per run, when the pump stops
+        let statistics = zone_manager.pump().statistics();
+        if !zone_manager.pump().is_on()
+            && (statistics.cycles, statistics.total_on_time_s)
+                != (saved_statistics.cycles, saved_statistics.total_on_time_s)
+        {
+            if let Err(e) = storage.save(StorageSlot::PumpStatistics, &statistics) {
+                warn!("Failed to save pump statistics: {:?}", e);
+            }
+            let message = home_assistant.get_pump_statistics_mqtt_message(statistics);
+            mqtt_facade.send_message(message.unwrap());
+            saved_statistics = statistics;
+        }
+
+        // Sleep until there is something to do: a zone command, a request from
+        // the controller or scheduler, a change of the reservoir state, Home
+        // Assistant asking for the state, watering being switched off, a zone or
+        // the pump having to be stopped, or the remaining run time having to be
+        // published
+        let mut deadline = zone_manager.deadline().unwrap_or(Instant::MAX);
+        if zone_manager.active_zones() > 0 {
+            deadline = deadline.min(Instant::now() + ZONE_STATE_REFRESH_INTERVAL);
+        }
+        match select4(
+            select(commands.receive(), pump_commands.receive()),
+            ZONE_REQUESTS.receive(),
+            select3(
+                sensors::wait_tank_empty_changed(),
+                ZONES_STATE_REQUESTED.wait(),
+                STOP_ALL_ZONES.wait(),
+            ),
+            Timer::at(deadline),
+        )
+        .await
+        {
+            Either4::First(Either::First(message) | Either::Second(message)) => {
+                info!("Received message on {:?}: {:?}", message.topic, message.content);
+                let Some(zone) = home_assistant.parse_zone_command_topic(message.topic.as_str())
+                else {
+                    warn!("Ignoring message on unexpected topic {:?}", message.topic);
+                    continue;
+                };
+
+                match PumpCommand::parse(message.content.as_str()) {
+                    Ok(command) => {
+                        handle_zone_command(
+                            &mut zone_manager,
+                            ZoneRequest::new(zone, command),
+                            &home_assistant,
+                            &mut mqtt_facade,
+                        )
+                        .await;
+                    }
+                    Err(e) => {
+                        warn!("Rejected zone {} command {:?}: {:?}", zone, message.content, e);
+                    }
+                }
+                send_zones_state(&home_assistant, &mut mqtt_facade, &zone_manager);
+            }
+            Either4::Second(request) => {
+                handle_zone_command(&mut zone_manager, request, &home_assistant, &mut mqtt_facade).await;
+                send_zones_state(&home_assistant, &mut mqtt_facade, &zone_manager);
+            }
+            Either4::Third(Either3::Second(())) => {
+                send_zones_state(&home_assistant, &mut mqtt_facade, &zone_manager);
+            }
+            Either4::Third(Either3::Third(())) => {
+                if zone_manager.active_zones() > 0 {
+                    info!("Watering switched off, closing every zone..");
+                    zone_manager.stop_all().await;
+                }
+                send_zones_state(&home_assistant, &mut mqtt_facade, &zone_manager);
+            }
+            Either4::Fourth(()) if zone_manager.active_zones() > 0 => {
+                send_zones_state(&home_assistant, &mut mqtt_facade, &zone_manager);
+            }
+            // Handled at the top of the loop
+            Either4::Third(Either3::First(())) | Either4::Fourth(()) => {}
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn controller_loop(
+    home_assistant_config: HomeAssistantFacadeConfig,
+    mqtt_facade_config: MqttFacadeConfig,
+) -> ! {
+    let home_assistant: HomeAssistantFacade = HomeAssistantFacade::new(home_assistant_config);
+    let mut mqtt_facade = MqttFacade::new(mqtt_facade_config);
+    let storage = StorageFacade::new();
+
+    let settings: ControllerSettings = storage
+        .load(StorageSlot::ControllerSettings)
+        .unwrap_or_default();
+    info!("Controller settings: {:?}", settings);
+    pump::set_max_runtime(settings.max_runtime());
+    let mut controller = WateringController::new(settings);
+    let mut commands = mqtt_facade
+        .subscribe(&home_assistant.get_controller_command_topic_filter())
+        .expect("Too many MQTT subscriptions");
+
+    let message = home_assistant.get_controller_state_mqtt_message(settings);
+    mqtt_facade.send_message(message.unwrap());
+
+    loop {
+        match select3(SOIL_MOISTURE.wait(), commands.receive(), CONTROLLER_REQUESTS.receive()).await {
+            Either3::First(soil_moisture) => {
+                // The zones task applies the pump safety limits to these requests
+                let request = match controller.update(soil_moisture) {
+                    Some(ControllerAction::StartPulse { zone, duration }) => {
+                        info!("Controller: Soil moisture at {}%, watering zone {}", soil_moisture, zone);
+                        let command = PumpCommand::On {
+                            duration_s: Some(duration.as_secs() as u32),
+                            volume_l: None,
+                        };
+                        ZoneRequest::new(zone, command)
+                    }
+                    Some(ControllerAction::Stop { zone }) => {
+                        info!("Controller: Soil moisture at {}%, stopping zone {}", soil_moisture, zone);
+                        ZoneRequest::new(zone, PumpCommand::Off)
+                    }
+                    None => continue,
+                };
+                ZONE_REQUESTS.send(request).await;
+            }
+            Either3::Second(message) => {
+                let Some(setting) = home_assistant.parse_controller_command_topic(message.topic.as_str())
+                else {
+                    continue;
+                };
+
+                let _ = apply_controller_setting(&mut controller, &storage, setting, message.content.as_str());
+
+                // Also sent on rejection so Home Assistant reverts the entity
+                let message = home_assistant.get_controller_state_mqtt_message(controller.settings());
+                mqtt_facade.send_message(message.unwrap());
+            }
+            Either3::Third(ControllerCall { id, request: ControllerRequest::Get }) => {
+                let result = Ok(controller.settings());
+                CONTROLLER_REPLIES.send(ControllerReply { id, result }).await;
+            }
+            Either3::Third(ControllerCall { id, request: ControllerRequest::Set { setting, value } }) => {
+                let result = apply_controller_setting(&mut controller, &storage, setting, value.as_str());
+                if result.is_ok() {
+                    let message = home_assistant.get_controller_state_mqtt_message(controller.settings());
+                    mqtt_facade.send_message(message.unwrap());
+                }
+                CONTROLLER_REPLIES.send(ControllerReply { id, result }).await;
+            }
+        }
+    }
+}
+
+/// Validates, applies and saves a new `value` for `setting`, returning the
+/// settings in effect afterwards.
+fn apply_controller_setting(
+    controller: &mut WateringController,
+    storage: &StorageFacade,
+    setting: ControllerSetting,
+    value: &str,
+) -> Result<ControllerSettings, ControllerSettingError> {
+    let mut settings = controller.settings();
+    match settings.apply(setting, value) {
+        Ok(()) => {
+            info!("Controller: {} set to {:?}", setting.key(), value);
+            controller.set_settings(settings);
+            pump::set_max_runtime(settings.max_runtime());
+            if !settings.mode.allows_watering() {
+                // Off also stops what's running, not only new starts
+                STOP_ALL_ZONES.signal(());
+            }
+            if let Err(e) = storage.save(StorageSlot::ControllerSettings, &settings) {
+                warn!("Failed to save controller settings: {:?}", e);
+            }
+            Ok(settings)
+        }
+        Err(e) => {
+            warn!("Controller: Rejected {} value {:?}: {:?}", setting.key(), value, e);
+            Err(e)
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn schedule_loop(
+    time_zone: TimeZoneConfig,
+    home_assistant_config: HomeAssistantFacadeConfig,
+    mqtt_facade_config: MqttFacadeConfig,
+) -> ! {
+    let home_assistant: HomeAssistantFacade = HomeAssistantFacade::new(home_assistant_config);
+    let mut mqtt_facade = MqttFacade::new(mqtt_facade_config);
+    let storage = StorageFacade::new();
+
+    let table: ScheduleTable = storage.load(StorageSlot::Schedules).unwrap_or_default();
+    let mut schedule_engine = ScheduleEngine::new(table, time_zone);
+    let mut commands = mqtt_facade
+        .subscribe(&home_assistant.get_schedule_command_topic_filter())
+        .expect("Too many MQTT subscriptions");
+
+    for slot in 0..MAX_SCHEDULES {
+        let message = home_assistant
+            .get_schedule_state_mqtt_message(slot, table.entries[slot].as_ref());
+        mqtt_facade.send_message(message.unwrap());
+    }
+
+    loop {
+        match select(Timer::after(Duration::from_secs(15)), commands.receive()).await {
+            Either::First(()) => {
+                // Schedules only run once the clock has been synchronised
+                let Some(unix_time) = clock::unix_time() else {
+                    continue;
+                };
+
+                // Entries due in other modes are skipped rather than run later
+                let due_entries = schedule_engine.due_entries(unix_time);
+                if !controller::current_settings().mode.runs_schedules() {
+                    continue;
+                }
+                for entry in due_entries {
+                    info!("Scheduler: Running \"{}\"", entry);
+                    let command = PumpCommand::On {
+                        duration_s: Some(entry.duration_s),
+                        volume_l: None,
+                    };
+                    ZONE_REQUESTS.send(ZoneRequest::new(entry.zone, command)).await;
+                }
+            }
+            Either::Second(message) => {
+                let Some(slot) = home_assistant.parse_schedule_command_topic(message.topic.as_str())
+                else {
+                    continue;
+                };
+
+                // An empty payload clears the slot
+                let content = message.content.trim();
+                let entry = if content.is_empty() {
+                    Ok(None)
                 } else {
-                    info!("Pump is off, turning on..");
-                    pump_facade.turn_on();
+                    ScheduleEntry::parse(content).map(Some)
+                };
+                match entry.and_then(|entry| schedule_engine.set_entry(slot, entry)) {
+                    Ok(()) => {
+                        info!("Scheduler: Slot {} set to {:?}", slot, content);
+                        let table = schedule_engine.table();
+                        if let Err(e) = storage.save(StorageSlot::Schedules, table) {
+                            warn!("Failed to save schedules: {:?}", e);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Scheduler: Rejected schedule {:?} for slot {}: {:?}", content, slot, e);
+                    }
                 }
 
-                let message = home_assistant.get_pump_state_mqtt_message(pump_facade.is_on());
+                let entry = schedule_engine.table().entries.get(slot).and_then(Option::as_ref);
+                let message = home_assistant.get_schedule_state_mqtt_message(slot, entry);
                 mqtt_facade.send_message(message.unwrap());
             }
-            None => {
-                info!("No message received");
+        }
+    }
+}
+
+fn handle_zone_event(
+    event: ZoneEvent,
+    home_assistant: &HomeAssistantFacade,
+    mqtt_facade: &mut MqttFacade,
+) {
+    match event {
+        ZoneEvent::RunCompleted(zone) => {
+            info!("Timed run of zone {} finished", zone);
+        }
+        ZoneEvent::PumpStopped(PumpStopReason::RunCompleted) => {
+            info!("Timed pump run finished, all zones closed");
+        }
+        ZoneEvent::PumpStopped(PumpStopReason::VolumeDelivered) => {
+            info!("Requested volume delivered, all zones closed");
+        }
+        ZoneEvent::PumpStopped(PumpStopReason::BudgetExhausted(rejection)) => {
+            warn!("Pump stopped, runtime budget used up: {:?}", rejection);
+            send_rejection(home_assistant, mqtt_facade, rejection.as_str());
+        }
+        ZoneEvent::PumpStopped(PumpStopReason::Fault(fault)) => {
+            warn!("Pump stopped by safety cutoff: {:?}", fault);
+        }
+    }
+}
+
+async fn handle_zone_command(
+    zone_manager: &mut ZoneManager<Relay, Relay>,
+    request: ZoneRequest,
+    home_assistant: &HomeAssistantFacade,
+    mqtt_facade: &mut MqttFacade,
+) {
+    let zone = request.zone;
+    let starts_zone = match request.command {
+        PumpCommand::On { .. } => true,
+        PumpCommand::Off => false,
+        PumpCommand::Toggle => !zone_manager.is_zone_on(zone),
+    };
+    if starts_zone && !controller::current_settings().mode.allows_watering() {
+        warn!("Zone {} not started, watering is off", zone);
+        send_rejection(home_assistant, mqtt_facade, "watering_off");
+        return;
+    }
+
+    // Valves take a moment to settle, which Home Assistant shows meanwhile
+    if zone < zone_manager.zone_count() && zone_manager.is_zone_on(zone) != starts_zone {
+        let transition = if starts_zone { ZoneState::Opening } else { ZoneState::Closing };
+        send_zone_state(home_assistant, mqtt_facade, zone, transition, None);
+    }
+
+    let result = match request.command {
+        PumpCommand::On { duration_s, volume_l } => {
+            info!(
+                "Turning zone {} on (duration: {:?}s, volume: {:?}L)..",
+                zone, duration_s, volume_l
+            );
+            let duration = duration_s.map(|seconds| Duration::from_secs(seconds as u64));
+            zone_manager.start_zone(zone, duration, volume_l).await
+        }
+        PumpCommand::Off => {
+            info!("Turning zone {} off..", zone);
+            zone_manager.stop_zone(zone).await
+        }
+        PumpCommand::Toggle => {
+            if zone_manager.is_zone_on(zone) {
+                info!("Zone {} is on, turning off..", zone);
+                zone_manager.stop_zone(zone).await
+            } else {
+                info!("Zone {} is off, turning on..", zone);
+                zone_manager.start_zone(zone, None, None).await
             }
         }
+    };
 
-        Timer::after(Duration::from_millis(2000)).await;
+    if let Err(e) = result {
+        warn!("Zone {} refused to start: {:?}", zone, e);
+        send_rejection(home_assistant, mqtt_facade, e.as_str());
     }
 }
 
+fn send_zones_state(
+    home_assistant: &HomeAssistantFacade,
+    mqtt_facade: &mut MqttFacade,
+    zone_manager: &ZoneManager<Relay, Relay>,
+) {
+    let pump = zone_manager.pump();
+    let message = home_assistant.get_pump_state_mqtt_message(pump.is_on(), pump.fault());
+    mqtt_facade.send_message(message.unwrap());
+
+    for zone in 0..zone_manager.zone_count() {
+        send_zone_state(
+            home_assistant,
+            mqtt_facade,
+            zone,
+            zone_manager.zone_state(zone),
+            zone_manager.remaining_run_time(zone),
+        );
+    }
+}
 
+fn send_zone_state(
+    home_assistant: &HomeAssistantFacade,
+    mqtt_facade: &mut MqttFacade,
+    zone: usize,
+    zone_state: ZoneState,
+    remaining_run_time: Option<Duration>,
+) {
+    let message = home_assistant.get_zone_state_mqtt_message(zone, zone_state, remaining_run_time);
+    mqtt_facade.send_message(message.unwrap());
+}
+
+fn send_rejection(
+    home_assistant: &HomeAssistantFacade,
+    mqtt_facade: &mut MqttFacade,
+    reason: &str,
+) {
+    let message = home_assistant.get_pump_rejection_mqtt_message(reason);
+    mqtt_facade.send_message(message.unwrap());
+}
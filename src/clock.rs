@@ -0,0 +1,341 @@
+use core::cell::Cell;
+use core::fmt;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+/// Unix time in milliseconds at which the device booted, known once the clock
+/// has been synchronised. Wall-clock time is derived from it and the uptime.
+static BOOT_UNIX_TIME_MS: Mutex<CriticalSectionRawMutex, Cell<Option<u64>>> =
+    Mutex::new(Cell::new(None));
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Sets the wall clock from a Unix time in milliseconds observed at `at`.
+pub fn set_unix_time_ms(unix_time_ms: u64, at: Instant) {
+    let boot_unix_time_ms = unix_time_ms.saturating_sub(at.as_millis());
+    BOOT_UNIX_TIME_MS.lock(|boot| boot.set(Some(boot_unix_time_ms)));
+}
+
+/// Current Unix time in seconds, or `None` until the clock has been synchronised.
+pub fn unix_time() -> Option<u64> {
+    let boot_unix_time_ms = BOOT_UNIX_TIME_MS.lock(|boot| boot.get())?;
+    Some((boot_unix_time_ms + Instant::now().as_millis()) / 1000)
+}
+
+pub fn is_synchronised() -> bool {
+    BOOT_UNIX_TIME_MS.lock(|boot| boot.get()).is_some()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Weekday {
+    Monday = 0,
+    Tuesday = 1,
+    Wednesday = 2,
+    Thursday = 3,
+    Friday = 4,
+    Saturday = 5,
+    Sunday = 6,
+}
+
+impl Weekday {
+    pub const ALL: [Weekday; 7] = [
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+        Weekday::Saturday,
+        Weekday::Sunday,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Weekday::Monday => "mon",
+            Weekday::Tuesday => "tue",
+            Weekday::Wednesday => "wed",
+            Weekday::Thursday => "thu",
+            Weekday::Friday => "fri",
+            Weekday::Saturday => "sat",
+            Weekday::Sunday => "sun",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|weekday| weekday.as_str().eq_ignore_ascii_case(value))
+    }
+
+    fn from_days_since_epoch(days: i64) -> Self {
+        // 1970-01-01 was a Thursday
+        Self::ALL[(days + 3).rem_euclid(7) as usize]
+    }
+}
+
+/// A civil date and time, without any time zone attached.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateTime {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub weekday: Weekday,
+}
+
+impl DateTime {
+    pub fn from_unix(seconds: i64) -> Self {
+        let days = seconds.div_euclid(SECONDS_PER_DAY);
+        let seconds_of_day = seconds.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: (seconds_of_day / 60 % 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+            weekday: Weekday::from_days_since_epoch(days),
+        }
+    }
+}
+
+/// ISO 8601, e.g. `2025-06-01T06:30:00`.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DstRule {
+    None,
+    /// Last Sunday of March to last Sunday of October, switching at 01:00 UTC.
+    Eu,
+    /// Second Sunday of March to first Sunday of November, switching at 02:00 local.
+    Us,
+}
+
+impl DstRule {
+    pub fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("none") {
+            Some(DstRule::None)
+        } else if value.eq_ignore_ascii_case("eu") {
+            Some(DstRule::Eu)
+        } else if value.eq_ignore_ascii_case("us") {
+            Some(DstRule::Us)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimeZoneConfig {
+    /// Offset of standard (winter) time from UTC, in minutes.
+    pub utc_offset_min: i32,
+    pub dst_rule: DstRule,
+}
+
+impl TimeZoneConfig {
+    pub fn new(utc_offset_min: i32, dst_rule: DstRule) -> Self {
+        Self {
+            utc_offset_min,
+            dst_rule,
+        }
+    }
+
+    /// Reads `TIMEZONE_UTC_OFFSET_MIN` and `TIMEZONE_DST`, defaulting to UTC.
+    pub fn from_env() -> Self {
+        Self {
+            utc_offset_min: option_env!("TIMEZONE_UTC_OFFSET_MIN")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0),
+            dst_rule: option_env!("TIMEZONE_DST")
+                .and_then(DstRule::parse)
+                .unwrap_or(DstRule::None),
+        }
+    }
+
+    pub fn to_local(&self, unix_time: u64) -> DateTime {
+        let unix_time = unix_time as i64;
+        DateTime::from_unix(unix_time + self.utc_offset_s(unix_time))
+    }
+
+    /// Whether the local time at `unix_time` was already shown an hour earlier,
+    /// which happens during the hour repeated when daylight saving time ends.
+    pub fn is_repeated_hour(&self, unix_time: u64) -> bool {
+        let unix_time = unix_time as i64;
+        self.utc_offset_s(unix_time - 3600) > self.utc_offset_s(unix_time)
+    }
+
+    /// Offset from UTC in effect at `unix_time`, daylight saving included.
+    fn utc_offset_s(&self, unix_time: i64) -> i64 {
+        let standard_offset_s = self.utc_offset_min as i64 * 60;
+        let year = DateTime::from_unix(unix_time + standard_offset_s).year;
+
+        let (dst_start, dst_end) = match self.dst_rule {
+            DstRule::None => return standard_offset_s,
+            DstRule::Eu => (
+                last_sunday(year, 3) * SECONDS_PER_DAY + 3600,
+                last_sunday(year, 10) * SECONDS_PER_DAY + 3600,
+            ),
+            DstRule::Us => (
+                nth_sunday(year, 3, 2) * SECONDS_PER_DAY + 2 * 3600 - standard_offset_s,
+                nth_sunday(year, 11, 1) * SECONDS_PER_DAY + 3600 - standard_offset_s,
+            ),
+        };
+
+        if (dst_start..dst_end).contains(&unix_time) {
+            standard_offset_s + 3600
+        } else {
+            standard_offset_s
+        }
+    }
+}
+
+impl Default for TimeZoneConfig {
+    fn default() -> Self {
+        Self::new(0, DstRule::None)
+    }
+}
+
+/// Days since 1970-01-01 of a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i32, month: u8, day: u8) -> i64 {
+    // See http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year } as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i32, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u8;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as i32, month, day)
+}
+
+/// Day (since the epoch) of the `n`-th Sunday of a month.
+fn nth_sunday(year: i32, month: u8, n: i64) -> i64 {
+    let first = days_from_civil(year, month, 1);
+    let first_weekday = Weekday::from_days_since_epoch(first) as i64;
+    first + (6 - first_weekday) + (n - 1) * 7
+}
+
+fn last_sunday(year: i32, month: u8) -> i64 {
+    let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    let last = days_from_civil(next_year, next_month, 1) - 1;
+    last - (Weekday::from_days_since_epoch(last) as i64 + 1) % 7
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CET: TimeZoneConfig = TimeZoneConfig {
+        utc_offset_min: 60,
+        dst_rule: DstRule::Eu,
+    };
+    const US_EASTERN: TimeZoneConfig = TimeZoneConfig {
+        utc_offset_min: -300,
+        dst_rule: DstRule::Us,
+    };
+
+    #[test]
+    fn days_from_civil_counts_from_the_epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+        assert_eq!(days_from_civil(1600, 1, 1), -135_140);
+    }
+
+    #[test]
+    fn civil_from_days_reverses_days_from_civil() {
+        for days in (-150_000..150_000).step_by(97) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+    }
+
+    #[test]
+    fn sundays_of_a_month() {
+        assert_eq!(civil_from_days(nth_sunday(2025, 3, 2)), (2025, 3, 9));
+        assert_eq!(civil_from_days(nth_sunday(2025, 11, 1)), (2025, 11, 2));
+        // A month starting on a Sunday
+        assert_eq!(civil_from_days(nth_sunday(2025, 6, 1)), (2025, 6, 1));
+        assert_eq!(civil_from_days(last_sunday(2025, 3)), (2025, 3, 30));
+        assert_eq!(civil_from_days(last_sunday(2025, 10)), (2025, 10, 26));
+        assert_eq!(civil_from_days(last_sunday(2024, 12)), (2024, 12, 29));
+        // A month ending on a Sunday
+        assert_eq!(civil_from_days(last_sunday(2025, 8)), (2025, 8, 31));
+    }
+
+    #[test]
+    fn date_time_from_unix() {
+        let date_time = DateTime::from_unix(1_748_842_200);
+        assert_eq!(date_time.weekday, Weekday::Monday);
+        let mut text = heapless::String::<32>::new();
+        core::fmt::write(&mut text, format_args!("{}", date_time)).unwrap();
+        assert_eq!(text, "2025-06-02T05:30:00");
+    }
+
+    #[test]
+    fn offset_without_dst_is_constant() {
+        let time_zone = TimeZoneConfig::new(330, DstRule::None);
+        assert_eq!(time_zone.utc_offset_s(1_743_296_400), 330 * 60);
+        assert_eq!(time_zone.utc_offset_s(1_761_440_400), 330 * 60);
+    }
+
+    #[test]
+    fn eu_dst_switches_at_1_utc() {
+        // 2025-03-30T01:00:00Z
+        assert_eq!(CET.utc_offset_s(1_743_296_400 - 1), 3600);
+        assert_eq!(CET.utc_offset_s(1_743_296_400), 7200);
+        // 2025-10-26T01:00:00Z
+        assert_eq!(CET.utc_offset_s(1_761_440_400 - 1), 7200);
+        assert_eq!(CET.utc_offset_s(1_761_440_400), 3600);
+    }
+
+    #[test]
+    fn us_dst_switches_at_2_local() {
+        // 2025-03-09T02:00:00 EST
+        assert_eq!(US_EASTERN.utc_offset_s(1_741_503_600 - 1), -5 * 3600);
+        assert_eq!(US_EASTERN.utc_offset_s(1_741_503_600), -4 * 3600);
+        // 2025-11-02T02:00:00 EDT
+        assert_eq!(US_EASTERN.utc_offset_s(1_762_063_200 - 1), -4 * 3600);
+        assert_eq!(US_EASTERN.utc_offset_s(1_762_063_200), -5 * 3600);
+    }
+
+    #[test]
+    fn hour_after_the_end_of_dst_is_repeated() {
+        // 01:30 EDT, then 01:30 EST an hour later
+        assert!(!US_EASTERN.is_repeated_hour(1_762_061_400));
+        assert!(US_EASTERN.is_repeated_hour(1_762_061_400 + 3600));
+        assert!(!US_EASTERN.is_repeated_hour(1_762_063_200 + 3600));
+        assert!(CET.is_repeated_hour(1_761_440_400));
+        assert!(!CET.is_repeated_hour(1_761_440_400 + 3600));
+        assert!(!CET.is_repeated_hour(1_743_296_400));
+    }
+}
@@ -0,0 +1,481 @@
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant};
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+use crate::pump::MAX_CONTINUOUS_RUNTIME;
+
+/// Latest soil moisture reading, published by the sensors task.
+pub static SOIL_MOISTURE: Signal<CriticalSectionRawMutex, f32> = Signal::new();
+
+/// Requests from the RPC interface, each answered on `CONTROLLER_REPLIES` with
+/// the settings in effect afterwards.
+pub static CONTROLLER_REQUESTS: Channel<CriticalSectionRawMutex, ControllerCall, 1> = Channel::new();
+pub static CONTROLLER_REPLIES: Channel<CriticalSectionRawMutex, ControllerReply, 1> = Channel::new();
+
+/// Settings of the controller, for the tasks that don't own it.
+static SETTINGS: Mutex<CriticalSectionRawMutex, Cell<ControllerSettings>> =
+    Mutex::new(Cell::new(ControllerSettings::DEFAULT));
+
+/// Longest soak time accepted between two pulses.
+const MAX_SOAK_TIME: Duration = Duration::from_secs(4 * 60 * 60);
+
+/// Bounds of the period at which the sensors are read.
+pub const MIN_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+pub const MAX_SAMPLE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Settings in effect in the controller task.
+pub fn current_settings() -> ControllerSettings {
+    SETTINGS.lock(|settings| settings.get())
+}
+
+/// What starts watering on its own. Zones can be turned on from Home Assistant
+/// or the RPC interface in every mode but `Off`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ControllerMode {
+    /// The soil moisture controller.
+    Auto,
+    /// Nothing, zones only run when asked to.
+    Manual,
+    /// The schedule table.
+    Schedule,
+    /// Nothing, and requests to start a zone are refused.
+    Off,
+}
+
+impl ControllerMode {
+    pub const ALL: [ControllerMode; 4] = [
+        ControllerMode::Auto,
+        ControllerMode::Manual,
+        ControllerMode::Schedule,
+        ControllerMode::Off,
+    ];
+
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            ControllerMode::Auto => "auto",
+            ControllerMode::Manual => "manual",
+            ControllerMode::Schedule => "schedule",
+            ControllerMode::Off => "off",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.as_str().eq_ignore_ascii_case(value))
+    }
+
+    pub fn runs_schedules(&self) -> bool {
+        *self == ControllerMode::Schedule
+    }
+
+    pub fn allows_watering(&self) -> bool {
+        *self != ControllerMode::Off
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControllerSetting {
+    Mode,
+    MoistureLow,
+    MoistureHigh,
+    PulseDuration,
+    SoakDuration,
+    MaxRuntime,
+    SampleInterval,
+}
+
+impl ControllerSetting {
+    pub const ALL: [ControllerSetting; 7] = [
+        ControllerSetting::Mode,
+        ControllerSetting::MoistureLow,
+        ControllerSetting::MoistureHigh,
+        ControllerSetting::PulseDuration,
+        ControllerSetting::SoakDuration,
+        ControllerSetting::MaxRuntime,
+        ControllerSetting::SampleInterval,
+    ];
+
+    /// Name of the setting in MQTT topics and state messages.
+    pub fn key(&self) -> &'static str {
+        match self {
+            ControllerSetting::Mode => "controller_mode",
+            ControllerSetting::MoistureLow => "moisture_low",
+            ControllerSetting::MoistureHigh => "moisture_high",
+            ControllerSetting::PulseDuration => "pulse_s",
+            ControllerSetting::SoakDuration => "soak_s",
+            ControllerSetting::MaxRuntime => "max_runtime_s",
+            ControllerSetting::SampleInterval => "sample_interval_s",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|setting| setting.key() == key)
+    }
+}
+
+#[derive(Debug)]
+pub enum ControllerSettingError {
+    InvalidValue,
+    OutOfRange,
+}
+
+impl ControllerSettingError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ControllerSettingError::InvalidValue => "invalid_value",
+            ControllerSettingError::OutOfRange => "out_of_range",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ControllerRequest {
+    Get,
+    Set {
+        setting: ControllerSetting,
+        value: String<16>,
+    },
+}
+
+/// A request together with the id its reply carries, so the caller can tell the
+/// reply apart from a late one to an earlier request it gave up on.
+#[derive(Debug, Clone)]
+pub struct ControllerCall {
+    pub id: u32,
+    pub request: ControllerRequest,
+}
+
+#[derive(Debug)]
+pub struct ControllerReply {
+    pub id: u32,
+    pub result: Result<ControllerSettings, ControllerSettingError>,
+}
+
+/// Settings saved before a field was added get its default value.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControllerSettings {
+    pub mode: ControllerMode,
+    /// Watering starts when the soil moisture drops below this percentage.
+    pub moisture_low: f32,
+    /// Watering stops once the soil moisture reaches this percentage.
+    pub moisture_high: f32,
+    pub pulse_s: u32,
+    /// Time given to the water to spread through the soil between pulses.
+    pub soak_s: u32,
+    pub zone: usize,
+    /// The pump is cut off once it ran this long, at most `MAX_CONTINUOUS_RUNTIME`.
+    pub max_runtime_s: u32,
+    /// Period at which the sensors are read.
+    pub sample_interval_s: u32,
+}
+
+impl Default for ControllerSettings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl ControllerSettings {
+    const DEFAULT: ControllerSettings = ControllerSettings {
+        mode: ControllerMode::Manual,
+        moisture_low: 30.0,
+        moisture_high: 60.0,
+        pulse_s: 20,
+        soak_s: 10 * 60,
+        zone: 0,
+        max_runtime_s: MAX_CONTINUOUS_RUNTIME.as_secs() as u32,
+        sample_interval_s: 10,
+    };
+
+    pub fn max_runtime(&self) -> Duration {
+        Duration::from_secs(self.max_runtime_s as u64)
+    }
+
+    pub fn sample_interval(&self) -> Duration {
+        Duration::from_secs(self.sample_interval_s as u64)
+    }
+
+    /// Validates and applies a new value received for `setting`.
+    pub fn apply(
+        &mut self,
+        setting: ControllerSetting,
+        payload: &str,
+    ) -> Result<(), ControllerSettingError> {
+        let payload = payload.trim();
+        let mut updated = *self;
+
+        match setting {
+            ControllerSetting::Mode => {
+                updated.mode =
+                    ControllerMode::parse(payload).ok_or(ControllerSettingError::InvalidValue)?;
+            }
+            ControllerSetting::MoistureLow => updated.moisture_low = Self::parse_percent(payload)?,
+            ControllerSetting::MoistureHigh => updated.moisture_high = Self::parse_percent(payload)?,
+            ControllerSetting::PulseDuration => {
+                updated.pulse_s = Self::parse_seconds(payload, MAX_CONTINUOUS_RUNTIME)?;
+                if updated.pulse_s == 0 {
+                    return Err(ControllerSettingError::OutOfRange);
+                }
+            }
+            ControllerSetting::SoakDuration => {
+                updated.soak_s = Self::parse_seconds(payload, MAX_SOAK_TIME)?;
+            }
+            ControllerSetting::MaxRuntime => {
+                updated.max_runtime_s = Self::parse_seconds(payload, MAX_CONTINUOUS_RUNTIME)?;
+                if updated.max_runtime_s == 0 {
+                    return Err(ControllerSettingError::OutOfRange);
+                }
+            }
+            ControllerSetting::SampleInterval => {
+                updated.sample_interval_s = Self::parse_seconds(payload, MAX_SAMPLE_INTERVAL)?;
+                if updated.sample_interval() < MIN_SAMPLE_INTERVAL {
+                    return Err(ControllerSettingError::OutOfRange);
+                }
+            }
+        }
+
+        // A pulse longer than the maximum runtime would always end in a fault
+        if updated.moisture_low >= updated.moisture_high || updated.pulse_s > updated.max_runtime_s {
+            return Err(ControllerSettingError::OutOfRange);
+        }
+        *self = updated;
+        Ok(())
+    }
+
+    fn parse_percent(payload: &str) -> Result<f32, ControllerSettingError> {
+        let value: f32 = payload.parse().map_err(|_| ControllerSettingError::InvalidValue)?;
+        if !(0.0..=100.0).contains(&value) {
+            return Err(ControllerSettingError::OutOfRange);
+        }
+        Ok(value)
+    }
+
+    fn parse_seconds(payload: &str, max: Duration) -> Result<u32, ControllerSettingError> {
+        // Home Assistant number entities send floats such as "20.0"
+        let value: f32 = payload.parse().map_err(|_| ControllerSettingError::InvalidValue)?;
+        if !(0.0..=max.as_secs() as f32).contains(&value) {
+            return Err(ControllerSettingError::OutOfRange);
+        }
+        Ok(value as u32)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControllerAction {
+    StartPulse { zone: usize, duration: Duration },
+    Stop { zone: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ControllerState {
+    Idle,
+    Pulsing { until: Instant },
+    Soaking { until: Instant },
+}
+
+/// Hysteresis controller watering in pulses: it starts below `moisture_low`,
+/// soaks between pulses and stops once `moisture_high` is reached.
+pub struct WateringController {
+    _settings: ControllerSettings,
+    _state: ControllerState,
+}
+
+impl WateringController {
+    pub fn new(settings: ControllerSettings) -> Self {
+        SETTINGS.lock(|current| current.set(settings));
+        Self {
+            _settings: settings,
+            _state: ControllerState::Idle,
+        }
+    }
+
+    pub fn settings(&self) -> ControllerSettings {
+        self._settings
+    }
+
+    /// Also makes `settings` the ones returned by `current_settings`.
+    pub fn set_settings(&mut self, settings: ControllerSettings) {
+        SETTINGS.lock(|current| current.set(settings));
+        self._settings = settings;
+    }
+
+    /// Feeds a new soil moisture reading, returning what the zones should do.
+    pub fn update(&mut self, soil_moisture: f32) -> Option<ControllerAction> {
+        let now = Instant::now();
+        let zone = self._settings.zone;
+
+        if self._settings.mode != ControllerMode::Auto {
+            self._state = ControllerState::Idle;
+            return None;
+        }
+
+        match self._state {
+            ControllerState::Idle if soil_moisture < self._settings.moisture_low => {
+                self.start_pulse(now)
+            }
+            ControllerState::Idle => None,
+            ControllerState::Pulsing { .. } if soil_moisture >= self._settings.moisture_high => {
+                self._state = ControllerState::Idle;
+                Some(ControllerAction::Stop { zone })
+            }
+            ControllerState::Pulsing { until } => {
+                if now >= until {
+                    self._state = ControllerState::Soaking {
+                        until: until + Duration::from_secs(self._settings.soak_s as u64),
+                    };
+                }
+                None
+            }
+            ControllerState::Soaking { .. } if soil_moisture >= self._settings.moisture_high => {
+                self._state = ControllerState::Idle;
+                None
+            }
+            ControllerState::Soaking { until } if now >= until => self.start_pulse(now),
+            ControllerState::Soaking { .. } => None,
+        }
+    }
+
+    fn start_pulse(&mut self, now: Instant) -> Option<ControllerAction> {
+        let duration = Duration::from_secs(self._settings.pulse_s as u64);
+        self._state = ControllerState::Pulsing {
+            until: now + duration,
+        };
+        Some(ControllerAction::StartPulse {
+            zone: self._settings.zone,
+            duration,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::MockDriver;
+
+    use super::*;
+
+    fn auto_controller() -> WateringController {
+        WateringController::new(ControllerSettings {
+            mode: ControllerMode::Auto,
+            ..ControllerSettings::DEFAULT
+        })
+    }
+
+    const PULSE: ControllerAction = ControllerAction::StartPulse {
+        zone: 0,
+        duration: Duration::from_secs(20),
+    };
+
+    #[test]
+    fn starts_below_the_low_threshold() {
+        let mut controller = auto_controller();
+        assert_eq!(controller.update(30.0), None);
+        assert_eq!(controller.update(29.9), Some(PULSE));
+    }
+
+    #[test]
+    fn only_waters_in_auto_mode() {
+        let mut controller = WateringController::new(ControllerSettings::DEFAULT);
+        assert_eq!(controller.update(10.0), None);
+    }
+
+    #[test]
+    fn soaks_between_pulses() {
+        let mut controller = auto_controller();
+        assert_eq!(controller.update(20.0), Some(PULSE));
+
+        MockDriver::get().advance(Duration::from_secs(20));
+        assert_eq!(controller.update(25.0), None);
+
+        // The soak time counts from the end of the pulse
+        MockDriver::get().advance(Duration::from_secs(10 * 60 - 1));
+        assert_eq!(controller.update(25.0), None);
+        MockDriver::get().advance(Duration::from_secs(1));
+        assert_eq!(controller.update(25.0), Some(PULSE));
+    }
+
+    #[test]
+    fn stops_at_the_high_threshold() {
+        let mut controller = auto_controller();
+        assert_eq!(controller.update(20.0), Some(PULSE));
+        assert_eq!(controller.update(59.9), None);
+        assert_eq!(controller.update(60.0), Some(ControllerAction::Stop { zone: 0 }));
+
+        // Back to idle: nothing happens until it's dry again
+        MockDriver::get().advance(Duration::from_secs(60 * 60));
+        assert_eq!(controller.update(45.0), None);
+        assert_eq!(controller.update(29.0), Some(PULSE));
+    }
+
+    #[test]
+    fn high_threshold_while_soaking_ends_the_cycle() {
+        let mut controller = auto_controller();
+        assert_eq!(controller.update(20.0), Some(PULSE));
+        MockDriver::get().advance(Duration::from_secs(20));
+        assert_eq!(controller.update(50.0), None);
+
+        assert_eq!(controller.update(60.0), None);
+        MockDriver::get().advance(Duration::from_secs(10 * 60));
+        assert_eq!(controller.update(50.0), None);
+    }
+
+    #[test]
+    fn rejects_a_low_threshold_not_below_the_high_one() {
+        let mut settings = ControllerSettings::DEFAULT;
+        assert!(matches!(
+            settings.apply(ControllerSetting::MoistureLow, "60"),
+            Err(ControllerSettingError::OutOfRange)
+        ));
+        assert!(matches!(
+            settings.apply(ControllerSetting::MoistureHigh, "25.5"),
+            Err(ControllerSettingError::OutOfRange)
+        ));
+        assert_eq!(settings.moisture_low, 30.0);
+        assert_eq!(settings.moisture_high, 60.0);
+
+        assert!(settings.apply(ControllerSetting::MoistureLow, "59.5").is_ok());
+        assert_eq!(settings.moisture_low, 59.5);
+    }
+
+    #[test]
+    fn rejects_a_pulse_longer_than_the_max_runtime() {
+        let mut settings = ControllerSettings::DEFAULT;
+        assert!(settings.apply(ControllerSetting::MaxRuntime, "60").is_ok());
+        assert!(matches!(
+            settings.apply(ControllerSetting::PulseDuration, "61"),
+            Err(ControllerSettingError::OutOfRange)
+        ));
+        assert!(matches!(
+            settings.apply(ControllerSetting::MaxRuntime, "19"),
+            Err(ControllerSettingError::OutOfRange)
+        ));
+        assert_eq!(settings.pulse_s, 20);
+        assert_eq!(settings.max_runtime_s, 60);
+
+        assert!(settings.apply(ControllerSetting::PulseDuration, "60.0").is_ok());
+        assert_eq!(settings.pulse_s, 60);
+    }
+
+    #[test]
+    fn rejects_values_that_are_not_numbers_or_modes() {
+        let mut settings = ControllerSettings::DEFAULT;
+        assert!(matches!(
+            settings.apply(ControllerSetting::PulseDuration, "long"),
+            Err(ControllerSettingError::InvalidValue)
+        ));
+        assert!(matches!(
+            settings.apply(ControllerSetting::Mode, "sometimes"),
+            Err(ControllerSettingError::InvalidValue)
+        ));
+        assert!(settings.apply(ControllerSetting::Mode, " AUTO ").is_ok());
+        assert_eq!(settings.mode, ControllerMode::Auto);
+    }
+}
@@ -0,0 +1,61 @@
+use core::net::Ipv4Addr;
+#[cfg(target_os = "none")]
+use embassy_net::Stack;
+use embassy_time::Duration;
+#[cfg(target_os = "none")]
+use embassy_time::{Instant, Timer};
+#[cfg(target_os = "none")]
+use log::info;
+
+#[cfg(target_os = "none")]
+pub use esp_hal::rtc_cntl::SocResetReason as ResetReason;
+
+/// Stand-in for the reset reasons of the chip, which are only printed.
+#[cfg(not(target_os = "none"))]
+#[derive(Debug, Clone, Copy)]
+pub enum ResetReason {
+    ChipPowerOn,
+    CoreSw,
+}
+
+/// Firmware version, the crate version followed by the commit it was built from.
+pub const FIRMWARE_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("GIT_HASH"));
+/// Time given to the pending messages to reach the broker before rebooting.
+#[cfg(target_os = "none")]
+const REBOOT_DELAY: Duration = Duration::from_secs(1);
+
+/// Health of the device, besides the MQTT connection.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceDiagnostics {
+    /// `None` when the WiFi controller couldn't tell, e.g. while disconnected.
+    pub wifi_rssi: Option<i32>,
+    /// `None` until DHCP configured the interface.
+    pub ip_address: Option<Ipv4Addr>,
+    pub uptime: Duration,
+    pub free_heap: usize,
+    pub reset_reason: Option<ResetReason>,
+}
+
+#[cfg(target_os = "none")]
+impl DeviceDiagnostics {
+    /// Reads everything but the RSSI, which only the owner of the WiFi
+    /// controller can query.
+    pub fn read(stack: Stack<'_>, wifi_rssi: Option<i32>) -> Self {
+        Self {
+            wifi_rssi,
+            ip_address: stack.config_v4().map(|config| config.address.address()),
+            uptime: Duration::from_ticks(Instant::now().as_ticks()),
+            free_heap: esp_alloc::HEAP.free(),
+            reset_reason: esp_hal::system::reset_reason(),
+        }
+    }
+}
+
+/// Resets the device, after giving the pending messages, e.g. the answer to
+/// the reboot request, time to reach the broker.
+#[cfg(target_os = "none")]
+pub async fn reboot() -> ! {
+    info!("Rebooting");
+    Timer::after(REBOOT_DELAY).await;
+    esp_hal::system::software_reset();
+}
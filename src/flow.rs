@@ -0,0 +1,213 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_time::Instant;
+#[cfg(target_os = "none")]
+use embassy_time::{Duration, Timer};
+#[cfg(target_os = "none")]
+use esp_hal::gpio::{Input, InputConfig, InputPin, Pull};
+#[cfg(target_os = "none")]
+use esp_hal::pcnt::{channel::EdgeMode, unit::Unit};
+
+/// Pulses counted since boot, shared between the counting task and every reader.
+static PULSE_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// How often the hardware counter is read. Its 16 bits wrap after 65535 pulses,
+/// over two minutes at the highest flow a YF-S201 measures, so the executor may
+/// be blocked for far longer than this without losing a pulse.
+#[cfg(target_os = "none")]
+const PULSE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Glitch filter of the pulse counter, in APB clock cycles (12.8 µs, its maximum).
+#[cfg(target_os = "none")]
+const PULSE_FILTER_CYCLES: u16 = 1023;
+
+/// Pulses per litre of a YF-S201 (pulse frequency in Hz = 7.5 * flow in L/min).
+const YF_S201_PULSES_PER_LITRE: f32 = 450.0;
+
+#[derive(Clone, Copy)]
+pub struct FlowMeterConfig {
+    /// K-factor of the sensor, in pulses per litre.
+    pub pulses_per_litre: f32,
+}
+
+impl FlowMeterConfig {
+    pub fn new(pulses_per_litre: f32) -> Self {
+        Self { pulses_per_litre }
+    }
+}
+
+impl Default for FlowMeterConfig {
+    fn default() -> Self {
+        Self::new(YF_S201_PULSES_PER_LITRE)
+    }
+}
+
+/// Owns the hall-effect sensor input and counts its pulses with a PCNT unit, so
+/// none are missed while the executor is busy.
+#[cfg(target_os = "none")]
+pub struct FlowSensorFacade<'lifetime, const UNIT: usize> {
+    _flow_sensor_input: Input<'lifetime>,
+    _pulse_counter: Unit<'lifetime, UNIT>,
+    _last_count: u16,
+}
+
+#[cfg(target_os = "none")]
+impl<'lifetime, const UNIT: usize> FlowSensorFacade<'lifetime, UNIT> {
+    pub fn new(
+        pulse_counter: Unit<'lifetime, UNIT>,
+        flow_sensor_pin: impl InputPin + 'lifetime,
+    ) -> Self {
+        // The sensor has an open-collector output
+        let flow_sensor_input =
+            Input::new(flow_sensor_pin, InputConfig::default().with_pull(Pull::Up));
+
+        // Without limits the counter wraps around, which `run_counter` accounts for
+        pulse_counter.set_low_limit(None).unwrap();
+        pulse_counter.set_high_limit(None).unwrap();
+        pulse_counter.set_filter(Some(PULSE_FILTER_CYCLES)).unwrap();
+        pulse_counter
+            .channel0
+            .set_edge_signal(flow_sensor_input.peripheral_input());
+        pulse_counter
+            .channel0
+            .set_input_mode(EdgeMode::Hold, EdgeMode::Increment);
+        pulse_counter.clear();
+        pulse_counter.resume();
+
+        FlowSensorFacade {
+            _flow_sensor_input: flow_sensor_input,
+            _pulse_counter: pulse_counter,
+            _last_count: 0,
+        }
+    }
+
+    /// Moves the pulses counted in hardware into `PULSE_COUNT`.
+    pub async fn run_counter(&mut self) -> ! {
+        loop {
+            Timer::after(PULSE_POLL_INTERVAL).await;
+            let count = self._pulse_counter.value() as u16;
+            let new_pulses = count.wrapping_sub(self._last_count);
+            self._last_count = count;
+            PULSE_COUNT.fetch_add(new_pulses as u32, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Adds `pulses` as if the sensor had sent them.
+#[cfg(test)]
+pub(crate) fn add_pulses(pulses: u32) {
+    PULSE_COUNT.fetch_add(pulses, Ordering::Relaxed);
+}
+
+/// Converts the pulses counted by `FlowSensorFacade` into volumes.
+#[derive(Clone, Copy)]
+pub struct FlowMeter {
+    _config: FlowMeterConfig,
+}
+
+impl FlowMeter {
+    pub fn new(config: FlowMeterConfig) -> Self {
+        Self { _config: config }
+    }
+
+    pub fn pulses(&self) -> u32 {
+        PULSE_COUNT.load(Ordering::Relaxed)
+    }
+
+    pub fn total_litres(&self) -> f32 {
+        self.pulses_to_litres(self.pulses())
+    }
+
+    pub fn pulses_to_litres(&self, pulses: u32) -> f32 {
+        pulses as f32 / self._config.pulses_per_litre
+    }
+
+    pub fn litres_to_pulses(&self, litres: f32) -> u32 {
+        (litres * self._config.pulses_per_litre) as u32
+    }
+}
+
+/// Flow rate averaged between two consecutive calls to `read_litres_per_minute`.
+pub struct FlowRateMeter {
+    _flow_meter: FlowMeter,
+    _last_pulses: u32,
+    _last_reading: Instant,
+}
+
+impl FlowRateMeter {
+    pub fn new(flow_meter: FlowMeter) -> Self {
+        Self {
+            _flow_meter: flow_meter,
+            _last_pulses: flow_meter.pulses(),
+            _last_reading: Instant::now(),
+        }
+    }
+
+    pub fn read_litres_per_minute(&mut self) -> f32 {
+        let pulses = self._flow_meter.pulses();
+        let now = Instant::now();
+        let elapsed_ms = (now - self._last_reading).as_millis();
+        let litres = self
+            ._flow_meter
+            .pulses_to_litres(pulses.wrapping_sub(self._last_pulses));
+
+        self._last_pulses = pulses;
+        self._last_reading = now;
+
+        if elapsed_ms == 0 {
+            return 0.0;
+        }
+        litres * 60_000.0 / elapsed_ms as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::{Duration, MockDriver};
+
+    use super::*;
+
+    #[test]
+    fn default_k_factor_is_the_yf_s201() {
+        let flow_meter = FlowMeter::new(FlowMeterConfig::default());
+        assert_eq!(flow_meter.pulses_to_litres(450), 1.0);
+        assert_eq!(flow_meter.pulses_to_litres(225), 0.5);
+        assert_eq!(flow_meter.litres_to_pulses(2.0), 900);
+    }
+
+    #[test]
+    fn conversions_follow_the_configured_k_factor() {
+        let flow_meter = FlowMeter::new(FlowMeterConfig::new(98.0));
+        assert_eq!(flow_meter.pulses_to_litres(49), 0.5);
+        assert_eq!(flow_meter.litres_to_pulses(1.5), 147);
+        // Partial pulses can't be counted
+        assert_eq!(flow_meter.litres_to_pulses(0.01), 0);
+    }
+
+    #[test]
+    fn total_litres_counts_every_pulse_since_boot() {
+        let flow_meter = FlowMeter::new(FlowMeterConfig::default());
+        let litres = flow_meter.total_litres();
+        add_pulses(900);
+        assert_eq!(flow_meter.total_litres() - litres, 2.0);
+    }
+
+    #[test]
+    fn rate_is_averaged_between_readings() {
+        let mut flow_rate_meter = FlowRateMeter::new(FlowMeter::new(FlowMeterConfig::default()));
+
+        add_pulses(225);
+        MockDriver::get().advance(Duration::from_secs(30));
+        assert_eq!(flow_rate_meter.read_litres_per_minute(), 1.0);
+
+        MockDriver::get().advance(Duration::from_secs(30));
+        assert_eq!(flow_rate_meter.read_litres_per_minute(), 0.0);
+    }
+
+    #[test]
+    fn rate_is_zero_without_elapsed_time() {
+        let mut flow_rate_meter = FlowRateMeter::new(FlowMeter::new(FlowMeterConfig::default()));
+        add_pulses(450);
+        assert_eq!(flow_rate_meter.read_litres_per_minute(), 0.0);
+    }
+}
@@ -1,31 +1,633 @@
-use crate::mqtt::MqttMessage;
-use crate::sensors::SensorsValues;
+use crate::clock::DateTime;
+use crate::controller::{
+    ControllerMode, ControllerSetting, ControllerSettings, MAX_SAMPLE_INTERVAL, MIN_SAMPLE_INTERVAL,
+};
+use crate::diagnostics::{DeviceDiagnostics, FIRMWARE_VERSION};
+use crate::mqtt::{MessagePriority, MessageQos, MqttInboundMessage, MqttMessage, MqttMetrics};
+use crate::pump::{PumpFault, PumpStatistics, MAX_CONTINUOUS_RUNTIME};
+use crate::schedule::{ScheduleEntry, MAX_SCHEDULES};
+use crate::sensors::{CalibrationPoint, SensorsValues, SoilMoistureCalibration};
+use crate::zones::ZoneState;
 
 #[derive(Clone, Copy)]
 pub struct HomeAssistantFacadeConfig {
-    device_id: &'static str
+    device_id: &'static str,
+    /// WiFi MAC address, lets Home Assistant merge the device with other
+    /// integrations that see it on the network.
+    mac_address: Option<[u8; 6]>,
+    suggested_area: Option<&'static str>,
+    configuration_url: Option<&'static str>,
+    zone_platform: ZonePlatform,
 }
 
 impl HomeAssistantFacadeConfig {
     pub fn new(device_id: &'static str) -> Self {
         Self {
-            device_id: device_id
+            device_id,
+            mac_address: None,
+            suggested_area: None,
+            configuration_url: None,
+            zone_platform: ZonePlatform::Switch,
         }
     }
 
+    /// Also reads the optional `DEVICE_AREA`, `DEVICE_CONFIGURATION_URL` and
+    /// `ZONE_PLATFORM`.
+    #[cfg(target_os = "none")]
     pub fn new_from_env() -> Self {
         Self {
-            device_id: env!("DEVICE_NAME")
+            device_id: env!("DEVICE_NAME"),
+            mac_address: None,
+            suggested_area: option_env!("DEVICE_AREA"),
+            configuration_url: option_env!("DEVICE_CONFIGURATION_URL"),
+            zone_platform: option_env!("ZONE_PLATFORM")
+                .and_then(ZonePlatform::parse)
+                .unwrap_or(ZonePlatform::Switch),
         }
     }
+
+    pub fn with_mac_address(mut self, mac_address: [u8; 6]) -> Self {
+        self.mac_address = Some(mac_address);
+        self
+    }
+
+    pub fn with_zone_platform(mut self, zone_platform: ZonePlatform) -> Self {
+        self.zone_platform = zone_platform;
+        self
+    }
+}
+
+/// How zones show up in Home Assistant.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZonePlatform {
+    /// On and off only, as the first releases did.
+    Switch,
+    /// Opening, open, closing and closed.
+    Valve,
+}
+
+impl ZonePlatform {
+    /// Parses `switch` or `valve`, as used in the build environment.
+    pub fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("switch") {
+            Some(ZonePlatform::Switch)
+        } else if value.eq_ignore_ascii_case("valve") {
+            Some(ZonePlatform::Valve)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum PumpStatistic {
+    OnTime,
+    Cycles,
+    LastRunStart,
+    LastRunDuration,
+    Energy,
+}
+
+impl PumpStatistic {
+    pub const ALL: [PumpStatistic; 5] = [
+        PumpStatistic::OnTime,
+        PumpStatistic::Cycles,
+        PumpStatistic::LastRunStart,
+        PumpStatistic::LastRunDuration,
+        PumpStatistic::Energy,
+    ];
+}
+
+#[derive(Clone, Copy)]
+pub enum MqttMetric {
+    ConnectionAttempts,
+    TcpFailures,
+    TlsFailures,
+    BrokerFailures,
+    SessionLosses,
+    Reconnects,
+    MessagesSent,
+    MessagesDropped,
+    MessagesReceived,
+    SessionAge,
+}
+
+impl MqttMetric {
+    pub const ALL: [MqttMetric; 10] = [
+        MqttMetric::ConnectionAttempts,
+        MqttMetric::TcpFailures,
+        MqttMetric::TlsFailures,
+        MqttMetric::BrokerFailures,
+        MqttMetric::SessionLosses,
+        MqttMetric::Reconnects,
+        MqttMetric::MessagesSent,
+        MqttMetric::MessagesDropped,
+        MqttMetric::MessagesReceived,
+        MqttMetric::SessionAge,
+    ];
+}
+
+#[derive(Clone, Copy)]
+pub enum DeviceDiagnostic {
+    WifiRssi,
+    IpAddress,
+    Uptime,
+    FreeHeap,
+    ResetReason,
+}
+
+impl DeviceDiagnostic {
+    pub const ALL: [DeviceDiagnostic; 5] = [
+        DeviceDiagnostic::WifiRssi,
+        DeviceDiagnostic::IpAddress,
+        DeviceDiagnostic::Uptime,
+        DeviceDiagnostic::FreeHeap,
+        DeviceDiagnostic::ResetReason,
+    ];
+}
+
+/// Actions triggered from Home Assistant, which sends a message on their command
+/// topic each time they're pressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceButton {
+    /// Runs the controller zone for one watering pulse.
+    WaterNow,
+    CalibrateDry,
+    CalibrateWet,
+    Reboot,
+}
+
+impl DeviceButton {
+    pub const ALL: [DeviceButton; 4] = [
+        DeviceButton::WaterNow,
+        DeviceButton::CalibrateDry,
+        DeviceButton::CalibrateWet,
+        DeviceButton::Reboot,
+    ];
+
+    fn key(&self) -> &'static str {
+        match self {
+            DeviceButton::WaterNow => "water_now",
+            DeviceButton::CalibrateDry => "calibrate_dry",
+            DeviceButton::CalibrateWet => "calibrate_wet",
+            DeviceButton::Reboot => "reboot",
+        }
+    }
+}
+
+/// Set of values published together on their own retained state topic, so
+/// one group is never overwritten by the state of another.
+#[derive(Clone, Copy)]
+enum StateGroup {
+    Sensors,
+    Flow,
+    Pump,
+    PumpRejection,
+    PumpStatistics,
+    Zone(usize),
+    Controller,
+    Schedule(usize),
+    MqttMetrics,
+    DeviceDiagnostics,
+    Calibration,
+}
+
+impl fmt::Display for StateGroup {
+    /// Last levels of the state topic of the group.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateGroup::Sensors => write!(f, "sensors"),
+            StateGroup::Flow => write!(f, "flow"),
+            StateGroup::Pump => write!(f, "pump"),
+            StateGroup::PumpRejection => write!(f, "pump_rejection"),
+            StateGroup::PumpStatistics => write!(f, "pump_statistics"),
+            StateGroup::Zone(zone) => write!(f, "zone/{}", zone),
+            StateGroup::Controller => write!(f, "controller"),
+            StateGroup::Schedule(slot) => write!(f, "schedule/{}", slot),
+            StateGroup::MqttMetrics => write!(f, "mqtt_metrics"),
+            StateGroup::DeviceDiagnostics => write!(f, "diagnostics"),
+            StateGroup::Calibration => write!(f, "calibration"),
+        }
+    }
+}
+
+/// Components the registry has room for.
+pub const MAX_COMPONENTS: usize = 64;
+/// Buffer needed by the discovery document of the largest registry built at
+/// boot, for a device id of up to 48 characters.
+pub const MAX_DISCOVERY_DOCUMENT: usize = 24576;
+
+/// An entity of the device, announced in the discovery document.
+#[derive(Clone, Copy)]
+pub enum Component {
+    Temperature,
+    Humidity,
+    SoilMoisture,
+    TankEmpty,
+    TankLevel,
+    FlowRate,
+    WaterVolume,
+    Pump,
+    /// Switch of the first firmware, now starting and stopping zone 0.
+    PumpSwitch,
+    PumpFault,
+    PumpRejection,
+    PumpStatistic(PumpStatistic),
+    Zone(usize),
+    ZoneValve(usize),
+    ZoneRemainingRunTime(usize),
+    ControllerSetting(ControllerSetting),
+    Schedule(usize),
+    MqttMetric(MqttMetric),
+    DeviceDiagnostic(DeviceDiagnostic),
+    SoilMoistureCalibration(CalibrationPoint),
+    Button(DeviceButton),
+}
+
+impl Component {
+    /// Identifies the component in the document, also the field of its value
+    /// in the state topic unless its description has its own template.
+    fn key(&self) -> String<32> {
+        let mut key_buffer: String<32> = String::new();
+        match self.index() {
+            Some(index) => write!(&mut key_buffer, "{}_{}", self.description().key, index),
+            None => write!(&mut key_buffer, "{}", self.description().key),
+        }.ok();
+        key_buffer
+    }
+
+    /// Key in the document and suffix of the unique id the components of the
+    /// first firmware were discovered with. Kept so Home Assistant updates
+    /// their entities instead of adding new ones.
+    fn legacy_ids(&self) -> Option<(&'static str, &'static str)> {
+        match self {
+            Component::Temperature => Some(("temperature_cmp", "-temperature")),
+            Component::Humidity => Some(("humidity_cmp", "_humidity")),
+            Component::SoilMoisture => Some(("soil_cmp", "_soil")),
+            Component::PumpSwitch => Some(("pump_cmp", "_pump")),
+            _ => None,
+        }
+    }
+
+    fn document_key(&self) -> String<32> {
+        match self.legacy_ids() {
+            Some((document_key, _)) => String::try_from(document_key).unwrap_or_default(),
+            None => self.key(),
+        }
+    }
+
+    fn name(&self) -> Option<String<32>> {
+        let mut name_buffer: String<32> = String::new();
+        let name = self.description().name?;
+        match self.index() {
+            Some(index) => write!(&mut name_buffer, "{} {}", name, index),
+            None => write!(&mut name_buffer, "{}", name),
+        }.ok()?;
+        Some(name_buffer)
+    }
+
+    fn index(&self) -> Option<usize> {
+        match self {
+            Component::Zone(index)
+            | Component::ZoneValve(index)
+            | Component::ZoneRemainingRunTime(index)
+            | Component::Schedule(index) => Some(*index),
+            _ => None,
+        }
+    }
+
+    /// Group whose state topic carries the value, `None` for buttons.
+    fn state_group(&self) -> Option<StateGroup> {
+        match self {
+            Component::Temperature
+            | Component::Humidity
+            | Component::SoilMoisture
+            | Component::TankEmpty
+            | Component::TankLevel => Some(StateGroup::Sensors),
+            Component::FlowRate | Component::WaterVolume => Some(StateGroup::Flow),
+            Component::Pump | Component::PumpFault => Some(StateGroup::Pump),
+            Component::PumpSwitch => Some(StateGroup::Zone(0)),
+            Component::PumpRejection => Some(StateGroup::PumpRejection),
+            Component::PumpStatistic(_) => Some(StateGroup::PumpStatistics),
+            Component::Zone(zone)
+            | Component::ZoneValve(zone)
+            | Component::ZoneRemainingRunTime(zone) => Some(StateGroup::Zone(*zone)),
+            Component::ControllerSetting(_) => Some(StateGroup::Controller),
+            Component::Schedule(slot) => Some(StateGroup::Schedule(*slot)),
+            Component::MqttMetric(_) => Some(StateGroup::MqttMetrics),
+            Component::DeviceDiagnostic(_) => Some(StateGroup::DeviceDiagnostics),
+            Component::SoilMoistureCalibration(_) => Some(StateGroup::Calibration),
+            Component::Button(_) => None,
+        }
+    }
+
+    fn description(&self) -> ComponentDescription {
+        match self {
+            Component::Temperature => ComponentDescription {
+                device_class: Some("temperature"),
+                unit: Some("°C"),
+                ..ComponentDescription::new("sensor", "temperature", None)
+            },
+            Component::Humidity => ComponentDescription {
+                device_class: Some("humidity"),
+                unit: Some("%"),
+                ..ComponentDescription::new("sensor", "humidity", None)
+            },
+            Component::SoilMoisture => ComponentDescription {
+                device_class: Some("moisture"),
+                unit: Some("%"),
+                ..ComponentDescription::new("sensor", "soil_moisture", Some("Soil moisture"))
+            },
+            Component::TankEmpty => ComponentDescription {
+                device_class: Some("problem"),
+                ..ComponentDescription::new("binary_sensor", "tank_empty", Some("Tank empty"))
+            },
+            Component::TankLevel => ComponentDescription {
+                unit: Some("%"),
+                state_class: Some("measurement"),
+                ..ComponentDescription::new("sensor", "tank_level", Some("Tank level"))
+            },
+            Component::FlowRate => ComponentDescription {
+                device_class: Some("volume_flow_rate"),
+                unit: Some("L/min"),
+                state_class: Some("measurement"),
+                ..ComponentDescription::new("sensor", "flow_rate", Some("Flow rate"))
+            },
+            Component::WaterVolume => ComponentDescription {
+                device_class: Some("water"),
+                unit: Some("L"),
+                state_class: Some("total_increasing"),
+                ..ComponentDescription::new("sensor", "water_volume", Some("Water volume"))
+            },
+            Component::Pump => ComponentDescription {
+                device_class: Some("running"),
+                value_template: Some("{{ value_json.pump_state }}"),
+                ..ComponentDescription::new("binary_sensor", "pump_running", Some("Pump running"))
+            },
+            // Reads the state of zone 0 with either zone platform
+            Component::PumpSwitch => ComponentDescription {
+                value_template: Some("{{ 'ON' if value_json.zone_0 in ['ON', 'open', 'closing'] else 'OFF' }}"),
+                ..ComponentDescription::new("switch", "pump", Some("Pump"))
+            },
+            Component::PumpFault => ComponentDescription {
+                device_class: Some("problem"),
+                value_template: Some("{{ 'OFF' if value_json.pump_fault == 'none' else 'ON' }}"),
+                ..ComponentDescription::new("binary_sensor", "pump_fault", Some("Pump fault"))
+            },
+            Component::PumpRejection => {
+                ComponentDescription::new("sensor", "pump_rejection", Some("Pump last rejection"))
+            }
+            Component::PumpStatistic(statistic) => match statistic {
+                PumpStatistic::OnTime => ComponentDescription {
+                    device_class: Some("duration"),
+                    unit: Some("s"),
+                    state_class: Some("total_increasing"),
+                    ..ComponentDescription::new("sensor", "pump_on_time", Some("Pump total on time"))
+                },
+                PumpStatistic::Cycles => ComponentDescription {
+                    state_class: Some("total_increasing"),
+                    ..ComponentDescription::new("sensor", "pump_cycles", Some("Pump cycles"))
+                },
+                PumpStatistic::LastRunStart => ComponentDescription {
+                    device_class: Some("timestamp"),
+                    ..ComponentDescription::new("sensor", "pump_last_run_start", Some("Pump last run start"))
+                },
+                PumpStatistic::LastRunDuration => ComponentDescription {
+                    device_class: Some("duration"),
+                    unit: Some("s"),
+                    ..ComponentDescription::new("sensor", "pump_last_run_duration", Some("Pump last run duration"))
+                },
+                PumpStatistic::Energy => ComponentDescription {
+                    device_class: Some("energy"),
+                    unit: Some("Wh"),
+                    state_class: Some("total_increasing"),
+                    ..ComponentDescription::new("sensor", "pump_energy", Some("Pump energy"))
+                },
+            },
+            Component::Zone(_) => ComponentDescription::new("switch", "zone", Some("Zone")),
+            // Same key as the switch, the state field is read by either
+            Component::ZoneValve(_) => ComponentDescription {
+                device_class: Some("water"),
+                ..ComponentDescription::new("valve", "zone", Some("Zone"))
+            },
+            Component::ZoneRemainingRunTime(_) => ComponentDescription {
+                device_class: Some("duration"),
+                unit: Some("s"),
+                ..ComponentDescription::new("sensor", "zone_remaining", Some("Zone remaining time"))
+            },
+            Component::ControllerSetting(setting) => {
+                let (platform, name) = match setting {
+                    ControllerSetting::Mode => ("select", "Watering mode"),
+                    ControllerSetting::MoistureLow => ("number", "Moisture low"),
+                    ControllerSetting::MoistureHigh => ("number", "Moisture high"),
+                    ControllerSetting::PulseDuration => ("number", "Watering pulse"),
+                    ControllerSetting::SoakDuration => ("number", "Soak time"),
+                    ControllerSetting::MaxRuntime => ("number", "Max pump runtime"),
+                    ControllerSetting::SampleInterval => ("number", "Sample interval"),
+                };
+                let description = ComponentDescription {
+                    entity_category: Some("config"),
+                    ..ComponentDescription::new(platform, setting.key(), Some(name))
+                };
+                match setting {
+                    ControllerSetting::Mode => ComponentDescription {
+                        options: &CONTROLLER_MODE_OPTIONS,
+                        ..description
+                    },
+                    ControllerSetting::MoistureLow | ControllerSetting::MoistureHigh => ComponentDescription {
+                        unit: Some("%"),
+                        range: Some(NumberRange { min: 0, max: 100, step: 1 }),
+                        ..description
+                    },
+                    ControllerSetting::PulseDuration => ComponentDescription {
+                        unit: Some("s"),
+                        range: Some(NumberRange { min: 1, max: MAX_CONTINUOUS_RUNTIME.as_secs(), step: 1 }),
+                        ..description
+                    },
+                    ControllerSetting::SoakDuration => ComponentDescription {
+                        unit: Some("s"),
+                        range: Some(NumberRange { min: 0, max: 14400, step: 60 }),
+                        ..description
+                    },
+                    ControllerSetting::MaxRuntime => ComponentDescription {
+                        unit: Some("s"),
+                        range: Some(NumberRange { min: 1, max: MAX_CONTINUOUS_RUNTIME.as_secs(), step: 1 }),
+                        ..description
+                    },
+                    ControllerSetting::SampleInterval => ComponentDescription {
+                        unit: Some("s"),
+                        range: Some(NumberRange {
+                            min: MIN_SAMPLE_INTERVAL.as_secs(),
+                            max: MAX_SAMPLE_INTERVAL.as_secs(),
+                            step: 1,
+                        }),
+                        ..description
+                    },
+                }
+            }
+            Component::Schedule(_) => ComponentDescription {
+                entity_category: Some("config"),
+                max_length: Some(64),
+                ..ComponentDescription::new("text", "schedule", Some("Schedule"))
+            },
+            Component::MqttMetric(metric) => {
+                let (key, name) = match metric {
+                    MqttMetric::ConnectionAttempts => ("mqtt_connection_attempts", "MQTT connection attempts"),
+                    MqttMetric::TcpFailures => ("mqtt_tcp_failures", "MQTT TCP failures"),
+                    MqttMetric::TlsFailures => ("mqtt_tls_failures", "MQTT TLS failures"),
+                    MqttMetric::BrokerFailures => ("mqtt_broker_failures", "MQTT broker failures"),
+                    MqttMetric::SessionLosses => ("mqtt_session_losses", "MQTT session losses"),
+                    MqttMetric::Reconnects => ("mqtt_reconnects", "MQTT reconnects"),
+                    MqttMetric::MessagesSent => ("mqtt_messages_sent", "MQTT messages sent"),
+                    MqttMetric::MessagesDropped => ("mqtt_messages_dropped", "MQTT messages dropped"),
+                    MqttMetric::MessagesReceived => ("mqtt_messages_received", "MQTT messages received"),
+                    MqttMetric::SessionAge => ("mqtt_session_age", "MQTT session age"),
+                };
+                let description = ComponentDescription {
+                    entity_category: Some("diagnostic"),
+                    ..ComponentDescription::new("sensor", key, Some(name))
+                };
+                match metric {
+                    MqttMetric::SessionAge => ComponentDescription {
+                        device_class: Some("duration"),
+                        unit: Some("s"),
+                        ..description
+                    },
+                    _ => ComponentDescription {
+                        state_class: Some("total_increasing"),
+                        ..description
+                    },
+                }
+            }
+            Component::DeviceDiagnostic(diagnostic) => {
+                let description = match diagnostic {
+                    DeviceDiagnostic::WifiRssi => ComponentDescription {
+                        device_class: Some("signal_strength"),
+                        unit: Some("dBm"),
+                        state_class: Some("measurement"),
+                        ..ComponentDescription::new("sensor", "wifi_rssi", Some("WiFi signal"))
+                    },
+                    DeviceDiagnostic::IpAddress => {
+                        ComponentDescription::new("sensor", "ip_address", Some("IP address"))
+                    }
+                    DeviceDiagnostic::Uptime => ComponentDescription {
+                        device_class: Some("duration"),
+                        unit: Some("s"),
+                        ..ComponentDescription::new("sensor", "uptime", Some("Uptime"))
+                    },
+                    DeviceDiagnostic::FreeHeap => ComponentDescription {
+                        device_class: Some("data_size"),
+                        unit: Some("B"),
+                        state_class: Some("measurement"),
+                        ..ComponentDescription::new("sensor", "free_heap", Some("Free heap"))
+                    },
+                    DeviceDiagnostic::ResetReason => {
+                        ComponentDescription::new("sensor", "reset_reason", Some("Reset reason"))
+                    }
+                };
+                ComponentDescription {
+                    entity_category: Some("diagnostic"),
+                    ..description
+                }
+            }
+            Component::SoilMoistureCalibration(point) => {
+                let (key, name) = match point {
+                    CalibrationPoint::Dry => ("soil_moisture_dry", "Soil moisture dry value"),
+                    CalibrationPoint::Wet => ("soil_moisture_wet", "Soil moisture wet value"),
+                };
+                ComponentDescription {
+                    entity_category: Some("diagnostic"),
+                    ..ComponentDescription::new("sensor", key, Some(name))
+                }
+            }
+            Component::Button(button) => {
+                let name = match button {
+                    DeviceButton::WaterNow => "Water now",
+                    DeviceButton::CalibrateDry => "Calibrate dry",
+                    DeviceButton::CalibrateWet => "Calibrate wet",
+                    DeviceButton::Reboot => "Reboot",
+                };
+                let description = ComponentDescription::new("button", button.key(), Some(name));
+                match button {
+                    DeviceButton::WaterNow => description,
+                    DeviceButton::CalibrateDry | DeviceButton::CalibrateWet => ComponentDescription {
+                        entity_category: Some("config"),
+                        ..description
+                    },
+                    DeviceButton::Reboot => ComponentDescription {
+                        device_class: Some("restart"),
+                        entity_category: Some("config"),
+                        ..description
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// Components announced by the device, in the order they're registered.
+#[derive(Default)]
+pub struct ComponentRegistry {
+    _components: Vec<Component, MAX_COMPONENTS>,
+}
+
+impl ComponentRegistry {
+    pub fn new() -> Self {
+        Self {
+            _components: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, component: Component) {
+        if self._components.push(component).is_err() {
+            panic!("Too many Home Assistant components");
+        }
+    }
+
+    pub fn register_all(&mut self, components: impl IntoIterator<Item = Component>) {
+        for component in components {
+            self.register(component);
+        }
+    }
+
+    pub fn components(&self) -> &[Component] {
+        &self._components
+    }
+}
+
+/// Optional hardware fitted to the device, deciding which components exist.
+#[derive(Clone, Copy)]
+pub struct DeviceHardware {
+    pub zone_count: usize,
+    pub float_switch: bool,
+    pub tank_level_sensor: bool,
+    pub flow_meter: bool,
 }
 
 pub struct HomeAssistantFacade {
     _config: HomeAssistantFacadeConfig,
 }
 
-use core::fmt::Write;
-use heapless::String;
+use core::fmt::{self, Write};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::Duration;
+use heapless::{String, Vec};
+use serde::ser::{SerializeMap, SerializeStruct, Serializer};
+use serde::Serialize;
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+/// Sensor readings are refreshed every few seconds, older ones are only noise.
+const TELEMETRY_EXPIRY: Duration = Duration::from_secs(60);
+const DEVICE_MODEL: &str = "ESP32 watering controller";
+const DEVICE_MANUFACTURER: &str = "watering-system";
+/// Published by Home Assistant on its status topic once it has started.
+const STATUS_ONLINE: &str = "online";
+
+/// Raised to have the pump and zones state published again, e.g. once Home
+/// Assistant restarted.
+pub static ZONES_STATE_REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Raised to have the sensors read and published without waiting for the next period.
+pub static SENSORS_STATE_REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
 impl HomeAssistantFacade {
     pub fn new(config: HomeAssistantFacadeConfig) -> Self {
@@ -34,135 +636,962 @@ impl HomeAssistantFacade {
         }
     }
 
+    /// Identifies the device in every topic, so it's unique on the broker.
+    pub fn device_id(&self) -> &'static str {
+        self._config.device_id
+    }
+
     pub fn get_pump_state_mqtt_message(
         &self, 
-        pump_on: bool
+        pump_on: bool,
+        pump_fault: Option<PumpFault>,
     ) -> Option<MqttMessage> {
-        let mut topic_buffer: String<128> = String::new();
         let mut message_buffer: String<256> = String::new();
 
-        write!(&mut topic_buffer, "homeassistant/device/{}/state", self._config.device_id).ok()?;
+        let topic_buffer = self.get_state_topic(StateGroup::Pump);
+        write!(&mut message_buffer,
+            r#"{{"pump_state":"{}","pump_fault":"{}"}}"#,
+            if pump_on {"ON"} else {"OFF"},
+            pump_fault.map(|fault| fault.as_str()).unwrap_or("none")
+        ).ok()?;
+
+        MqttMessage::new(
+            topic_buffer.as_str(),
+            message_buffer.as_str()
+        )
+        .map(|message| {
+            message
+                .with_retain(true)
+                .with_content_type(JSON_CONTENT_TYPE)
+                .with_coalesce_key("pump")
+        })
+    }
+
+    /// Every component of a device fitted with `hardware`. The discovery
+    /// document lists every entity at once, so it can only be built once the
+    /// optional hardware is known.
+    pub fn get_component_registry(&self, hardware: DeviceHardware) -> ComponentRegistry {
+        let mut components = ComponentRegistry::new();
+        components.register_all([Component::Temperature, Component::Humidity, Component::SoilMoisture]);
+        components.register_all([
+            Component::SoilMoistureCalibration(CalibrationPoint::Dry),
+            Component::SoilMoistureCalibration(CalibrationPoint::Wet),
+        ]);
+        if hardware.float_switch || hardware.tank_level_sensor {
+            components.register(Component::TankEmpty);
+        }
+        if hardware.tank_level_sensor {
+            components.register(Component::TankLevel);
+        }
+        if hardware.flow_meter {
+            components.register_all([Component::FlowRate, Component::WaterVolume]);
+        }
+        components.register_all([Component::Pump, Component::PumpFault, Component::PumpRejection]);
+        components.register_all(PumpStatistic::ALL.map(Component::PumpStatistic));
+        components.register_all((0..hardware.zone_count).map(|zone| self.get_zone_component(zone)));
+        if hardware.zone_count > 0 {
+            components.register(Component::PumpSwitch);
+        }
+        components.register_all((0..hardware.zone_count).map(Component::ZoneRemainingRunTime));
+        components.register_all(ControllerSetting::ALL.map(Component::ControllerSetting));
+        components.register_all((0..MAX_SCHEDULES).map(Component::Schedule));
+        components.register_all(MqttMetric::ALL.map(Component::MqttMetric));
+        components.register_all(DeviceDiagnostic::ALL.map(Component::DeviceDiagnostic));
+        components.register_all(DeviceButton::ALL.map(Component::Button));
+        components
+    }
+
+    /// Zone component of the platform set in the configuration.
+    pub fn get_zone_component(&self, zone: usize) -> Component {
+        match self._config.zone_platform {
+            ZonePlatform::Switch => Component::Zone(zone),
+            ZonePlatform::Valve => Component::ZoneValve(zone),
+        }
+    }
+
+    /// `remaining_run_time` is reported as zero when not known.
+    pub fn get_zone_state_mqtt_message(
+        &self,
+        zone: usize,
+        zone_state: ZoneState,
+        remaining_run_time: Option<Duration>,
+    ) -> Option<MqttMessage> {
+        let mut key_buffer: String<16> = String::new();
+        let mut message_buffer: String<256> = String::new();
+
+        let topic_buffer = self.get_state_topic(StateGroup::Zone(zone));
+        write!(&mut key_buffer, "zone_{}", zone).ok()?;
         write!(&mut message_buffer,
-            r#"{{"pump_state":"{}"}}"#,
-            if pump_on {"ON"} else {"OFF"}
+            r#"{{"zone_{}":"{}","zone_remaining_{}":{}}}"#,
+            zone,
+            match self._config.zone_platform {
+                ZonePlatform::Switch => if zone_state.is_open() {"ON"} else {"OFF"},
+                ZonePlatform::Valve => zone_state.as_str(),
+            },
+            zone,
+            remaining_run_time.map(|duration| duration.as_secs()).unwrap_or(0),
         ).ok()?;
 
         MqttMessage::new(
             topic_buffer.as_str(),
             message_buffer.as_str()
         )
+        .map(|message| {
+            message
+                .with_retain(true)
+                .with_content_type(JSON_CONTENT_TYPE)
+                .with_coalesce_key(key_buffer.as_str())
+        })
+    }
+
+    pub fn get_pump_rejection_mqtt_message(
+        &self,
+        rejection: &str,
+    ) -> Option<MqttMessage> {
+        let mut message_buffer: String<256> = String::new();
+
+        let topic_buffer = self.get_state_topic(StateGroup::PumpRejection);
+        write!(&mut message_buffer,
+            r#"{{"pump_rejection":"{}"}}"#,
+            rejection
+        ).ok()?;
+
+        MqttMessage::new(
+            topic_buffer.as_str(),
+            message_buffer.as_str()
+        )
+        .map(|message| message.with_content_type(JSON_CONTENT_TYPE))
     }
 
     pub fn get_sensors_state_mqtt_message(
         &self, 
         sensors_values: SensorsValues,
     ) -> Option<MqttMessage> {
-        let mut topic_buffer: String<128> = String::new();
         let mut message_buffer: String<256> = String::new();
 
-        write!(&mut topic_buffer, "homeassistant/device/{}/state", self._config.device_id).ok()?;
+        let topic_buffer = self.get_state_topic(StateGroup::Sensors);
         write!(&mut message_buffer,
-            r#"{{"temperature":{},"humidity":{},"soil_moisture":{}}}"#,
+            r#"{{"temperature":{},"humidity":{},"soil_moisture":{},"tank_empty":"{}""#,
             sensors_values.temperature,
             sensors_values.humidity,
             sensors_values.soil_moisture_sensor_value,
+            if sensors_values.tank_empty {"ON"} else {"OFF"},
         ).ok()?;
+        if let Some(tank_level) = sensors_values.tank_level {
+            write!(&mut message_buffer, r#","tank_level":{:.1}"#, tank_level).ok()?;
+        }
+        message_buffer.push('}').ok()?;
 
         MqttMessage::new(
             topic_buffer.as_str(),
             message_buffer.as_str()
         )
+        .map(|message| {
+            message
+                .with_qos(MessageQos::AtMostOnce)
+                .with_message_expiry(TELEMETRY_EXPIRY)
+                .with_content_type(JSON_CONTENT_TYPE)
+                .with_priority(MessagePriority::Telemetry)
+                .with_coalesce_key("sensors")
+        })
     }
 
-    pub fn get_discovery_message_temperature(&self) -> Option<MqttMessage> {
-        let mut topic_buffer: String<128> = String::new();
-        let mut message_buffer: String<2048> = String::new();
-        
-        write!(&mut topic_buffer, "homeassistant/device/{}/config", self._config.device_id).ok()?;
+    pub fn get_pump_statistics_mqtt_message(
+        &self,
+        statistics: PumpStatistics,
+    ) -> Option<MqttMessage> {
+        let mut message_buffer: String<256> = String::new();
+
+        let topic_buffer = self.get_state_topic(StateGroup::PumpStatistics);
         write!(&mut message_buffer,
-r#"{{
-"dev":{{"ids":"{id}","name":"WateringSystem"}},
-"o": {{"name":"watering-system"}},
-"cmps":{{"temperature_cmp":{{"p":"sensor","dev_cla":"temperature","unit_of_measurement":"°C","val_tpl":"{{{{ value_json.temperature }}}}","unique_id":"{id}-temperature"}}}},
-"state_topic":"homeassistant/device/{id}/state"
-}}"#,
-            id = self._config.device_id
-        ).unwrap();
+            r#"{{"pump_on_time":{},"pump_cycles":{},"pump_last_run_duration":{},"pump_energy":{:.3}"#,
+            statistics.total_on_time_s,
+            statistics.cycles,
+            statistics.last_run_duration_s,
+            statistics.energy_wh,
+        ).ok()?;
+        if let Some(last_run_start_s) = statistics.last_run_start_s {
+            write!(&mut message_buffer,
+                r#","pump_last_run_start":"{}+00:00""#,
+                DateTime::from_unix(last_run_start_s as i64)
+            ).ok()?;
+        }
+        message_buffer.push('}').ok()?;
 
         MqttMessage::new(
             topic_buffer.as_str(),
             message_buffer.as_str()
         )
+        .map(|message| {
+            message
+                .with_retain(true)
+                .with_content_type(JSON_CONTENT_TYPE)
+                .with_coalesce_key("pump_statistics")
+        })
     }
 
-    pub fn get_discovery_message_humidity(&self) -> Option<MqttMessage> {
-        let mut topic_buffer: String<128> = String::new();
-        let mut message_buffer: String<2048> = String::new();
-        
-        write!(&mut topic_buffer, "homeassistant/device/{}/config", self._config.device_id).ok()?;
+    pub fn get_mqtt_metrics_mqtt_message(&self, metrics: MqttMetrics) -> Option<MqttMessage> {
+        let mut message_buffer: String<512> = String::new();
+
+        let topic_buffer = self.get_state_topic(StateGroup::MqttMetrics);
         write!(&mut message_buffer,
-r#"{{
-"dev":{{"ids":"{id}","name":"WateringSystem"}},
-"o": {{"name":"watering-system"}},
-"cmps":{{"humidity_cmp":{{"p":"sensor","dev_cla":"humidity","unit_of_measurement":"%","val_tpl":"{{{{ value_json.humidity }}}}","unique_id":"{id}_humidity"}}}},
-"state_topic":"homeassistant/device/{id}/state"
-}}"#,
-            id = self._config.device_id
-        ).unwrap();
+            r#"{{"mqtt_connection_attempts":{},"mqtt_tcp_failures":{},"mqtt_tls_failures":{},"mqtt_broker_failures":{},"mqtt_session_losses":{},"mqtt_reconnects":{},"mqtt_messages_sent":{},"mqtt_messages_dropped":{},"mqtt_messages_received":{}"#,
+            metrics.connection_attempts,
+            metrics.tcp_failures,
+            metrics.tls_failures,
+            metrics.broker_failures,
+            metrics.session_losses,
+            metrics.reconnects,
+            metrics.messages_sent,
+            metrics.messages_dropped,
+            metrics.messages_received,
+        ).ok()?;
+        if let Some(session_age) = metrics.session_age {
+            write!(&mut message_buffer, r#","mqtt_session_age":{}"#, session_age.as_secs()).ok()?;
+        }
+        message_buffer.push('}').ok()?;
 
         MqttMessage::new(
             topic_buffer.as_str(),
             message_buffer.as_str()
         )
+        .map(|message| {
+            message
+                .with_qos(MessageQos::AtMostOnce)
+                .with_message_expiry(TELEMETRY_EXPIRY)
+                .with_content_type(JSON_CONTENT_TYPE)
+                .with_priority(MessagePriority::Telemetry)
+                .with_coalesce_key("mqtt_metrics")
+        })
     }
 
+    pub fn get_device_diagnostics_mqtt_message(
+        &self,
+        diagnostics: DeviceDiagnostics,
+    ) -> Option<MqttMessage> {
+        let mut message_buffer: String<256> = String::new();
 
-    pub fn get_discovery_message_soil_moisture(&self) -> Option<MqttMessage> {
-        let mut topic_buffer: String<128> = String::new();
-        let mut message_buffer: String<2048> = String::new();
-        
-        write!(&mut topic_buffer, "homeassistant/device/{}/config", self._config.device_id).ok()?;
+        let topic_buffer = self.get_state_topic(StateGroup::DeviceDiagnostics);
         write!(&mut message_buffer,
-r#"{{
-"dev":{{"ids":"{id}","name":"WateringSystem"}},
-"o": {{"name":"watering-system"}},
-"cmps":{{"soil_cmp":{{"p":"sensor","name":"Soil moisture","unit_of_measurement":"%","dev_cla":"moisture","val_tpl":"{{{{ value_json.soil_moisture }}}}","unique_id":"{id}_soil"}}}},
-"state_topic":"homeassistant/device/{id}/state"
-}}"#,
-            id = self._config.device_id
-        ).unwrap();
+            r#"{{"uptime":{},"free_heap":{}"#,
+            diagnostics.uptime.as_secs(),
+            diagnostics.free_heap,
+        ).ok()?;
+        match diagnostics.reset_reason {
+            Some(reset_reason) => write!(&mut message_buffer, r#","reset_reason":"{:?}""#, reset_reason),
+            None => write!(&mut message_buffer, r#","reset_reason":"unknown""#),
+        }.ok()?;
+        if let Some(wifi_rssi) = diagnostics.wifi_rssi {
+            write!(&mut message_buffer, r#","wifi_rssi":{}"#, wifi_rssi).ok()?;
+        }
+        if let Some(ip_address) = diagnostics.ip_address {
+            write!(&mut message_buffer, r#","ip_address":"{}""#, ip_address).ok()?;
+        }
+        message_buffer.push('}').ok()?;
 
         MqttMessage::new(
             topic_buffer.as_str(),
             message_buffer.as_str()
         )
+        .map(|message| {
+            message
+                .with_qos(MessageQos::AtMostOnce)
+                .with_message_expiry(TELEMETRY_EXPIRY)
+                .with_content_type(JSON_CONTENT_TYPE)
+                .with_priority(MessagePriority::Telemetry)
+                .with_coalesce_key("device_diagnostics")
+        })
     }
 
-    pub fn get_discovery_message_pump(&self) -> Option<MqttMessage> {
-        let mut topic_buffer: String<128> = String::new();
-        let mut message_buffer: String<2048> = String::new();
-        
-        write!(&mut topic_buffer, "homeassistant/device/{}/config", self._config.device_id).ok()?;
+    pub fn get_controller_state_mqtt_message(
+        &self,
+        settings: ControllerSettings,
+    ) -> Option<MqttMessage> {
+        let mut message_buffer: String<256> = String::new();
+
+        let topic_buffer = self.get_state_topic(StateGroup::Controller);
+        write!(&mut message_buffer,
+            r#"{{"controller_mode":"{}","moisture_low":{},"moisture_high":{},"pulse_s":{},"soak_s":{},"max_runtime_s":{},"sample_interval_s":{}}}"#,
+            settings.mode.as_str(),
+            settings.moisture_low,
+            settings.moisture_high,
+            settings.pulse_s,
+            settings.soak_s,
+            settings.max_runtime_s,
+            settings.sample_interval_s,
+        ).ok()?;
+
+        MqttMessage::new(
+            topic_buffer.as_str(),
+            message_buffer.as_str()
+        )
+        .map(|message| {
+            message
+                .with_retain(true)
+                .with_content_type(JSON_CONTENT_TYPE)
+                .with_coalesce_key("controller")
+        })
+    }
+
+    pub fn get_calibration_state_mqtt_message(
+        &self,
+        calibration: SoilMoistureCalibration,
+    ) -> Option<MqttMessage> {
+        let mut message_buffer: String<128> = String::new();
+
+        let topic_buffer = self.get_state_topic(StateGroup::Calibration);
         write!(&mut message_buffer,
-r#"{{
-"dev":{{"ids":"{id}","name":"WateringSystem"}},
-"o": {{"name":"watering-system"}},
-"cmps":{{"pump_cmp":{{"p":"switch","name":"Pump","command_topic":"{topic}","val_tpl":"{{{{ value_json.pump_state }}}}","unique_id":"{id}_pump"}}}},
-"state_topic":"homeassistant/device/{id}/state"
-}}"#,
-            id = self._config.device_id,
-            topic = self.get_pump_topic().as_str()
-        ).unwrap();
+            r#"{{"soil_moisture_dry":{},"soil_moisture_wet":{}}}"#,
+            calibration.dry_value,
+            calibration.wet_value,
+        ).ok()?;
 
         MqttMessage::new(
             topic_buffer.as_str(),
             message_buffer.as_str()
         )
+        .map(|message| {
+            message
+                .with_retain(true)
+                .with_content_type(JSON_CONTENT_TYPE)
+                .with_coalesce_key("calibration")
+        })
     }
 
+    pub fn get_schedule_state_mqtt_message(
+        &self,
+        slot: usize,
+        entry: Option<&ScheduleEntry>,
+    ) -> Option<MqttMessage> {
+        let mut key_buffer: String<16> = String::new();
+        let mut message_buffer: String<128> = String::new();
+
+        let topic_buffer = self.get_state_topic(StateGroup::Schedule(slot));
+        write!(&mut key_buffer, "schedule_{}", slot).ok()?;
+        match entry {
+            Some(entry) => write!(&mut message_buffer, r#"{{"schedule_{}":"{}"}}"#, slot, entry),
+            None => write!(&mut message_buffer, r#"{{"schedule_{}":""}}"#, slot),
+        }.ok()?;
+
+        MqttMessage::new(
+            topic_buffer.as_str(),
+            message_buffer.as_str()
+        )
+        .map(|message| {
+            message
+                .with_retain(true)
+                .with_content_type(JSON_CONTENT_TYPE)
+                .with_coalesce_key(key_buffer.as_str())
+        })
+    }
+
+    pub fn get_flow_state_mqtt_message(
+        &self,
+        flow_rate_l_per_min: f32,
+        total_volume_l: f32,
+    ) -> Option<MqttMessage> {
+        let mut message_buffer: String<256> = String::new();
+
+        let topic_buffer = self.get_state_topic(StateGroup::Flow);
+        write!(&mut message_buffer,
+            r#"{{"flow_rate":{:.2},"water_volume":{:.3}}}"#,
+            flow_rate_l_per_min,
+            total_volume_l,
+        ).ok()?;
+
+        MqttMessage::new(
+            topic_buffer.as_str(),
+            message_buffer.as_str()
+        )
+        .map(|message| {
+            message
+                .with_qos(MessageQos::AtMostOnce)
+                .with_message_expiry(TELEMETRY_EXPIRY)
+                .with_content_type(JSON_CONTENT_TYPE)
+                .with_priority(MessagePriority::Telemetry)
+                .with_coalesce_key("flow")
+        })
+    }
+
+    /// Retained device discovery document announcing every registered component.
+    /// The document is serialised into `buffer`, which the message refers to so
+    /// it can be published again without being rebuilt.
+    pub fn get_discovery_mqtt_message(
+        &self,
+        components: &ComponentRegistry,
+        buffer: &'static mut [u8],
+    ) -> Option<MqttMessage> {
+        let mut topic_buffer: String<128> = String::new();
+        let document = DiscoveryDocument {
+            facade: self,
+            components: components.components(),
+        };
+        let length = serde_json_core::to_slice(&document, buffer).ok()?;
+        let buffer: &'static [u8] = buffer;
+
+        write!(&mut topic_buffer, "homeassistant/device/{}/config", self._config.device_id).ok()?;
+        MqttMessage::new_static(
+            topic_buffer.as_str(),
+            core::str::from_utf8(&buffer[..length]).ok()?
+        )
+        .map(|message| {
+            message
+                .with_retain(true)
+                .with_content_type(JSON_CONTENT_TYPE)
+        })
+    }
+
+    fn get_state_topic(&self, group: StateGroup) -> String<128> {
+        let mut topic_buffer: String<128> = String::new();
+        write!(&mut topic_buffer, "homeassistant/device/{}/state/{}", self._config.device_id, group).ok();
+        topic_buffer
+    }
+
+    fn get_command_topic(&self, component: Component) -> Option<String<128>> {
+        match component {
+            Component::Zone(zone) | Component::ZoneValve(zone) => Some(self.get_zone_command_topic(zone)),
+            Component::PumpSwitch => Some(self.get_pump_topic()),
+            Component::ControllerSetting(setting) => Some(self.get_controller_command_topic(setting)),
+            Component::Schedule(slot) => Some(self.get_schedule_command_topic(slot)),
+            Component::Button(button) => Some(self.get_button_command_topic(button)),
+            _ => None,
+        }
+    }
+
+    pub fn get_zone_command_topic(&self, zone: usize) -> String<128> {
+        let mut topic_buffer: String<128> = String::new();
+        write!(&mut topic_buffer, "homeassistant/device/{}/zone/{}/set", self._config.device_id, zone).ok();
+        topic_buffer
+    }
+
+    /// Command topic of the pump switch of the first firmware, kept as an alias
+    /// of the zone 0 command topic.
     pub fn get_pump_topic(&self) -> String<128> {
         let mut topic_buffer: String<128> = String::new();
         write!(&mut topic_buffer, "homeassistant/device/{}/pump", self._config.device_id).ok();
         topic_buffer
     }
-}
\ No newline at end of file
+
+    pub fn get_controller_command_topic(&self, setting: ControllerSetting) -> String<128> {
+        let mut topic_buffer: String<128> = String::new();
+        write!(&mut topic_buffer, "homeassistant/device/{}/controller/{}/set", self._config.device_id, setting.key()).ok();
+        topic_buffer
+    }
+
+    pub fn get_schedule_command_topic(&self, slot: usize) -> String<128> {
+        let mut topic_buffer: String<128> = String::new();
+        write!(&mut topic_buffer, "homeassistant/device/{}/schedule/{}/set", self._config.device_id, slot).ok();
+        topic_buffer
+    }
+
+    pub fn get_button_command_topic(&self, button: DeviceButton) -> String<128> {
+        let mut topic_buffer: String<128> = String::new();
+        write!(&mut topic_buffer, "homeassistant/device/{}/button/{}/press", self._config.device_id, button.key()).ok();
+        topic_buffer
+    }
+
+    /// Topic on which the broker publishes the Last Will of the device.
+    pub fn get_availability_topic(&self) -> String<128> {
+        let mut topic_buffer: String<128> = String::new();
+        write!(&mut topic_buffer, "homeassistant/device/{}/availability", self._config.device_id).ok();
+        topic_buffer
+    }
+
+    /// Topic on which Home Assistant announces it's online or going offline.
+    pub fn get_status_topic(&self) -> String<128> {
+        let mut topic_buffer: String<128> = String::new();
+        write!(&mut topic_buffer, "homeassistant/status").ok();
+        topic_buffer
+    }
+
+    /// Whether `message` on the status topic tells Home Assistant has (re)started.
+    pub fn is_online_status(&self, message: &MqttInboundMessage) -> bool {
+        message.content.trim() == STATUS_ONLINE
+    }
+
+    /// Topic of the management RPC requests, see `rpc::RpcRequest`.
+    pub fn get_rpc_topic(&self) -> String<128> {
+        let mut topic_buffer: String<128> = String::new();
+        write!(&mut topic_buffer, "homeassistant/device/{}/rpc", self._config.device_id).ok();
+        topic_buffer
+    }
+
+    /// Topic filter matching the command topic of every zone.
+    pub fn get_zone_command_topic_filter(&self) -> String<128> {
+        let mut topic_buffer: String<128> = String::new();
+        write!(&mut topic_buffer, "homeassistant/device/{}/zone/+/set", self._config.device_id).ok();
+        topic_buffer
+    }
+
+    /// Topic filter matching the command topic of every controller setting.
+    pub fn get_controller_command_topic_filter(&self) -> String<128> {
+        let mut topic_buffer: String<128> = String::new();
+        write!(&mut topic_buffer, "homeassistant/device/{}/controller/+/set", self._config.device_id).ok();
+        topic_buffer
+    }
+
+    /// Topic filter matching the command topic of every schedule slot.
+    pub fn get_schedule_command_topic_filter(&self) -> String<128> {
+        let mut topic_buffer: String<128> = String::new();
+        write!(&mut topic_buffer, "homeassistant/device/{}/schedule/+/set", self._config.device_id).ok();
+        topic_buffer
+    }
+
+    /// Topic filter matching the command topic of every button.
+    pub fn get_button_command_topic_filter(&self) -> String<128> {
+        let mut topic_buffer: String<128> = String::new();
+        write!(&mut topic_buffer, "homeassistant/device/{}/button/+/press", self._config.device_id).ok();
+        topic_buffer
+    }
+
+    /// Returns the zone addressed by a zone command topic.
+    pub fn parse_zone_command_topic(&self, topic: &str) -> Option<usize> {
+        if topic == self.get_pump_topic().as_str() {
+            return Some(0);
+        }
+
+        let mut prefix_buffer: String<128> = String::new();
+        write!(&mut prefix_buffer, "homeassistant/device/{}/zone/", self._config.device_id).ok()?;
+
+        topic
+            .strip_prefix(prefix_buffer.as_str())?
+            .strip_suffix("/set")?
+            .parse()
+            .ok()
+    }
+
+    /// Returns the setting addressed by a controller command topic.
+    pub fn parse_controller_command_topic(&self, topic: &str) -> Option<ControllerSetting> {
+        let mut prefix_buffer: String<128> = String::new();
+        write!(&mut prefix_buffer, "homeassistant/device/{}/controller/", self._config.device_id).ok()?;
+
+        ControllerSetting::from_key(
+            topic
+                .strip_prefix(prefix_buffer.as_str())?
+                .strip_suffix("/set")?
+        )
+    }
+
+    /// Returns the slot addressed by a schedule command topic.
+    pub fn parse_schedule_command_topic(&self, topic: &str) -> Option<usize> {
+        let mut prefix_buffer: String<128> = String::new();
+        write!(&mut prefix_buffer, "homeassistant/device/{}/schedule/", self._config.device_id).ok()?;
+
+        topic
+            .strip_prefix(prefix_buffer.as_str())?
+            .strip_suffix("/set")?
+            .parse()
+            .ok()
+    }
+
+    /// Returns the button addressed by a button command topic.
+    pub fn parse_button_command_topic(&self, topic: &str) -> Option<DeviceButton> {
+        let mut prefix_buffer: String<128> = String::new();
+        write!(&mut prefix_buffer, "homeassistant/device/{}/button/", self._config.device_id).ok()?;
+
+        let key = topic
+            .strip_prefix(prefix_buffer.as_str())?
+            .strip_suffix("/press")?;
+        DeviceButton::ALL.into_iter().find(|button| button.key() == key)
+    }
+}
+
+const CONTROLLER_MODE_OPTIONS: [&str; 4] = [
+    ControllerMode::Auto.as_str(),
+    ControllerMode::Manual.as_str(),
+    ControllerMode::Schedule.as_str(),
+    ControllerMode::Off.as_str(),
+];
+
+struct NumberRange {
+    min: u64,
+    max: u64,
+    step: u64,
+}
+
+/// Configuration of a component that doesn't depend on the device.
+struct ComponentDescription {
+    platform: &'static str,
+    key: &'static str,
+    name: Option<&'static str>,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    state_class: Option<&'static str>,
+    entity_category: Option<&'static str>,
+    options: &'static [&'static str],
+    range: Option<NumberRange>,
+    max_length: Option<u32>,
+    /// Replaces the template reading the field named after the key.
+    value_template: Option<&'static str>,
+}
+
+impl ComponentDescription {
+    const fn new(platform: &'static str, key: &'static str, name: Option<&'static str>) -> Self {
+        Self {
+            platform,
+            key,
+            name,
+            device_class: None,
+            unit: None,
+            state_class: None,
+            entity_category: None,
+            options: &[],
+            range: None,
+            max_length: None,
+            value_template: None,
+        }
+    }
+}
+
+/// Serialises as a JSON string of its `Display` output.
+struct DisplayStr<T: fmt::Display>(T);
+
+impl<T: fmt::Display> Serialize for DisplayStr<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.0)
+    }
+}
+
+#[derive(Serialize)]
+struct DeviceInfo {
+    ids: &'static str,
+    name: &'static str,
+    #[serde(rename = "sw")]
+    sw_version: &'static str,
+    #[serde(rename = "mdl")]
+    model: &'static str,
+    #[serde(rename = "mf")]
+    manufacturer: &'static str,
+    #[serde(rename = "cns", skip_serializing_if = "Option::is_none")]
+    connections: Option<[(&'static str, DisplayStr<MacAddress>); 1]>,
+    #[serde(rename = "cu", skip_serializing_if = "Option::is_none")]
+    configuration_url: Option<&'static str>,
+    #[serde(rename = "sa", skip_serializing_if = "Option::is_none")]
+    suggested_area: Option<&'static str>,
+}
+
+/// Formats as `aa:bb:cc:dd:ee:ff`, as Home Assistant expects in connections.
+struct MacAddress([u8; 6]);
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+#[derive(Serialize)]
+struct OriginInfo {
+    name: &'static str,
+}
+
+/// Device based discovery document, one per device with every component in
+/// `cmps`: a document published later replaces the whole device.
+struct DiscoveryDocument<'a> {
+    facade: &'a HomeAssistantFacade,
+    components: &'a [Component],
+}
+
+impl Serialize for DiscoveryDocument<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let device_id = self.facade._config.device_id;
+
+        let mut document = serializer.serialize_struct("DiscoveryDocument", 4)?;
+        document.serialize_field("dev", &DeviceInfo {
+            ids: device_id,
+            name: "WateringSystem",
+            sw_version: FIRMWARE_VERSION,
+            model: DEVICE_MODEL,
+            manufacturer: DEVICE_MANUFACTURER,
+            connections: self.facade._config.mac_address
+                .map(|mac_address| [("mac", DisplayStr(MacAddress(mac_address)))]),
+            configuration_url: self.facade._config.configuration_url,
+            suggested_area: self.facade._config.suggested_area,
+        })?;
+        document.serialize_field("o", &OriginInfo {
+            name: "watering-system",
+        })?;
+        document.serialize_field("avty_t", self.facade.get_availability_topic().as_str())?;
+        document.serialize_field("cmps", &DiscoveryComponents(self))?;
+        document.end()
+    }
+}
+
+struct DiscoveryComponents<'a>(&'a DiscoveryDocument<'a>);
+
+impl Serialize for DiscoveryComponents<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut components = serializer.serialize_map(Some(self.0.components.len()))?;
+        for component in self.0.components {
+            components.serialize_entry(component.document_key().as_str(), &ComponentConfig {
+                facade: self.0.facade,
+                component: *component,
+            })?;
+        }
+        components.end()
+    }
+}
+
+struct ComponentConfig<'a> {
+    facade: &'a HomeAssistantFacade,
+    component: Component,
+}
+
+impl Serialize for ComponentConfig<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let description = self.component.description();
+        let key = self.component.key();
+
+        // Absent options are left out rather than set to null
+        let mut config = serializer.serialize_struct("ComponentConfig", 15)?;
+        config.serialize_field("p", description.platform)?;
+        if let Some(name) = self.component.name() {
+            config.serialize_field("name", name.as_str())?;
+        }
+        if let Some(device_class) = description.device_class {
+            config.serialize_field("dev_cla", device_class)?;
+        }
+        if let Some(unit) = description.unit {
+            config.serialize_field("unit_of_meas", unit)?;
+        }
+        if let Some(state_class) = description.state_class {
+            config.serialize_field("stat_cla", state_class)?;
+        }
+        if let Some(entity_category) = description.entity_category {
+            config.serialize_field("ent_cat", entity_category)?;
+        }
+        if let Some(command_topic) = self.facade.get_command_topic(self.component) {
+            config.serialize_field("cmd_t", command_topic.as_str())?;
+        }
+        if !description.options.is_empty() {
+            config.serialize_field("options", description.options)?;
+        }
+        if let Some(range) = description.range {
+            config.serialize_field("min", &range.min)?;
+            config.serialize_field("max", &range.max)?;
+            config.serialize_field("step", &range.step)?;
+        }
+        if let Some(max_length) = description.max_length {
+            config.serialize_field("max", &max_length)?;
+        }
+        if let Some(state_group) = self.component.state_group() {
+            config.serialize_field("stat_t", self.facade.get_state_topic(state_group).as_str())?;
+            match description.value_template {
+                Some(value_template) => config.serialize_field("val_tpl", value_template)?,
+                // A value missing from the state, e.g. an optional reading,
+                // keeps the entity at its current state instead of blanking it
+                None => config.serialize_field("val_tpl", &DisplayStr(format_args!(
+                    "{{{{ value_json.{0} if value_json.{0} is defined else this.state }}}}",
+                    key
+                )))?,
+            }
+        }
+        let device_id = self.facade._config.device_id;
+        match self.component.legacy_ids() {
+            Some((_, suffix)) => config.serialize_field("uniq_id", &DisplayStr(format_args!("{}{}", device_id, suffix)))?,
+            None => config.serialize_field("uniq_id", &DisplayStr(format_args!("{}_{}", device_id, key)))?,
+        }
+        config.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zones::MAX_ZONES;
+
+    fn discovery_json(facade: &HomeAssistantFacade, components: &[Component]) -> std::string::String {
+        let mut buffer = [0_u8; MAX_DISCOVERY_DOCUMENT];
+        let document = DiscoveryDocument { facade, components };
+        let length = serde_json_core::to_slice(&document, &mut buffer).unwrap();
+        core::str::from_utf8(&buffer[..length]).unwrap().into()
+    }
+
+    fn component_json(component: Component) -> std::string::String {
+        let facade = HomeAssistantFacade::new(HomeAssistantFacadeConfig::new("test"));
+        let json = discovery_json(&facade, &[component]);
+        let key = component.document_key();
+        let prefix = format!(r#""cmps":{{"{}":"#, key);
+        let start = json.find(&prefix).unwrap() + prefix.len();
+        json[start..json.len() - 2].into()
+    }
+
+    #[test]
+    fn document_has_device_origin_availability_and_components() {
+        let facade = HomeAssistantFacade::new(HomeAssistantFacadeConfig::new("test"));
+        let json = discovery_json(&facade, &[Component::Temperature, Component::Pump]);
+
+        assert_eq!(
+            json,
+            format!(
+                concat!(
+                    r#"{{"dev":{{"ids":"test","name":"WateringSystem","sw":"{}","#,
+                    r#""mdl":"ESP32 watering controller","mf":"watering-system"}},"#,
+                    r#""o":{{"name":"watering-system"}},"#,
+                    r#""avty_t":"homeassistant/device/test/availability","#,
+                    r#""cmps":{{"temperature_cmp":{},"pump_running":{}}}}}"#,
+                ),
+                FIRMWARE_VERSION,
+                component_json(Component::Temperature),
+                component_json(Component::Pump),
+            )
+        );
+    }
+
+    #[test]
+    fn device_lists_the_mac_address_as_a_connection() {
+        let facade = HomeAssistantFacade::new(
+            HomeAssistantFacadeConfig::new("test").with_mac_address([0xa4, 0xcf, 0x12, 0x00, 0x01, 0xff]),
+        );
+        let json = discovery_json(&facade, &[]);
+
+        assert!(json.contains(r#""mf":"watering-system","cns":[["mac","a4:cf:12:00:01:ff"]]},"#));
+    }
+
+    #[test]
+    fn sensor_leaves_out_absent_options() {
+        assert_eq!(
+            component_json(Component::Temperature),
+            concat!(
+                r#"{"p":"sensor","dev_cla":"temperature","unit_of_meas":"°C","#,
+                r#""stat_t":"homeassistant/device/test/state/sensors","#,
+                r#""val_tpl":"{{ value_json.temperature if value_json.temperature is defined else this.state }}","#,
+                r#""uniq_id":"test-temperature"}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn components_of_the_first_firmware_keep_their_ids() {
+        let facade = HomeAssistantFacade::new(HomeAssistantFacadeConfig::new("test"));
+        let json = discovery_json(
+            &facade,
+            &[Component::Temperature, Component::Humidity, Component::SoilMoisture, Component::TankLevel],
+        );
+
+        assert!(json.contains(r#""cmps":{"temperature_cmp":{"#));
+        assert!(json.contains(r#""uniq_id":"test-temperature"}"#));
+        assert!(json.contains(r#""humidity_cmp":{"#));
+        assert!(json.contains(r#""uniq_id":"test_humidity"}"#));
+        assert!(json.contains(r#""soil_cmp":{"#));
+        assert!(json.contains(r#""val_tpl":"{{ value_json.soil_moisture if value_json.soil_moisture is defined else this.state }}","uniq_id":"test_soil"}"#));
+        assert!(json.contains(r#""tank_level":{"#));
+        assert!(json.contains(r#""uniq_id":"test_tank_level"}"#));
+    }
+
+    #[test]
+    fn custom_value_template_replaces_the_default_one() {
+        assert_eq!(
+            component_json(Component::Pump),
+            concat!(
+                r#"{"p":"binary_sensor","name":"Pump running","dev_cla":"running","#,
+                r#""stat_t":"homeassistant/device/test/state/pump","#,
+                r#""val_tpl":"{{ value_json.pump_state }}","uniq_id":"test_pump_running"}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn pump_switch_of_the_first_firmware_commands_zone_0() {
+        assert_eq!(
+            component_json(Component::PumpSwitch),
+            concat!(
+                r#"{"p":"switch","name":"Pump","#,
+                r#""cmd_t":"homeassistant/device/test/pump","#,
+                r#""stat_t":"homeassistant/device/test/state/zone/0","#,
+                r#""val_tpl":"{{ 'ON' if value_json.zone_0 in ['ON', 'open', 'closing'] else 'OFF' }}","#,
+                r#""uniq_id":"test_pump"}"#,
+            )
+        );
+
+        let facade = HomeAssistantFacade::new(HomeAssistantFacadeConfig::new("test"));
+        assert!(discovery_json(&facade, &[Component::PumpSwitch]).contains(r#""cmps":{"pump_cmp":{"#));
+        assert_eq!(facade.parse_zone_command_topic("homeassistant/device/test/pump"), Some(0));
+        assert_eq!(facade.parse_zone_command_topic("homeassistant/device/test/zone/1/set"), Some(1));
+    }
+
+    #[test]
+    fn only_commandable_components_have_a_command_topic() {
+        assert_eq!(
+            component_json(Component::Zone(1)),
+            concat!(
+                r#"{"p":"switch","name":"Zone 1","#,
+                r#""cmd_t":"homeassistant/device/test/zone/1/set","#,
+                r#""stat_t":"homeassistant/device/test/state/zone/1","#,
+                r#""val_tpl":"{{ value_json.zone_1 if value_json.zone_1 is defined else this.state }}","#,
+                r#""uniq_id":"test_zone_1"}"#,
+            )
+        );
+        assert!(!component_json(Component::ZoneRemainingRunTime(1)).contains("cmd_t"));
+        assert!(!component_json(Component::PumpStatistic(PumpStatistic::Cycles)).contains("cmd_t"));
+    }
+
+    #[test]
+    fn number_has_a_range() {
+        assert_eq!(
+            component_json(Component::ControllerSetting(ControllerSetting::MoistureLow)),
+            concat!(
+                r#"{"p":"number","name":"Moisture low","unit_of_meas":"%","ent_cat":"config","#,
+                r#""cmd_t":"homeassistant/device/test/controller/moisture_low/set","#,
+                r#""min":0,"max":100,"step":1,"#,
+                r#""stat_t":"homeassistant/device/test/state/controller","#,
+                r#""val_tpl":"{{ value_json.moisture_low if value_json.moisture_low is defined else this.state }}","#,
+                r#""uniq_id":"test_moisture_low"}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn select_lists_its_options() {
+        assert_eq!(
+            component_json(Component::ControllerSetting(ControllerSetting::Mode)),
+            concat!(
+                r#"{"p":"select","name":"Watering mode","ent_cat":"config","#,
+                r#""cmd_t":"homeassistant/device/test/controller/controller_mode/set","#,
+                r#""options":["auto","manual","schedule","off"],"#,
+                r#""stat_t":"homeassistant/device/test/state/controller","#,
+                r#""val_tpl":"{{ value_json.controller_mode if value_json.controller_mode is defined else this.state }}","#,
+                r#""uniq_id":"test_controller_mode"}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn button_has_no_state() {
+        assert_eq!(
+            component_json(Component::Button(DeviceButton::Reboot)),
+            concat!(
+                r#"{"p":"button","name":"Reboot","dev_cla":"restart","ent_cat":"config","#,
+                r#""cmd_t":"homeassistant/device/test/button/reboot/press","#,
+                r#""uniq_id":"test_reboot"}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn full_registry_fits_the_discovery_document() {
+        let device_id = "watering-system-with-a-rather-long-device-name-1";
+        assert_eq!(device_id.len(), 48);
+        let hardware = DeviceHardware {
+            zone_count: MAX_ZONES,
+            float_switch: true,
+            tank_level_sensor: true,
+            flow_meter: true,
+        };
+
+        for zone_platform in [ZonePlatform::Switch, ZonePlatform::Valve] {
+            let facade = HomeAssistantFacade::new(
+                HomeAssistantFacadeConfig::new(device_id)
+                    .with_mac_address([0xa4, 0xcf, 0x12, 0x00, 0x01, 0xff])
+                    .with_zone_platform(zone_platform),
+            );
+            let components = facade.get_component_registry(hardware);
+            let mut buffer = [0_u8; MAX_DISCOVERY_DOCUMENT];
+            let document = DiscoveryDocument {
+                facade: &facade,
+                components: components.components(),
+            };
+            assert!(serde_json_core::to_slice(&document, &mut buffer).is_ok());
+        }
+    }
+}
@@ -1,8 +1,32 @@
-#![no_std]
+//! Watering system firmware for the ESP32.
+//!
+//! The hardware-free modules also build on the host, where their unit tests
+//! run with `cargo +stable test-host` and are linted with `cargo +stable
+//! clippy-host` (see `.cargo/config.toml`). The drivers and network services
+//! are only built for the ESP32.
+#![cfg_attr(not(test), no_std)]
 
+pub mod clock;
+pub mod controller;
+pub mod diagnostics;
+pub mod flow;
+pub mod home_assistant;
+pub mod mqtt;
+pub mod output;
 pub mod pump;
+pub mod schedule;
 pub mod sensors;
-pub mod mqtt;
+pub mod zones;
+
+#[cfg(target_os = "none")]
+pub mod logs;
+#[cfg(target_os = "none")]
 pub mod mdns;
-pub mod home_assistant;
-pub mod wifi;
\ No newline at end of file
+#[cfg(target_os = "none")]
+pub mod rpc;
+#[cfg(target_os = "none")]
+pub mod sntp;
+#[cfg(target_os = "none")]
+pub mod storage;
+#[cfg(target_os = "none")]
+pub mod wifi;
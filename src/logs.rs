@@ -0,0 +1,69 @@
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::{Deque, String};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+pub const MAX_RECENT_LOGS: usize = 8;
+pub const MAX_LOG_LINE: usize = 120;
+
+/// Latest warnings and errors, so they can be fetched remotely.
+static RECENT_LOGS: Mutex<CriticalSectionRawMutex, RefCell<Deque<String<MAX_LOG_LINE>, MAX_RECENT_LOGS>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+static LOGGER: RecordingLogger = RecordingLogger;
+
+/// Prints every record like the `esp-println` logger and keeps the latest
+/// warnings and errors in memory.
+struct RecordingLogger;
+
+impl Log for RecordingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        esp_println::println!("{} - {}", record.level(), record.args());
+
+        if record.level() <= Level::Warn {
+            // Longer lines are cut short
+            let mut line: String<MAX_LOG_LINE> = String::new();
+            let _ = write!(&mut line, "{} - {}", record.level(), record.args());
+
+            RECENT_LOGS.lock(|logs| {
+                let mut logs = logs.borrow_mut();
+                if logs.is_full() {
+                    logs.pop_front();
+                }
+                let _ = logs.push_back(line);
+            });
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init_logger(level: LevelFilter) {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Uses the level in `ESP_LOG`, e.g. `debug`, defaulting to `info`.
+pub fn init_logger_from_env() {
+    init_logger(
+        option_env!("ESP_LOG")
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(LevelFilter::Info),
+    );
+}
+
+/// Latest warnings and errors, oldest first.
+pub fn recent_logs() -> Deque<String<MAX_LOG_LINE>, MAX_RECENT_LOGS> {
+    RECENT_LOGS.lock(|logs| logs.borrow().clone())
+}
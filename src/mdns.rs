@@ -3,14 +3,15 @@
 
 use core::net::{IpAddr, Ipv4Addr};
 use embassy_net::{udp, IpAddress, Ipv4Address, Stack};
-use embassy_time::{Duration, Instant, Timer};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 use esp_hal_mdns::MdnsQuery;
-use log::info;
+use log::{info, warn};
 
 use heapless::String;
-use static_cell::StaticCell;
 
 const BUFF_SIZE: usize = 256;
+/// Longest wait for a packet, so the query is resent and the deadline checked.
+const RECEIVE_INTERVAL: Duration = Duration::from_millis(250);
 
 pub struct MdnsFacade;
 
@@ -19,13 +20,14 @@ impl MdnsFacade {
         Self
     }
 
-    /// Browse `service_name` and return the first IPv4 address found.
-    /// Retries for ~5s total; loop/extend to taste.
+    /// Browse `service_name` and return the first IPv4 address found, or `None`
+    /// if nothing answered within `timeout` once the network is up.
     pub async fn query_service<'s>(
         &self,
         service_name: &'static str, // e.g. "_mqtt._tcp.local"
         stack: &'static Stack<'s>,
-    ) -> (IpAddr, u16) {
+        timeout: Duration,
+    ) -> Option<(IpAddr, u16)> {
         loop {
             if stack.is_link_up() {
                 info!("Network is up.");
@@ -40,29 +42,27 @@ impl MdnsFacade {
             Timer::after_millis(400).await;
         }
 
-        let _ = stack
-            .join_multicast_group(IpAddress::v4(224, 0, 0, 251))
-            .expect("mDNS: join multicast 224.0.0.251 failed");
+        // Joining again on a later query is harmless
+        if let Err(e) = stack.join_multicast_group(IpAddress::v4(224, 0, 0, 251)) {
+            warn!("mDNS: join multicast 224.0.0.251 failed: {:?}", e);
+        }
 
-        static RX_META: StaticCell<[udp::PacketMetadata; 4]> = StaticCell::new();
-        static RX_BUFF: StaticCell<[u8; BUFF_SIZE]> = StaticCell::new();
-        static TX_META: StaticCell<[udp::PacketMetadata; 4]> = StaticCell::new();
-        static TX_BUFF: StaticCell<[u8; BUFF_SIZE]> = StaticCell::new();
-        
-        let rx_meta = RX_META.init([udp::PacketMetadata::EMPTY; 4]);
-        let rx_buff = RX_BUFF.init([0; BUFF_SIZE]);
-        let tx_meta = TX_META.init([udp::PacketMetadata::EMPTY; 4]);
-        let tx_buff = TX_BUFF.init([0; BUFF_SIZE]);
+        let mut rx_meta = [udp::PacketMetadata::EMPTY; 4];
+        let mut rx_buff = [0; BUFF_SIZE];
+        let mut tx_meta = [udp::PacketMetadata::EMPTY; 4];
+        let mut tx_buff = [0; BUFF_SIZE];
 
         let mut sock = udp::UdpSocket::new(
             *stack,
-            rx_meta,
-            rx_buff,
-            tx_meta,
-            tx_buff,
+            &mut rx_meta,
+            &mut rx_buff,
+            &mut tx_meta,
+            &mut tx_buff,
         );
-        sock.bind(5353)
-            .expect("mDNS: bind(5353) failed — is another mDNS/responder running?");
+        if let Err(e) = sock.bind(5353) {
+            warn!("mDNS: bind(5353) failed — is another mDNS/responder running? {:?}", e);
+            return None;
+        }
         sock.set_hop_limit(Some(255));
 
         let mut q = MdnsQuery::new(
@@ -71,7 +71,7 @@ impl MdnsFacade {
             || Instant::now().as_millis() as u64,
         );
         let mdns_peer = (Ipv4Address::new(224, 0, 0, 251), 5353);
-        let deadline = Instant::now() + Duration::from_millis(5000);
+        let deadline = Instant::now() + timeout;
         let mut rx = [0u8; 128];
 
         // Local state variables for caching partial mDNS records
@@ -81,12 +81,16 @@ impl MdnsFacade {
         let mut cache_time: Option<u64> = None;
 
         loop {
+            if Instant::now() >= deadline {
+                info!("mDNS: No answer for {:?}", service_name);
+                return None;
+            }
             if let Some(pkt) = q.should_send_mdns_packet() {
                 info!("mDNS: Querying service {:?}", service_name);
                 let _query = sock.send_to(pkt, mdns_peer).await;
                 info!("mDNS: Sent query {:?}", _query);
             }
-            if let Ok((n, _peer)) = sock.recv_from(&mut rx).await {
+            if let Ok(Ok((n, _peer))) = with_timeout(RECEIVE_INTERVAL, sock.recv_from(&mut rx)).await {
                 info!("mDNS: Got response: {:?} {:?}", n, _peer);
 
                 let mut ascii: String<128> = String::new();
@@ -115,17 +119,12 @@ impl MdnsFacade {
 
                 if port != 0 && ip_v4 != [0, 0, 0, 0] {
                     info!("mDNS: Got result: {:?} {:?}", ip_v4, port);
-                    return (
+                    return Some((
                         IpAddr::V4(Ipv4Addr::new(ip_v4[0], ip_v4[1], ip_v4[2], ip_v4[3])),
                         port,
-                    );
+                    ));
                 }
             }
-            if Instant::now() >= deadline {
-                // no result yet: back off a bit, then extend the window
-                Timer::after(Duration::from_millis(250)).await;
-            }
-            Timer::after(Duration::from_millis(100)).await;
         }
     }
 
@@ -1,53 +1,226 @@
-use core::net::IpAddr;
-use core::net::SocketAddr;
-use embassy_net::{
-    tcp::client::{TcpClient, TcpClientState},
-    Stack,
-};
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-use embassy_time::Timer;
-use embedded_nal_async::TcpConnect;
-use log::{info,warn,error};
-use rust_mqtt::packet::v5::publish_packet::QualityOfService;
-use rust_mqtt::{
-    client::{
-        client::MqttClient,
-        client_config::{ClientConfig, MqttVersion},
-    },
-    utils::rng_generator::CountingRng,
+// Only the worker, built for the ESP32, sends the queued messages and routes
+// the received ones.
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::cell::{Cell, RefCell};
+use core::fmt;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex},
+    channel::Channel,
+    signal::Signal,
 };
-use static_cell::StaticCell;
+use embassy_time::{Duration, Instant};
+#[cfg(feature = "tls")]
+use esp_hal::rng::Rng;
+use log::{info,warn};
+
+#[cfg(target_os = "none")]
+mod worker;
+
+#[derive(Clone, Copy)]
+pub struct MqttCredentials {
+    pub username: &'static str,
+    pub password: &'static str,
+}
+
+impl MqttCredentials {
+    pub fn new(username: &'static str, password: &'static str) -> Self {
+        Self { username, password }
+    }
+
+    /// Reads `MQTT_USERNAME` and `MQTT_PASSWORD`, `None` for an anonymous broker.
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            username: option_env!("MQTT_USERNAME")?,
+            password: option_env!("MQTT_PASSWORD").unwrap_or(""),
+        })
+    }
+}
+
+/// TLS 1.3 with a pre-shared key, which pins the broker without having to
+/// verify certificates on the device.
+#[cfg(feature = "tls")]
+#[derive(Clone, Copy)]
+pub struct MqttTlsConfig {
+    pub server_name: Option<&'static str>,
+    pub psk_identity: &'static str,
+    /// Hex encoded, as in Mosquitto's `psk_file`.
+    pub psk_hex: &'static str,
+    pub rng: Rng,
+}
+
+#[cfg(feature = "tls")]
+impl MqttTlsConfig {
+    pub fn new(
+        server_name: Option<&'static str>,
+        psk_identity: &'static str,
+        psk_hex: &'static str,
+        rng: Rng,
+    ) -> Self {
+        Self {
+            server_name,
+            psk_identity,
+            psk_hex,
+            rng,
+        }
+    }
+
+    /// Reads `MQTT_TLS_PSK_IDENTITY`, `MQTT_TLS_PSK` and optionally `MQTT_TLS_SERVER_NAME`.
+    pub fn from_env(rng: Rng) -> Self {
+        Self {
+            server_name: option_env!("MQTT_TLS_SERVER_NAME"),
+            psk_identity: env!("MQTT_TLS_PSK_IDENTITY"),
+            psk_hex: env!("MQTT_TLS_PSK"),
+            rng,
+        }
+    }
+}
+
+/// Where to find the broker.
+#[derive(Clone, Copy)]
+pub enum MqttBrokerConfig {
+    /// An IP address, or a host name resolved with DNS.
+    Static { host: &'static str, port: u16 },
+    /// Browsed with mDNS. The address found is kept in flash and used first at
+    /// the next boot, the service is only browsed again once it stops answering.
+    Mdns { service: &'static str },
+}
+
+impl MqttBrokerConfig {
+    /// Uses `MQTT_BROKER_HOST` and `MQTT_BROKER_PORT` (1883 by default) if set,
+    /// browses the `MQTT_SERVICE` mDNS service otherwise.
+    #[cfg(target_os = "none")]
+    pub fn from_env() -> Self {
+        match option_env!("MQTT_BROKER_HOST") {
+            Some(host) => MqttBrokerConfig::Static {
+                host,
+                port: option_env!("MQTT_BROKER_PORT")
+                    .and_then(|port| port.parse().ok())
+                    .unwrap_or(1883),
+            },
+            None => MqttBrokerConfig::Mdns {
+                service: env!("MQTT_SERVICE"),
+            },
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct MqttFacadeConfig {
-    pub broker_ip: IpAddr,
-    pub broker_port: u16,
+    pub broker: MqttBrokerConfig,
     pub client_id: &'static str,
-    pub topic_id: String<MAX_TOPIC>,
+    /// Retained `online` once connected, and `offline` as Last Will.
+    pub availability_topic: String<MAX_TOPIC>,
+    pub credentials: Option<MqttCredentials>,
+    #[cfg(feature = "tls")]
+    pub tls: Option<MqttTlsConfig>,
 }
 
 impl MqttFacadeConfig {
-    pub fn new(broker_ip: IpAddr, broker_port: u16, client_id: &'static str, topic_id: &str) -> Self {
-        let mut topic = String::new();
-        topic.push_str(topic_id).expect("Topic too long");
+    pub fn new(
+        broker: MqttBrokerConfig,
+        client_id: &'static str,
+        availability_topic: &str,
+    ) -> Self {
+        let mut availability = String::new();
+        availability.push_str(availability_topic).expect("Topic too long");
         
         Self {
-            broker_ip,
-            broker_port,
+            broker,
             client_id,
-            topic_id: topic,
+            availability_topic: availability,
+            credentials: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    pub fn with_credentials(mut self, credentials: MqttCredentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tls: MqttTlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+}
+
+use heapless::{String, Vec};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageQos {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+}
+
+/// Order in which queued messages leave once the broker is reachable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessagePriority {
+    /// Periodic readings, sent last and evicted first when the queue is full.
+    Telemetry,
+    /// Discovery, state and everything else.
+    Normal,
+}
+
+/// Payload of a message. Documents larger than `MAX_PAYLOAD`, such as the
+/// discovery document, are kept in static memory rather than in every queue slot.
+/// There's no heap to box the owned variant into, every message is the full size.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+pub enum MqttPayload {
+    Owned(String<MAX_PAYLOAD>),
+    Static(&'static str),
+}
+
+impl MqttPayload {
+    pub fn as_str(&self) -> &str {
+        match self {
+            MqttPayload::Owned(content) => content.as_str(),
+            MqttPayload::Static(content) => content,
         }
     }
 }
 
-use heapless::String;
+impl Deref for MqttPayload {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Debug for MqttPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
 
+#[derive(Clone)]
 pub struct MqttMessage {
     pub topic: String<MAX_TOPIC>,
-    pub content: String<MAX_PAYLOAD>,
+    pub content: MqttPayload,
+    pub qos: MessageQos,
+    pub retain: bool,
+    /// MQTT v5 Message Expiry Interval. The message is also dropped if it expires
+    /// before leaving the outbound queue.
+    pub message_expiry: Option<Duration>,
+    /// MQTT v5 Content Type, e.g. `application/json`.
+    pub content_type: Option<&'static str>,
+    pub priority: MessagePriority,
+    /// A queued message with the same topic and key is replaced by this one
+    /// rather than sent first.
+    pub coalesce_key: Option<String<MAX_COALESCE_KEY>>,
+    /// MQTT v5 Correlation Data, echoed from a request this message answers.
+    pub correlation_data: Option<String<MAX_CORRELATION_DATA>>,
+    _created_at: Instant,
 }
 
 impl MqttMessage {
+    /// Creates a QoS 1, non retained message of normal priority, without properties.
     pub fn new(mqtt_topic: &str, mqtt_message_content: &str) -> Option<Self> {
         let mut topic = String::new();
         let mut content = String::new();
@@ -56,22 +229,323 @@ impl MqttMessage {
             return None;
         }
 
-        Some(Self { topic, content })
+        Some(Self::with_payload(topic, MqttPayload::Owned(content)))
+    }
+
+    /// Same as `new`, for a payload of any size that isn't copied.
+    pub fn new_static(mqtt_topic: &str, mqtt_message_content: &'static str) -> Option<Self> {
+        let mut topic = String::new();
+        topic.push_str(mqtt_topic).ok()?;
+
+        Some(Self::with_payload(topic, MqttPayload::Static(mqtt_message_content)))
+    }
+
+    fn with_payload(topic: String<MAX_TOPIC>, content: MqttPayload) -> Self {
+        Self {
+            topic,
+            content,
+            qos: MessageQos::AtLeastOnce,
+            retain: false,
+            message_expiry: None,
+            content_type: None,
+            priority: MessagePriority::Normal,
+            coalesce_key: None,
+            correlation_data: None,
+            _created_at: Instant::now(),
+        }
+    }
+
+    pub fn with_qos(mut self, qos: MessageQos) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    pub fn with_retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    pub fn with_message_expiry(mut self, message_expiry: Duration) -> Self {
+        self.message_expiry = Some(message_expiry);
+        self
+    }
+
+    pub fn with_content_type(mut self, content_type: &'static str) -> Self {
+        self.content_type = Some(content_type);
+        self
+    }
+
+    pub fn with_priority(mut self, priority: MessagePriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Ignored if `coalesce_key` is longer than `MAX_COALESCE_KEY`, so the
+    /// message is queued on its own.
+    pub fn with_coalesce_key(mut self, coalesce_key: &str) -> Self {
+        let mut key = String::new();
+        self.coalesce_key = key.push_str(coalesce_key).ok().map(|()| key);
+        self
+    }
+
+    /// Returns `None` if `correlation_data` is longer than `MAX_CORRELATION_DATA`.
+    pub fn with_correlation_data(mut self, correlation_data: &str) -> Option<Self> {
+        let mut data = String::new();
+        data.push_str(correlation_data).ok()?;
+        self.correlation_data = Some(data);
+        Some(self)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.message_expiry
+            .is_some_and(|message_expiry| self._created_at + message_expiry <= Instant::now())
+    }
+
+    /// Expiry interval left once the time spent in the queue is deducted, in
+    /// whole seconds rounded up as the broker only knows seconds.
+    fn remaining_expiry_s(&self) -> Option<u32> {
+        let expires_at = self._created_at + self.message_expiry?;
+        let remaining = expires_at.saturating_duration_since(Instant::now());
+        Some(remaining.as_millis().div_ceil(1000) as u32)
+    }
+}
+
+/// Message received on a subscription. Commands and requests are short, so it
+/// holds a much smaller payload than an outgoing message.
+#[derive(Debug, Clone)]
+pub struct MqttInboundMessage {
+    pub topic: String<MAX_TOPIC>,
+    pub content: String<MAX_INBOUND_PAYLOAD>,
+    /// MQTT v5 Response Topic of a request.
+    pub response_topic: Option<String<MAX_TOPIC>>,
+    /// MQTT v5 Correlation Data of a request, if it's UTF-8 and short enough.
+    pub correlation_data: Option<String<MAX_CORRELATION_DATA>>,
+}
+
+/// Topic filters that can be registered with `MqttFacade::subscribe`. Seven are
+/// registered today (Home Assistant status, zones, the pump topic of the first
+/// firmware, controller, schedules, buttons and RPC); the rest is headroom, as
+/// going over the limit only fails at boot.
+pub const MAX_SUBSCRIPTIONS: usize = 8;
+const SUBSCRIPTION_CAP: usize = 2;
+const OUT_CAP: usize = 12;
+/// QoS 1 messages written to the broker and not acknowledged yet. The outbound
+/// queue isn't read while that many are waiting for their PUBACK.
+const MAX_IN_FLIGHT: usize = 4;
+const MAX_TOPIC: usize = 128;
+const MAX_COALESCE_KEY: usize = 32;
+pub const MAX_CORRELATION_DATA: usize = 32;
+const MAX_PAYLOAD: usize = 1024;
+/// Payload of a received message, a larger one is dropped.
+const MAX_INBOUND_PAYLOAD: usize = 512;
+
+/// Message expiry, content type and correlation data of a PUBLISH packet.
+const PUBLISH_PROPERTIES_SIZE: usize = 96;
+/// Fixed header, topic, packet identifier and properties of a PUBLISH packet.
+const PUBLISH_HEADER_SIZE: usize = MAX_TOPIC + PUBLISH_PROPERTIES_SIZE + 16;
+const PUBLISH_PACKET_TYPE: u8 = 0x30;
+/// Registered topic filters. The messages matching the filter at index `i` are
+/// delivered through `SUBSCRIPTION_QUEUES[i]`.
+static SUBSCRIPTION_FILTERS: BlockingMutex<CriticalSectionRawMutex, RefCell<Vec<String<MAX_TOPIC>, MAX_SUBSCRIPTIONS>>> =
+    BlockingMutex::new(RefCell::new(Vec::new()));
+static SUBSCRIPTION_QUEUES: [Channel<CriticalSectionRawMutex, MqttInboundMessage, SUBSCRIPTION_CAP>; MAX_SUBSCRIPTIONS] =
+    [const { Channel::new() }; MAX_SUBSCRIPTIONS];
+/// Raised when a filter is registered, so a running session subscribes to it.
+static SUBSCRIPTIONS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static OUTBOUND: BlockingMutex<CriticalSectionRawMutex, RefCell<OutboundQueue>> =
+    BlockingMutex::new(RefCell::new(OutboundQueue::new()));
+static OUTBOUND_READY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static IN_FLIGHT: BlockingMutex<CriticalSectionRawMutex, RefCell<InFlightMessages>> =
+    BlockingMutex::new(RefCell::new(InFlightMessages::new()));
+
+static COUNTERS: MqttCounters = MqttCounters::new();
+static SESSION_STARTED_AT: BlockingMutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    BlockingMutex::new(Cell::new(None));
+
+/// Health of the connection to the broker since boot.
+#[derive(Debug, Clone, Copy)]
+pub struct MqttMetrics {
+    pub connection_attempts: u32,
+    pub tcp_failures: u32,
+    pub tls_failures: u32,
+    /// CONNECT or SUBSCRIBE refused by the broker, or no answer to them.
+    pub broker_failures: u32,
+    /// Established sessions that were lost.
+    pub session_losses: u32,
+    /// Sessions established after the first one.
+    pub reconnects: u32,
+    pub messages_sent: u32,
+    /// Messages dropped in either direction, e.g. on a full queue or expiry.
+    pub messages_dropped: u32,
+    pub messages_received: u32,
+    /// Age of the current session, `None` while disconnected.
+    pub session_age: Option<Duration>,
+}
+
+/// Messages waiting for the broker. Telemetry is only sent once nothing else is
+/// waiting, and is what gets dropped first when the queue is full.
+///
+/// Readings dropped while the broker is away aren't kept for replay: states are
+/// coalesced and retained, so Home Assistant gets the latest values when the
+/// connection returns, and a flash ring buffer would wear the storage sectors
+/// for a history Home Assistant wouldn't import.
+struct OutboundQueue {
+    _messages: Vec<MqttMessage, OUT_CAP>,
+}
+
+impl OutboundQueue {
+    const fn new() -> Self {
+        Self {
+            _messages: Vec::new(),
+        }
+    }
+
+    /// Queues `message`, returning the message dropped to make room, if any.
+    fn push(&mut self, message: MqttMessage) -> Option<MqttMessage> {
+        if message.coalesce_key.is_some() {
+            let queued = self._messages.iter_mut().find(|queued| {
+                queued.topic == message.topic && queued.coalesce_key == message.coalesce_key
+            });
+            if let Some(queued) = queued {
+                *queued = message;
+                return None;
+            }
+        }
+
+        if !self._messages.is_full() {
+            let _ = self._messages.push(message);
+            return None;
+        }
+
+        // Make room by dropping the oldest telemetry, or the new message
+        match self.position(MessagePriority::Telemetry) {
+            Some(index) => {
+                let dropped = self._messages.remove(index);
+                let _ = self._messages.push(message);
+                Some(dropped)
+            }
+            None => Some(message),
+        }
+    }
+
+    fn pop(&mut self) -> Option<MqttMessage> {
+        let index = self
+            .position(MessagePriority::Normal)
+            .or_else(|| self.position(MessagePriority::Telemetry))?;
+        Some(self._messages.remove(index))
+    }
+
+    fn position(&self, priority: MessagePriority) -> Option<usize> {
+        self._messages.iter().position(|message| message.priority == priority)
+    }
+
+    /// Waits for the next message to send, once fewer than `MAX_IN_FLIGHT`
+    /// messages are waiting for their PUBACK.
+    async fn receive() -> MqttMessage {
+        // A PUBACK ends the wait, as the session then asks for a message again
+        if IN_FLIGHT.lock(|in_flight| in_flight.borrow().is_full()) {
+            core::future::pending::<()>().await;
+        }
+
+        loop {
+            if let Some(message) = OUTBOUND.lock(|queue| queue.borrow_mut().pop()) {
+                return message;
+            }
+            OUTBOUND_READY.wait().await;
+        }
+    }
+}
+
+/// QoS 1 messages written to the broker, by packet identifier, until their
+/// PUBACK. They outlive the session so the next one can send them again.
+struct InFlightMessages {
+    _messages: Vec<(u16, MqttMessage), MAX_IN_FLIGHT>,
+    _last_packet_id: u16,
+}
+
+impl InFlightMessages {
+    const fn new() -> Self {
+        Self {
+            _messages: Vec::new(),
+            _last_packet_id: 0,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self._messages.is_full()
+    }
+
+    /// Returns a packet identifier that isn't used by a message in flight.
+    fn next_packet_id(&mut self) -> u16 {
+        loop {
+            self._last_packet_id = self._last_packet_id.checked_add(1).unwrap_or(1);
+            if !self._messages.iter().any(|(packet_id, _)| *packet_id == self._last_packet_id) {
+                return self._last_packet_id;
+            }
+        }
+    }
+
+    /// Tracks `message` until its PUBACK, returning `false` if the store is full.
+    fn insert(&mut self, packet_id: u16, message: MqttMessage) -> bool {
+        self._messages.push((packet_id, message)).is_ok()
+    }
+
+    /// Forgets the message acknowledged by a PUBACK, returning `false` if no
+    /// message was sent with `packet_id`.
+    fn acknowledge(&mut self, packet_id: u16) -> bool {
+        match self._messages.iter().position(|(id, _)| *id == packet_id) {
+            Some(index) => {
+                self._messages.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forgets the messages that expired while waiting for their PUBACK,
+    /// returning how many were dropped.
+    fn remove_expired(&mut self) -> usize {
+        let count = self._messages.len();
+        self._messages.retain(|(_, message)| !message.is_expired());
+        count - self._messages.len()
+    }
+
+    fn get(&self, index: usize) -> Option<(u16, MqttMessage)> {
+        self._messages.get(index).cloned()
     }
 }
-const IN_CAP: usize = 5;
-const OUT_CAP: usize = 5;
-const MAX_TOPIC: usize = 64;
-const MAX_PAYLOAD: usize = 512;
 
-const MQTT_SEND_BUFFER_SIZE: usize = 2048;
-const MQTT_RECV_BUFFER_SIZE: usize = 2048;
-const TCP_SEND_BUFFER_SIZE: usize = 2048;
-const TCP_RECV_BUFFER_SIZE: usize = 2048;
-const QUALITY_OF_SERVICE: QualityOfService = QualityOfService::QoS1;
+struct MqttCounters {
+    connection_attempts: AtomicU32,
+    tcp_failures: AtomicU32,
+    tls_failures: AtomicU32,
+    broker_failures: AtomicU32,
+    session_losses: AtomicU32,
+    sessions_established: AtomicU32,
+    messages_sent: AtomicU32,
+    messages_dropped: AtomicU32,
+    messages_received: AtomicU32,
+}
 
-static INBOUND: Channel<CriticalSectionRawMutex, MqttMessage, IN_CAP> = Channel::new();
-static OUTBOUND: Channel<CriticalSectionRawMutex, MqttMessage, OUT_CAP> = Channel::new();
+impl MqttCounters {
+    const fn new() -> Self {
+        Self {
+            connection_attempts: AtomicU32::new(0),
+            tcp_failures: AtomicU32::new(0),
+            tls_failures: AtomicU32::new(0),
+            broker_failures: AtomicU32::new(0),
+            session_losses: AtomicU32::new(0),
+            sessions_established: AtomicU32::new(0),
+            messages_sent: AtomicU32::new(0),
+            messages_dropped: AtomicU32::new(0),
+            messages_received: AtomicU32::new(0),
+        }
+    }
+
+    fn increment(counter: &AtomicU32) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
 
 pub struct MqttFacade {
     _config: MqttFacadeConfig,
@@ -82,246 +556,537 @@ impl MqttFacade {
         Self { _config: config }
     }
 
-    pub fn send_message<'s>(&mut self, message: MqttMessage) {
+    pub fn send_message(&mut self, message: MqttMessage) {
         info!(
-            "MqttFacade: Queuing message to host {:?}, port {:?}, topic {:?}, content {:?}",
-            self._config.broker_ip, self._config.broker_port, message.topic, message.content
+            "MqttFacade: Queuing message, topic {:?}, content {:?}",
+            message.topic, message.content
         );
 
-        if OUTBOUND.try_send(message).is_err() {
-            warn!("MqttFacade: Message queue full, dropping message");
+        let dropped = OUTBOUND.lock(|queue| queue.borrow_mut().push(message));
+        if let Some(dropped) = dropped {
+            warn!("MqttFacade: Message queue full, dropping message on {}", dropped.topic.as_str());
+            MqttCounters::increment(&COUNTERS.messages_dropped);
         }
+        OUTBOUND_READY.signal(());
     }
 
-    pub fn poll_message(&mut self) -> Option<MqttMessage> {
-        INBOUND.try_receive().ok()
+    pub fn metrics(&self) -> MqttMetrics {
+        MqttMetrics {
+            connection_attempts: COUNTERS.connection_attempts.load(Ordering::Relaxed),
+            tcp_failures: COUNTERS.tcp_failures.load(Ordering::Relaxed),
+            tls_failures: COUNTERS.tls_failures.load(Ordering::Relaxed),
+            broker_failures: COUNTERS.broker_failures.load(Ordering::Relaxed),
+            session_losses: COUNTERS.session_losses.load(Ordering::Relaxed),
+            reconnects: COUNTERS
+                .sessions_established
+                .load(Ordering::Relaxed)
+                .saturating_sub(1),
+            messages_sent: COUNTERS.messages_sent.load(Ordering::Relaxed),
+            messages_dropped: COUNTERS.messages_dropped.load(Ordering::Relaxed),
+            messages_received: COUNTERS.messages_received.load(Ordering::Relaxed),
+            session_age: SESSION_STARTED_AT
+                .lock(|started_at| started_at.get())
+                .map(|started_at| Instant::now() - started_at),
+        }
     }
 
-    pub async fn run_publisher_worker<'s>(&mut self, stack: &'static Stack<'s>) -> ! {
-        static SEND_BUFFER: StaticCell<[u8; MQTT_SEND_BUFFER_SIZE]> = StaticCell::new();
-        static RECEIVE_BUFFER: StaticCell<[u8; MQTT_RECV_BUFFER_SIZE]> = StaticCell::new();
+    /// Registers `filter`, which may contain `+` and `#` wildcards, and returns the
+    /// handle receiving the matching messages. Returns `None` if the filter is too
+    /// long or `MAX_SUBSCRIPTIONS` are already registered.
+    pub fn subscribe(&mut self, filter: &str) -> Option<MqttSubscription> {
+        let mut filter_buffer: String<MAX_TOPIC> = String::new();
+        filter_buffer.push_str(filter).ok()?;
 
-        let send_buffer = SEND_BUFFER.init([0_u8; MQTT_SEND_BUFFER_SIZE]);
-        let receive_buffer = RECEIVE_BUFFER.init([0_u8; MQTT_RECV_BUFFER_SIZE]);
+        let index = SUBSCRIPTION_FILTERS.lock(|filters| {
+            let mut filters = filters.borrow_mut();
+            filters.push(filter_buffer).ok()?;
+            Some(filters.len() - 1)
+        })?;
+        SUBSCRIPTIONS_CHANGED.signal(());
 
-        loop {
-            if OUTBOUND.is_empty() {
-                info!("MqttWorker - Publisher: No messages to send. Waiting...");
-                Timer::after_millis(500).await;
-                continue;
-            }
+        info!("MqttFacade: Registered subscription {} for {}", index, filter);
+        Some(MqttSubscription { _index: index })
+    }
 
-            if !stack.is_link_up() {
-                info!("MqttWorker - Publisher: Network is down. Waiting..");
-                Timer::after_millis(500).await;
-                continue;
-            } else {
-                info!("MqttWorker - Publisher: Network is up!");
-            }
+    /// Hands a received message to the subscriptions, with the response topic
+    /// and correlation data an RPC call is answered with.
+    fn handle_publish(publish: &InboundPublish<'_>) {
+        info!("MqttWorker: Received message on topic: {:?}", publish.topic);
 
-            if stack.config_v4().is_none() {
-                info!("MqttWorker - Publisher: DHCP not configured yet. Waiting..");
-                Timer::after_millis(500).await;
-                continue;
-            } else {
-                info!("MqttWorker - Publisher: DHCP configured!");
-                Timer::after_millis(100).await;
+        let Ok(content) = core::str::from_utf8(publish.payload) else {
+            warn!("MqttWorker: Received non-UTF8 message, dropping");
+            MqttCounters::increment(&COUNTERS.messages_dropped);
+            return;
+        };
+        info!("MqttWorker: Received message with content: {:?}", content);
+
+        let (Ok(topic), Ok(content)) = (String::try_from(publish.topic), String::try_from(content)) else {
+            warn!("MqttWorker: Message too large, dropping");
+            MqttCounters::increment(&COUNTERS.messages_dropped);
+            return;
+        };
+        let mut message = MqttInboundMessage {
+            topic,
+            content,
+            response_topic: None,
+            correlation_data: None,
+        };
+
+        if let Some(response_topic) = publish.response_topic {
+            message.response_topic = String::try_from(response_topic).ok();
+            if message.response_topic.is_none() {
+                warn!("MqttWorker: Response topic too long, ignoring");
+            }
+        }
+        if let Some(correlation_data) = publish.correlation_data {
+            message.correlation_data = core::str::from_utf8(correlation_data)
+                .ok()
+                .and_then(|correlation_data| String::try_from(correlation_data).ok());
+            if message.correlation_data.is_none() {
+                warn!(
+                    "MqttWorker: Correlation data isn't UTF-8 or longer than {} bytes, ignoring",
+                    MAX_CORRELATION_DATA
+                );
             }
+        }
 
-            info!("MqttWorker - Publisher: Creating TCP client state...");
-            let state: TcpClientState<3, TCP_SEND_BUFFER_SIZE, TCP_RECV_BUFFER_SIZE> =
-                TcpClientState::new();
-            info!("MqttWorker - Publisher: TCP client state created");
-
-            let tcp_client = TcpClient::new(*stack, &state);
-            info!("MqttWorker - Publisher: TCP client created, attempting connection to {} and port {}", self._config.broker_ip, self._config.broker_port,);
-
-            let tcp_connection = match tcp_client
-                .connect(SocketAddr::new(
-                    self._config.broker_ip,
-                    self._config.broker_port,
-                ))
-                .await
-            {
-                Ok(tcp_connection) => {
-                    info!("MqttWorker - Publisher: TCP connection established successfully");
-                    tcp_connection
-                }
-                Err(e) => {
-                    info!("MqttWorker - Publisher: TCP connection failed: {:?}", e);
-                    Timer::after_millis(500).await;
-                    continue;
-                }
-            };
-
-            info!("MqttWorker - Publisher: Creating MQTT client...");
-            send_buffer.fill(0);
-            receive_buffer.fill(0);
-
-            let mqtt_client_config: ClientConfig<'_, 5, CountingRng> =
-                ClientConfig::new(MqttVersion::MQTTv5, CountingRng(12345));
-            let mut mqtt_client = MqttClient::new(
-                tcp_connection,
-                send_buffer,
-                MQTT_SEND_BUFFER_SIZE,
-                receive_buffer,
-                MQTT_RECV_BUFFER_SIZE,
-                mqtt_client_config,
-            );
-
-            info!("MqttWorker - Publisher: MQTT client created, attempting broker connection...");
-            match mqtt_client.connect_to_broker().await {
-                Ok(_) => {
-                    info!("MqttWorker - Publisher: MQTT broker connection established");
-                }
-                Err(e) => {
-                    info!("MqttWorker - Publisher: MQTT broker connection failed: {:?}", e);
-                    Timer::after_millis(500).await;
-                    continue;
-                }
-            };
-
-            let message = OUTBOUND.receive().await;
-            info!("MqttWorker - Publisher: Attempting to send message (topic: {} bytes, content: {} bytes)...", 
-                    message.topic.len(), message.content.len());
-            info!("MqttWorker - Publisher: Attempting to send message (topic: {}, content: {})", 
-                    message.topic.as_str(), message.content);
-
-            match mqtt_client
-                .send_message(
-                    message.topic.as_str(),
-                    message.content.as_bytes(),
-                    QUALITY_OF_SERVICE,
-                    false,
-                ).await {
-                Ok(_) => {
-                    info!("MqttWorker - Publisher: Message sent successfully");
-                }
-                Err(e) => {
-                    error!("MqttWorker - Publisher: Error when sending message: {}", e);
-                    Timer::after_millis(500).await;
+        Self::route_message(message);
+    }
+
+    /// Delivers `message` to every subscription whose filter matches its topic.
+    fn route_message(message: MqttInboundMessage) {
+        let mut delivered = false;
+        let mut index = 0;
+        while let Some(filter) = Self::subscription_filter(index) {
+            if topic_matches(filter.as_str(), message.topic.as_str()) {
+                if SUBSCRIPTION_QUEUES[index].try_send(message.clone()).is_err() {
+                    warn!("MqttWorker: Subscription {} queue full, dropping message", index);
+                } else {
+                    delivered = true;
                 }
-            };
+            }
+            index += 1;
         }
+
+        if delivered {
+            MqttCounters::increment(&COUNTERS.messages_received);
+        } else {
+            warn!("MqttWorker: No subscription took message on {}, dropping", message.topic.as_str());
+            MqttCounters::increment(&COUNTERS.messages_dropped);
+        }
+    }
+
+    fn subscription_filter(index: usize) -> Option<String<MAX_TOPIC>> {
+        SUBSCRIPTION_FILTERS.lock(|filters| filters.borrow().get(index).cloned())
     }
+}
 
-    pub async fn run_receiver_worker<'s>(&mut self, stack: &'static Stack<'s>) -> ! {
-        static SEND_BUFFER: StaticCell<[u8; MQTT_SEND_BUFFER_SIZE]> = StaticCell::new();
-        static RECEIVE_BUFFER: StaticCell<[u8; MQTT_RECV_BUFFER_SIZE]> = StaticCell::new();
+/// Receiving end of a topic filter registered with `MqttFacade::subscribe`.
+pub struct MqttSubscription {
+    _index: usize,
+}
 
-        let send_buffer = SEND_BUFFER.init([0_u8; MQTT_SEND_BUFFER_SIZE]);
-        let receive_buffer = RECEIVE_BUFFER.init([0_u8; MQTT_RECV_BUFFER_SIZE]);
+impl MqttSubscription {
+    /// Waits for the next message matching the filter.
+    pub async fn receive(&mut self) -> MqttInboundMessage {
+        SUBSCRIPTION_QUEUES[self._index].receive().await
+    }
 
-        loop {
-            if !stack.is_link_up() {
-                info!("MqttWorker - Receiver: Network is down. Waiting..");
-                Timer::after_millis(500).await;
-                continue;
-            } else {
-                info!("MqttWorker - Receiver: Network is up!");
+    pub fn try_receive(&mut self) -> Option<MqttInboundMessage> {
+        SUBSCRIPTION_QUEUES[self._index].try_receive().ok()
+    }
+}
+
+/// Whether `topic` matches the subscription `filter`, where `+` matches a single
+/// level and a trailing `#` any number of levels, including none. As required by
+/// MQTT, topics starting with `$` are not matched by a leading wildcard.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return filter_levels.next().is_none(),
+            (Some("+"), Some(_)) => {}
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Everything of a PUBLISH packet but the payload.
+fn encode_publish_header(
+    message: &MqttMessage,
+    packet_id: u16,
+    duplicate: bool,
+) -> Option<Vec<u8, PUBLISH_HEADER_SIZE>> {
+    const DUP_FLAG: u8 = 0x08;
+    const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+    const CONTENT_TYPE: u8 = 0x03;
+    const CORRELATION_DATA: u8 = 0x09;
+
+    let mut properties: Vec<u8, PUBLISH_PROPERTIES_SIZE> = Vec::new();
+    if let Some(message_expiry_s) = message.remaining_expiry_s() {
+        properties.push(MESSAGE_EXPIRY_INTERVAL).ok()?;
+        properties.extend_from_slice(&message_expiry_s.to_be_bytes()).ok()?;
+    }
+    if let Some(content_type) = message.content_type {
+        properties.push(CONTENT_TYPE).ok()?;
+        properties.extend_from_slice(&(content_type.len() as u16).to_be_bytes()).ok()?;
+        properties.extend_from_slice(content_type.as_bytes()).ok()?;
+    }
+    if let Some(correlation_data) = &message.correlation_data {
+        properties.push(CORRELATION_DATA).ok()?;
+        properties.extend_from_slice(&(correlation_data.len() as u16).to_be_bytes()).ok()?;
+        properties.extend_from_slice(correlation_data.as_bytes()).ok()?;
+    }
+
+    let mut variable_header: Vec<u8, PUBLISH_HEADER_SIZE> = Vec::new();
+    variable_header.extend_from_slice(&(message.topic.len() as u16).to_be_bytes()).ok()?;
+    variable_header.extend_from_slice(message.topic.as_bytes()).ok()?;
+    if message.qos != MessageQos::AtMostOnce {
+        variable_header.extend_from_slice(&packet_id.to_be_bytes()).ok()?;
+    }
+    push_variable_byte_integer(&mut variable_header, properties.len())?;
+    variable_header.extend_from_slice(&properties).ok()?;
+
+    let mut header: Vec<u8, PUBLISH_HEADER_SIZE> = Vec::new();
+    let duplicate = if duplicate { DUP_FLAG } else { 0 };
+    header.push(PUBLISH_PACKET_TYPE | duplicate | ((message.qos as u8) << 1) | message.retain as u8).ok()?;
+    push_variable_byte_integer(&mut header, variable_header.len() + message.content.len())?;
+    header.extend_from_slice(&variable_header).ok()?;
+    Some(header)
+}
+
+/// PUBLISH packet received from the broker, borrowed from the buffer it was
+/// read into.
+struct InboundPublish<'p> {
+    topic: &'p str,
+    /// Set for QoS 1, which has to be acknowledged.
+    packet_id: Option<u16>,
+    response_topic: Option<&'p str>,
+    correlation_data: Option<&'p [u8]>,
+    payload: &'p [u8],
+}
+
+/// Decodes what follows the fixed header of a PUBLISH packet starting with
+/// `first_byte`. Returns `None` if the packet is malformed or cut short.
+fn decode_publish(first_byte: u8, packet: &[u8]) -> Option<InboundPublish<'_>> {
+    const PAYLOAD_FORMAT_INDICATOR: u8 = 0x01;
+    const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+    const CONTENT_TYPE: u8 = 0x03;
+    const RESPONSE_TOPIC: u8 = 0x08;
+    const CORRELATION_DATA: u8 = 0x09;
+    const SUBSCRIPTION_IDENTIFIER: u8 = 0x0B;
+    const TOPIC_ALIAS: u8 = 0x23;
+    const USER_PROPERTY: u8 = 0x26;
+
+    let mut reader = PacketReader::new(packet);
+    let topic = core::str::from_utf8(reader.binary()?).ok()?;
+    let packet_id = match (first_byte >> 1) & 0x03 {
+        0 => None,
+        // Topics are subscribed to at QoS 1, so the broker never sends QoS 2
+        1 => Some(reader.u16()?),
+        _ => return None,
+    };
+
+    let properties_length = reader.variable_byte_integer()?;
+    let mut properties = PacketReader::new(reader.take(properties_length)?);
+    let mut response_topic = None;
+    let mut correlation_data = None;
+    while !properties.is_empty() {
+        match properties.take(1)?[0] {
+            RESPONSE_TOPIC => response_topic = Some(core::str::from_utf8(properties.binary()?).ok()?),
+            CORRELATION_DATA => correlation_data = Some(properties.binary()?),
+            // Skipped, the rest only matters to the broker
+            PAYLOAD_FORMAT_INDICATOR => {
+                properties.take(1)?;
+            }
+            MESSAGE_EXPIRY_INTERVAL => {
+                properties.take(4)?;
+            }
+            TOPIC_ALIAS => {
+                properties.take(2)?;
+            }
+            CONTENT_TYPE => {
+                properties.binary()?;
+            }
+            SUBSCRIPTION_IDENTIFIER => {
+                properties.variable_byte_integer()?;
             }
+            USER_PROPERTY => {
+                properties.binary()?;
+                properties.binary()?;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(InboundPublish {
+        topic,
+        packet_id,
+        response_topic,
+        correlation_data,
+        payload: reader._bytes,
+    })
+}
+
+/// Consumes the fields of a received packet, each returning `None` if the
+/// packet is too short.
+struct PacketReader<'p> {
+    _bytes: &'p [u8],
+}
+
+impl<'p> PacketReader<'p> {
+    fn new(bytes: &'p [u8]) -> Self {
+        Self { _bytes: bytes }
+    }
+
+    fn is_empty(&self) -> bool {
+        self._bytes.is_empty()
+    }
+
+    fn take(&mut self, length: usize) -> Option<&'p [u8]> {
+        if length > self._bytes.len() {
+            return None;
+        }
+        let (taken, rest) = self._bytes.split_at(length);
+        self._bytes = rest;
+        Some(taken)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
 
-            if stack.config_v4().is_none() {
-                info!("MqttWorker - Receiver: DHCP not configured yet. Waiting..");
-                Timer::after_millis(500).await;
-                continue;
-            } else {
-                info!("MqttWorker - Receiver: DHCP configured!");
-                Timer::after_millis(100).await;
+    /// Binary data or a UTF-8 string, prefixed with its length.
+    fn binary(&mut self) -> Option<&'p [u8]> {
+        let length = self.u16()?;
+        self.take(length as usize)
+    }
+
+    fn variable_byte_integer(&mut self) -> Option<usize> {
+        let mut value = 0;
+        for shift in [0, 7, 14, 21] {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
             }
+        }
+        None
+    }
+}
 
-            info!("MqttWorker - Receiver: Creating TCP client state...");
-            let state: TcpClientState<3, TCP_SEND_BUFFER_SIZE, TCP_RECV_BUFFER_SIZE> =
-                TcpClientState::new();
-            info!("MqttWorker - Receiver: TCP client state created");
-
-            let tcp_client = TcpClient::new(*stack, &state);
-            info!("MqttWorker - Receiver: TCP client created, attempting connection...");
-
-            let tcp_connection = match tcp_client
-                .connect(SocketAddr::new(
-                    self._config.broker_ip,
-                    self._config.broker_port,
-                ))
-                .await
-            {
-                Ok(tcp_connection) => {
-                    info!("MqttWorker - Receiver: TCP connection established successfully");
-                    tcp_connection
-                }
-                Err(e) => {
-                    info!("MqttWorker - Receiver: TCP connection failed: {:?}", e);
-                    Timer::after_millis(500).await;
-                    continue;
-                }
-            };
-
-            info!("MqttWorker - Receiver: Creating MQTT client...");
-            send_buffer.fill(0);
-            receive_buffer.fill(0);
-
-            let mqtt_client_config: ClientConfig<'_, 5, CountingRng> =
-                ClientConfig::new(MqttVersion::MQTTv5, CountingRng(12345));
-            let mut mqtt_client = MqttClient::new(
-                tcp_connection,
-                send_buffer,
-                MQTT_SEND_BUFFER_SIZE,
-                receive_buffer,
-                MQTT_RECV_BUFFER_SIZE,
-                mqtt_client_config,
-            );
-
-            info!("MqttWorker - Receiver: MQTT client created, attempting broker connection...");
-            match mqtt_client.connect_to_broker().await {
-                Ok(_) => {
-                    info!("MqttWorker - Receiver: MQTT broker connection established");
-                }
-                Err(e) => {
-                    info!("MqttWorker - Receiver: MQTT broker connection failed: {:?}", e);
-                    Timer::after_millis(500).await;
-                    continue;
-                }
-            };
+fn push_variable_byte_integer<const N: usize>(buffer: &mut Vec<u8, N>, mut value: usize) -> Option<()> {
+    loop {
+        let mut byte = (value % 128) as u8;
+        value /= 128;
+        if value > 0 {
+            byte |= 0x80;
+        }
+        buffer.push(byte).ok()?;
+        if value == 0 {
+            return Some(());
+        }
+    }
+}
 
-            match mqtt_client.subscribe_to_topic(self._config.topic_id.as_str()).await {
-                Ok(_) => {
-                    info!("MqttWorker - Receiver: Subscribed to topic {}", self._config.topic_id.as_str());
-                }
-                Err(e) => {
-                    error!("MqttWorker - Receiver: Error when subscribing to topic: {}", e);
-                    Timer::after_millis(100).await;
-                    continue;
-                }
-            };
-
-            match mqtt_client.receive_message().await {
-                Ok((topic, content)) => {
-                    info!("MqttWorker - Receiver: Received message on topic: {:?}", topic);
-
-                    match core::str::from_utf8(content) {
-                        Ok(content_str) => {
-                            let message = MqttMessage::new(topic, content_str);
-                            
-                            info!("MqttWorker - Receiver: Received message with content: {:?}", content_str);
-                            if let Some(msg) = message {
-                                if INBOUND.try_send(msg).is_err() {
-                                    warn!("MqttWorker - Receiver: Message queue full, dropping message");
-                                }
-                            } else {
-                                warn!("MqttWorker - Receiver: Message too large, dropping");
-                            }
-                        }
-                        Err(_) => {
-                            warn!("MqttWorker - Receiver: Received non-UTF8 message, dropping");
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("MqttWorker - Receiver: Error when receiving message: {}", e);
-                    Timer::after_millis(100).await;
-                    continue;
-                }
-            };
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_publish, encode_publish_header, topic_matches, InFlightMessages, InboundPublish, MqttBrokerConfig,
+        MqttFacade, MqttFacadeConfig, MqttMessage, MAX_INBOUND_PAYLOAD, MAX_IN_FLIGHT,
+    };
+
+    fn message(topic: &str) -> MqttMessage {
+        MqttMessage::new(topic, "on").unwrap()
+    }
+
+    #[test]
+    fn exact_filter_matches_only_the_same_topic() {
+        assert!(topic_matches("a/b/c", "a/b/c"));
+        assert!(!topic_matches("a/b/c", "a/b/d"));
+        assert!(!topic_matches("a/b/c", "A/b/c"));
+    }
+
+    #[test]
+    fn single_level_wildcard_matches_one_level() {
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(topic_matches("+/+", "a/b"));
+        assert!(!topic_matches("a/+/c", "a/b/x/c"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+    }
+
+    #[test]
+    fn single_level_wildcard_matches_an_empty_level() {
+        assert!(topic_matches("a/+/c", "a//c"));
+        assert!(topic_matches("a/+", "a/"));
+        assert!(topic_matches("+/b", "/b"));
+    }
+
+    #[test]
+    fn multi_level_wildcard_matches_the_rest_and_its_parent() {
+        assert!(topic_matches("a/#", "a/b"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(topic_matches("a/#", "a"));
+        assert!(topic_matches("#", "a/b/c"));
+        assert!(!topic_matches("a/#", "b/c"));
+    }
+
+    #[test]
+    fn multi_level_wildcard_must_be_the_last_level() {
+        assert!(!topic_matches("a/#/c", "a/b/c"));
+        assert!(!topic_matches("#/c", "a/c"));
+    }
+
+    #[test]
+    fn wildcards_at_the_first_level_skip_system_topics() {
+        assert!(!topic_matches("#", "$SYS/broker/uptime"));
+        assert!(!topic_matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(topic_matches("$SYS/+/uptime", "$SYS/broker/uptime"));
+    }
+
+    #[test]
+    fn level_count_mismatch_does_not_match() {
+        assert!(!topic_matches("a/b", "a"));
+        assert!(!topic_matches("a", "a/b"));
+        assert!(!topic_matches("a/b/c", "a/b"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+    }
+
+    #[test]
+    fn packet_ids_skip_those_in_flight_and_zero() {
+        let mut in_flight = InFlightMessages::new();
+        assert_eq!(in_flight.next_packet_id(), 1);
+        assert!(in_flight.insert(2, message("a")));
+        assert_eq!(in_flight.next_packet_id(), 3);
+
+        in_flight._last_packet_id = u16::MAX;
+        assert!(in_flight.insert(1, message("b")));
+        assert_eq!(in_flight.next_packet_id(), 3);
+    }
+
+    #[test]
+    fn puback_forgets_only_the_matching_message() {
+        let mut in_flight = InFlightMessages::new();
+        assert!(in_flight.insert(1, message("a")));
+        assert!(in_flight.insert(2, message("b")));
+
+        assert!(in_flight.acknowledge(1));
+        assert!(!in_flight.acknowledge(1));
+        assert!(!in_flight.acknowledge(3));
+        let (packet_id, remaining) = in_flight.get(0).unwrap();
+        assert_eq!(packet_id, 2);
+        assert_eq!(remaining.topic.as_str(), "b");
+        assert!(in_flight.get(1).is_none());
+    }
+
+    #[test]
+    fn full_store_refuses_another_message() {
+        let mut in_flight = InFlightMessages::new();
+        for packet_id in 1..=MAX_IN_FLIGHT as u16 {
+            assert!(in_flight.insert(packet_id, message("a")));
         }
+
+        assert!(in_flight.is_full());
+        assert!(!in_flight.insert(100, message("b")));
+        assert!(!in_flight.acknowledge(100));
+    }
+
+    #[test]
+    fn duplicate_sets_the_dup_flag_and_keeps_the_packet_id() {
+        let message = message("a/b");
+        let expected = [0x32, 10, 0, 3, b'a', b'/', b'b', 0, 7, 0];
+
+        assert_eq!(&encode_publish_header(&message, 7, false).unwrap()[..], &expected);
+        let mut duplicate = expected;
+        duplicate[0] |= 0x08;
+        assert_eq!(&encode_publish_header(&message, 7, true).unwrap()[..], &duplicate);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn oversized_properties_fail_to_encode() {
+        static CONTENT_TYPE: [u8; 100] = [b'x'; 100];
+        let message = message("a").with_content_type(core::str::from_utf8(&CONTENT_TYPE).unwrap());
+        assert!(encode_publish_header(&message, 1, false).is_none());
+    }
+
+    #[test]
+    fn decodes_a_publish_without_properties() {
+        let packet = [0, 3, b'a', b'/', b'b', 0, b'o', b'n'];
+
+        let publish = decode_publish(0x30, &packet).unwrap();
+        assert_eq!(publish.topic, "a/b");
+        assert_eq!(publish.packet_id, None);
+        assert_eq!(publish.response_topic, None);
+        assert_eq!(publish.correlation_data, None);
+        assert_eq!(publish.payload, b"on");
+    }
+
+    #[test]
+    fn decodes_the_response_topic_and_correlation_data_among_other_properties() {
+        let packet = [
+            0, 1, b'r', // Topic
+            0x12, 0x34, // Packet identifier
+            30,   // Properties length
+            0x02, 0, 0, 0, 60, // Message expiry interval
+            0x08, 0, 5, b't', b'o', b'o', b'l', b's', // Response topic
+            0x0B, 0x81, 0x01, // Subscription identifier
+            0x09, 0, 2, b'4', b'2', // Correlation data
+            0x26, 0, 1, b'k', 0, 1, b'v', // User property
+            0x01, 1, // Payload format indicator
+            b'{', b'}',
+        ];
+
+        let publish = decode_publish(0x32, &packet).unwrap();
+        assert_eq!(publish.topic, "r");
+        assert_eq!(publish.packet_id, Some(0x1234));
+        assert_eq!(publish.response_topic, Some("tools"));
+        assert_eq!(publish.correlation_data, Some(&b"42"[..]));
+        assert_eq!(publish.payload, b"{}");
+    }
+
+    #[test]
+    fn malformed_publish_fails_to_decode() {
+        // Properties longer than the packet
+        assert!(decode_publish(0x30, &[0, 1, b'r', 9, 0x01, 1]).is_none());
+        // Correlation data cut short
+        assert!(decode_publish(0x30, &[0, 1, b'r', 4, 0x09, 0, 5, b'4']).is_none());
+        // Unknown property
+        assert!(decode_publish(0x30, &[0, 1, b'r', 2, 0x7F, 0]).is_none());
+        // QoS 2
+        assert!(decode_publish(0x34, &[0, 1, b'r', 0, 1, 0]).is_none());
+        // Topic that isn't UTF-8
+        assert!(decode_publish(0x30, &[0, 1, 0xFF, 0]).is_none());
+    }
+
+    #[test]
+    fn received_request_is_delivered_with_its_properties() {
+        let config = MqttFacadeConfig::new(MqttBrokerConfig::Static { host: "broker", port: 1883 }, "test", "test/availability");
+        let mut subscription = MqttFacade::new(config).subscribe("test/rpc").unwrap();
+        let publish = |payload| InboundPublish {
+            topic: "test/rpc",
+            packet_id: Some(1),
+            response_topic: Some("test/reply"),
+            correlation_data: Some(&b"42"[..]),
+            payload,
+        };
+
+        MqttFacade::handle_publish(&publish(br#"{"method":"get_config"}"#));
+        let message = subscription.try_receive().unwrap();
+        assert_eq!(message.content.as_str(), r#"{"method":"get_config"}"#);
+        assert_eq!(message.response_topic.as_deref(), Some("test/reply"));
+        assert_eq!(message.correlation_data.as_deref(), Some("42"));
+
+        // Larger than a received message can hold
+        MqttFacade::handle_publish(&publish(&[b'x'; MAX_INBOUND_PAYLOAD + 1]));
+        assert!(subscription.try_receive().is_none());
+    }
+}
@@ -0,0 +1,775 @@
+//! Connection to the broker, only built for the ESP32.
+
+use core::net::{IpAddr, SocketAddr};
+use embassy_futures::select::{select4, Either4};
+use embassy_net::{
+    dns::DnsQueryType,
+    tcp::TcpSocket,
+    Stack,
+};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::{Error as _, ErrorKind, ErrorType, Read, Write};
+#[cfg(feature = "tls")]
+use embedded_tls::{Aes128GcmSha256, TlsConfig, TlsConnection, TlsContext, UnsecureProvider};
+#[cfg(feature = "tls")]
+use esp_hal::rng::Rng;
+use log::{info,warn,error};
+use rust_mqtt::packet::v5::reason_codes::ReasonCode;
+use rust_mqtt::{
+    client::{
+        client_config::{ClientConfig, MqttVersion},
+        raw_client::{Event, RawMqttClient},
+    },
+    utils::rng_generator::CountingRng,
+};
+use serde::{Deserialize, Serialize};
+use static_cell::StaticCell;
+
+use crate::mdns::MdnsFacade;
+use crate::storage::{StorageFacade, StorageSlot};
+
+#[cfg(feature = "tls")]
+use super::MqttTlsConfig;
+use super::{
+    decode_publish, encode_publish_header, InboundPublish, MessageQos, MqttBrokerConfig,
+    MqttCounters, MqttFacade, MqttMessage, OutboundQueue, COUNTERS, IN_FLIGHT, MAX_INBOUND_PAYLOAD,
+    MAX_TOPIC, PUBLISH_PACKET_TYPE, PUBLISH_PROPERTIES_SIZE, SESSION_STARTED_AT,
+    SUBSCRIPTIONS_CHANGED, SUBSCRIPTION_FILTERS,
+};
+
+const MQTT_SEND_BUFFER_SIZE: usize = 2048;
+const MQTT_RECV_BUFFER_SIZE: usize = 2048;
+const TCP_SEND_BUFFER_SIZE: usize = 2048;
+const TCP_RECV_BUFFER_SIZE: usize = 2048;
+/// A TLS record can be up to 16 KiB, plus its header and authentication tag.
+#[cfg(feature = "tls")]
+const TLS_READ_BUFFER_SIZE: usize = 16384 + 256;
+#[cfg(feature = "tls")]
+const TLS_WRITE_BUFFER_SIZE: usize = 4096;
+#[cfg(feature = "tls")]
+const MAX_PSK_SIZE: usize = 64;
+const MQTT_MAX_PROPERTIES: usize = 5;
+/// Variable header and payload of a received PUBLISH packet, with room for a
+/// response topic. The payload of a larger one is dropped.
+const INBOUND_PUBLISH_SIZE: usize = 2 * MAX_TOPIC + PUBLISH_PROPERTIES_SIZE + MAX_INBOUND_PAYLOAD;
+const PUBACK_PACKET_TYPE: u8 = 0x40;
+/// Keep alive announced to the broker. A PINGREQ is sent after half of it
+/// without any outgoing packet, and the session is dropped if the broker
+/// doesn't answer within the other half.
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const AVAILABILITY_ONLINE: &str = "online";
+const AVAILABILITY_OFFLINE: &str = "offline";
+/// Polling interval while the network is down or DHCP not yet configured.
+const NETWORK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const MDNS_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+type Client<'a, 'c, T> =
+    RawMqttClient<'a, SharedConnection<'c, T>, MQTT_MAX_PROPERTIES, CountingRng>;
+
+/// Broker address last found with mDNS, as persisted in storage.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct DiscoveredBroker {
+    ip: [u8; 4],
+    port: u16,
+}
+
+impl DiscoveredBroker {
+    fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(IpAddr::from(self.ip), self.port)
+    }
+}
+
+/// Why a PUBLISH packet couldn't be written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PublishError {
+    /// The topic and properties don't fit `PUBLISH_HEADER_SIZE`, so the message
+    /// can never be sent.
+    HeaderTooLarge,
+    /// The connection failed while writing.
+    Network,
+}
+
+/// Exponential backoff between connection attempts, with jitter so devices that
+/// lost the broker at the same time don't all come back in lockstep.
+struct Backoff {
+    _delay: Duration,
+}
+
+impl Backoff {
+    const fn new() -> Self {
+        Self {
+            _delay: MIN_RECONNECT_DELAY,
+        }
+    }
+
+    fn reset(&mut self) {
+        self._delay = MIN_RECONNECT_DELAY;
+    }
+
+    /// Returns the current delay, randomised by ±25%, and doubles it up to
+    /// `MAX_RECONNECT_DELAY` for the next failure.
+    fn next_delay(&mut self) -> Duration {
+        let delay_ms = self._delay.as_millis();
+        // The low bits of the tick counter are random enough for jitter
+        let jitter_ms = Instant::now().as_ticks() % (delay_ms / 2 + 1);
+        self._delay = (self._delay * 2).min(MAX_RECONNECT_DELAY);
+        Duration::from_millis(delay_ms - delay_ms / 4 + jitter_ms)
+    }
+}
+
+impl MqttFacade {
+    /// Keeps a single MQTT session open, publishing queued messages and routing
+    /// the received ones to the matching subscriptions. Only reconnects when the
+    /// connection fails.
+    pub async fn run_worker<'s>(&mut self, stack: &'static Stack<'s>) -> ! {
+        static SEND_BUFFER: StaticCell<[u8; MQTT_SEND_BUFFER_SIZE]> = StaticCell::new();
+        static RECEIVE_BUFFER: StaticCell<[u8; MQTT_RECV_BUFFER_SIZE]> = StaticCell::new();
+        static INBOUND_BUFFER: StaticCell<[u8; INBOUND_PUBLISH_SIZE]> = StaticCell::new();
+        static TCP_SEND_BUFFER: StaticCell<[u8; TCP_SEND_BUFFER_SIZE]> = StaticCell::new();
+        static TCP_RECEIVE_BUFFER: StaticCell<[u8; TCP_RECV_BUFFER_SIZE]> = StaticCell::new();
+        #[cfg(feature = "tls")]
+        static TLS_READ_BUFFER: StaticCell<[u8; TLS_READ_BUFFER_SIZE]> = StaticCell::new();
+        #[cfg(feature = "tls")]
+        static TLS_WRITE_BUFFER: StaticCell<[u8; TLS_WRITE_BUFFER_SIZE]> = StaticCell::new();
+
+        let send_buffer = SEND_BUFFER.init([0_u8; MQTT_SEND_BUFFER_SIZE]);
+        let receive_buffer = RECEIVE_BUFFER.init([0_u8; MQTT_RECV_BUFFER_SIZE]);
+        let inbound_buffer = INBOUND_BUFFER.init([0_u8; INBOUND_PUBLISH_SIZE]);
+        let tcp_send_buffer = TCP_SEND_BUFFER.init([0_u8; TCP_SEND_BUFFER_SIZE]);
+        let tcp_receive_buffer = TCP_RECEIVE_BUFFER.init([0_u8; TCP_RECV_BUFFER_SIZE]);
+        #[cfg(feature = "tls")]
+        let tls_read_buffer = TLS_READ_BUFFER.init([0_u8; TLS_READ_BUFFER_SIZE]);
+        #[cfg(feature = "tls")]
+        let tls_write_buffer = TLS_WRITE_BUFFER.init([0_u8; TLS_WRITE_BUFFER_SIZE]);
+
+        let mut backoff = Backoff::new();
+        let storage = StorageFacade::new();
+        let mut discovered_broker = storage
+            .load(StorageSlot::MqttBroker)
+            .map(|broker: DiscoveredBroker| broker.socket_addr());
+
+        loop {
+            if !stack.is_link_up() {
+                info!("MqttWorker: Network is down. Waiting..");
+                Timer::after(NETWORK_POLL_INTERVAL).await;
+                continue;
+            }
+
+            if stack.config_v4().is_none() {
+                info!("MqttWorker: DHCP not configured yet. Waiting..");
+                Timer::after(NETWORK_POLL_INTERVAL).await;
+                continue;
+            }
+
+            let Some(broker) = self.resolve_broker(stack, &storage, &mut discovered_broker).await
+            else {
+                warn!("MqttWorker: Broker not found");
+                Timer::after(backoff.next_delay()).await;
+                continue;
+            };
+
+            let mut socket = TcpSocket::new(*stack, tcp_receive_buffer, tcp_send_buffer);
+            socket.set_timeout(Some(KEEP_ALIVE * 2));
+
+            info!("MqttWorker: Connecting to {}", broker);
+            MqttCounters::increment(&COUNTERS.connection_attempts);
+            if let Err(e) = socket.connect(broker).await {
+                info!("MqttWorker: TCP connection failed: {:?}", e);
+                MqttCounters::increment(&COUNTERS.tcp_failures);
+                // Browse again in case the broker moved
+                discovered_broker = None;
+                Timer::after(backoff.next_delay()).await;
+                continue;
+            }
+            info!("MqttWorker: TCP connection established successfully");
+
+            send_buffer.fill(0);
+            receive_buffer.fill(0);
+
+            #[cfg(feature = "tls")]
+            if let Some(tls) = self._config.tls {
+                let mut tls_connection =
+                    TlsConnection::new(socket, tls_read_buffer, tls_write_buffer);
+                match Self::open_tls(&mut tls_connection, tls).await {
+                    Ok(()) => {
+                        info!("MqttWorker: TLS session established");
+                        self.run_connection(
+                            tls_connection,
+                            send_buffer,
+                            receive_buffer,
+                            inbound_buffer,
+                            &mut backoff,
+                        )
+                        .await;
+                    }
+                    Err(()) => {
+                        MqttCounters::increment(&COUNTERS.tls_failures);
+                        tls_connection.shutdown().await;
+                    }
+                }
+                Timer::after(backoff.next_delay()).await;
+                continue;
+            }
+
+            self.run_connection(socket, send_buffer, receive_buffer, inbound_buffer, &mut backoff)
+                .await;
+            Timer::after(backoff.next_delay()).await;
+        }
+    }
+
+    /// Returns the address to connect to. With mDNS, that's the last address found
+    /// until `discovered_broker` is cleared.
+    async fn resolve_broker<'s>(
+        &self,
+        stack: &'static Stack<'s>,
+        storage: &StorageFacade,
+        discovered_broker: &mut Option<SocketAddr>,
+    ) -> Option<SocketAddr> {
+        match self._config.broker {
+            MqttBrokerConfig::Static { host, port } => {
+                let ip = match host.parse() {
+                    Ok(ip) => ip,
+                    Err(_) => {
+                        let addresses = stack.dns_query(host, DnsQueryType::A).await.ok()?;
+                        IpAddr::from(*addresses.first()?)
+                    }
+                };
+                Some(SocketAddr::new(ip, port))
+            }
+            MqttBrokerConfig::Mdns { service } => {
+                if discovered_broker.is_some() {
+                    return *discovered_broker;
+                }
+
+                let (ip, port) = MdnsFacade::new()
+                    .query_service(service, stack, MDNS_QUERY_TIMEOUT)
+                    .await?;
+                info!("MqttWorker: Found broker {} and port {} with mDNS", ip, port);
+                *discovered_broker = Some(SocketAddr::new(ip, port));
+
+                if let IpAddr::V4(ip) = ip {
+                    let broker = DiscoveredBroker {
+                        ip: ip.octets(),
+                        port,
+                    };
+                    if storage.load(StorageSlot::MqttBroker) != Some(broker) {
+                        if let Err(e) = storage.save(StorageSlot::MqttBroker, &broker) {
+                            warn!("MqttWorker: Failed to save the broker address: {:?}", e);
+                        }
+                    }
+                }
+                *discovered_broker
+            }
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    async fn open_tls(
+        tls_connection: &mut TlsConnection<'_, TcpSocket<'_>, Aes128GcmSha256>,
+        tls: MqttTlsConfig,
+    ) -> Result<(), ()> {
+        let mut psk: heapless::Vec<u8, MAX_PSK_SIZE> = heapless::Vec::new();
+        for pair in tls.psk_hex.as_bytes().chunks(2) {
+            let byte = core::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok());
+            match byte.map(|byte| psk.push(byte)) {
+                Some(Ok(())) => {}
+                _ => {
+                    error!("MqttWorker: MQTT_TLS_PSK must be at most {} hex encoded bytes", MAX_PSK_SIZE);
+                    return Err(());
+                }
+            }
+        }
+
+        let identities = [tls.psk_identity.as_bytes()];
+        let mut tls_config = TlsConfig::new().with_psk(&psk, &identities);
+        if let Some(server_name) = tls.server_name {
+            tls_config = tls_config.with_server_name(server_name);
+        }
+
+        // The key authenticates the broker, so no certificate has to be verified
+        let provider = UnsecureProvider::new::<Aes128GcmSha256>(RadioRng(tls.rng));
+        tls_connection
+            .open(TlsContext::new(&tls_config, provider))
+            .await
+            .map_err(|e| error!("MqttWorker: TLS handshake failed: {:?}", e))
+    }
+
+    /// Runs an MQTT session over an established `transport` until it fails, then
+    /// shuts it down. `backoff` starts over once the broker accepted the session.
+    async fn run_connection<T: MqttTransport>(
+        &self,
+        transport: T,
+        send_buffer: &mut [u8],
+        receive_buffer: &mut [u8],
+        inbound_buffer: &mut [u8],
+        backoff: &mut Backoff,
+    ) {
+        let connection = Mutex::new(PeekableConnection::new(transport));
+        let mut mqtt_client_config: ClientConfig<'_, MQTT_MAX_PROPERTIES, CountingRng> =
+            ClientConfig::new(MqttVersion::MQTTv5, CountingRng(12345));
+        mqtt_client_config.keep_alive = KEEP_ALIVE.as_secs() as u16;
+        mqtt_client_config.add_client_id(self._config.client_id);
+        mqtt_client_config.add_will(
+            self._config.availability_topic.as_str(),
+            AVAILABILITY_OFFLINE.as_bytes(),
+            true,
+        );
+        if let Some(credentials) = self._config.credentials {
+            mqtt_client_config.add_username(credentials.username);
+            mqtt_client_config.add_password(credentials.password);
+        }
+        let mut mqtt_client: Client<'_, '_, T> = RawMqttClient::new(
+            SharedConnection::new(&connection),
+            send_buffer,
+            MQTT_SEND_BUFFER_SIZE,
+            receive_buffer,
+            MQTT_RECV_BUFFER_SIZE,
+            mqtt_client_config,
+        );
+
+        let mut shared_connection = SharedConnection::new(&connection);
+        match self.open_session(&mut mqtt_client, &mut shared_connection).await {
+            Ok(()) => {
+                backoff.reset();
+                MqttCounters::increment(&COUNTERS.sessions_established);
+                SESSION_STARTED_AT.lock(|started_at| started_at.set(Some(Instant::now())));
+                let e = self
+                    .run_session(&mut mqtt_client, shared_connection, inbound_buffer)
+                    .await;
+                error!("MqttWorker: Session lost: {:?}", e);
+                SESSION_STARTED_AT.lock(|started_at| started_at.set(None));
+                MqttCounters::increment(&COUNTERS.session_losses);
+            }
+            Err(e) => {
+                error!("MqttWorker: MQTT broker connection failed: {:?}", e);
+                MqttCounters::increment(&COUNTERS.broker_failures);
+            }
+        }
+
+        drop(mqtt_client);
+        connection.into_inner()._transport.shutdown().await;
+    }
+
+    /// Connects to the broker and subscribes to the registered topic filters,
+    /// waiting for each to be acknowledged. Then sends the messages left without
+    /// a PUBACK by the previous session, flagged as duplicates, and marks the
+    /// device as available.
+    async fn open_session<T: MqttTransport>(
+        &self,
+        mqtt_client: &mut Client<'_, '_, T>,
+        connection: &mut SharedConnection<'_, T>,
+    ) -> Result<(), ReasonCode> {
+        mqtt_client.connect_to_broker().await?;
+        loop {
+            match mqtt_client.poll::<1>().await? {
+                Event::Connack => break,
+                event => Self::handle_event(event)?,
+            }
+        }
+        info!("MqttWorker: MQTT broker connection established");
+
+        // Filters registered from now on are picked up by `run_session`
+        SUBSCRIPTIONS_CHANGED.reset();
+        let mut index = 0;
+        while let Some(filter) = Self::subscription_filter(index) {
+            mqtt_client.subscribe_to_topic(filter.as_str()).await?;
+            loop {
+                match mqtt_client.poll::<1>().await? {
+                    Event::Suback(_) => break,
+                    event => Self::handle_event(event)?,
+                }
+            }
+            info!("MqttWorker: Subscribed to topic {}", filter.as_str());
+            index += 1;
+        }
+
+        let expired = IN_FLIGHT.lock(|in_flight| in_flight.borrow_mut().remove_expired());
+        for _ in 0..expired {
+            warn!("MqttWorker: Message expired waiting for its PUBACK, dropping");
+            MqttCounters::increment(&COUNTERS.messages_dropped);
+        }
+
+        let mut index = 0;
+        while let Some((packet_id, message)) = IN_FLIGHT.lock(|in_flight| in_flight.borrow().get(index)) {
+            info!("MqttWorker: Sending message on {} again", message.topic.as_str());
+            // The header was encoded once already, so only the connection can fail
+            connection
+                .write_publish(&message, packet_id, true)
+                .await
+                .map_err(|_| ReasonCode::NetworkError)?;
+            index += 1;
+        }
+
+        let availability =
+            MqttMessage::new_static(self._config.availability_topic.as_str(), AVAILABILITY_ONLINE)
+                .expect("Availability topic too long")
+                .with_retain(true);
+        Self::publish(connection, availability).await
+    }
+
+    /// Writes `message`, tracking it until its PUBACK if it's QoS 1. A message
+    /// that can't be encoded is dropped without ending the session.
+    async fn publish<T: MqttTransport>(
+        connection: &mut SharedConnection<'_, T>,
+        message: MqttMessage,
+    ) -> Result<(), ReasonCode> {
+        let packet_id = match message.qos {
+            MessageQos::AtMostOnce => 0,
+            MessageQos::AtLeastOnce => IN_FLIGHT.lock(|in_flight| in_flight.borrow_mut().next_packet_id()),
+        };
+
+        let result = connection.write_publish(&message, packet_id, false).await;
+        if message.qos == MessageQos::AtLeastOnce && result != Err(PublishError::HeaderTooLarge) {
+            // Also kept if the connection failed midway, for the next session
+            if !IN_FLIGHT.lock(|in_flight| in_flight.borrow_mut().insert(packet_id, message)) {
+                warn!("MqttWorker: Too many messages in flight, not tracking packet {}", packet_id);
+            }
+        }
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(PublishError::HeaderTooLarge) => {
+                MqttCounters::increment(&COUNTERS.messages_dropped);
+                Ok(())
+            }
+            Err(PublishError::Network) => Err(ReasonCode::NetworkError),
+        }
+    }
+
+    /// Multiplexes outgoing messages, incoming packets, keep alive pings and new
+    /// subscriptions over the session. Only returns on failure.
+    ///
+    /// Received PUBLISH packets are read into `inbound_buffer` rather than by the
+    /// client of rust-mqtt, which drops their properties.
+    async fn run_session<T: MqttTransport>(
+        &self,
+        mqtt_client: &mut Client<'_, '_, T>,
+        connection: SharedConnection<'_, T>,
+        inbound_buffer: &mut [u8],
+    ) -> ReasonCode {
+        let mut connection = connection;
+        let mut last_sent = Instant::now();
+        let mut ping_sent: Option<Instant> = None;
+        let mut subscribed = SUBSCRIPTION_FILTERS.lock(|filters| filters.borrow().len());
+
+        loop {
+            let wake_at = match ping_sent {
+                Some(ping_sent) => ping_sent + KEEP_ALIVE / 2,
+                None => last_sent + KEEP_ALIVE / 2,
+            };
+
+            let result = match select4(
+                OutboundQueue::receive(),
+                connection.wait_readable(),
+                Timer::at(wake_at),
+                SUBSCRIPTIONS_CHANGED.wait(),
+            )
+            .await
+            {
+                Either4::First(message) if message.is_expired() => {
+                    warn!("MqttWorker: Message on {} expired in the queue, dropping", message.topic.as_str());
+                    MqttCounters::increment(&COUNTERS.messages_dropped);
+                    Ok(())
+                }
+                Either4::First(message) => {
+                    info!("MqttWorker: Sending message (topic: {}, content: {})",
+                        message.topic.as_str(), message.content.as_str());
+                    last_sent = Instant::now();
+                    Self::publish(&mut connection, message).await
+                }
+                Either4::Second(Ok(first_byte)) if first_byte & 0xF0 == PUBLISH_PACKET_TYPE => {
+                    Self::receive_publish(&mut connection, first_byte, inbound_buffer).await
+                }
+                Either4::Second(Ok(_)) => match mqtt_client.poll::<1>().await {
+                    Ok(Event::Pingresp) => {
+                        ping_sent = None;
+                        Ok(())
+                    }
+                    Ok(event) => Self::handle_event(event),
+                    Err(e) => Err(e),
+                },
+                Either4::Second(Err(e)) => {
+                    warn!("MqttWorker: Connection error: {:?}", e);
+                    Err(ReasonCode::NetworkError)
+                }
+                Either4::Third(()) if ping_sent.is_some() => {
+                    warn!("MqttWorker: No answer to PINGREQ from the broker");
+                    Err(ReasonCode::KeepAliveTimeout)
+                }
+                Either4::Third(()) => {
+                    last_sent = Instant::now();
+                    ping_sent = Some(last_sent);
+                    mqtt_client.send_ping().await
+                }
+                // The SUBACK is handled like any other incoming packet
+                Either4::Fourth(()) => loop {
+                    let Some(filter) = Self::subscription_filter(subscribed) else {
+                        break Ok(());
+                    };
+                    info!("MqttWorker: Subscribing to topic {}", filter.as_str());
+                    last_sent = Instant::now();
+                    if let Err(e) = mqtt_client.subscribe_to_topic(filter.as_str()).await {
+                        break Err(e);
+                    }
+                    subscribed += 1;
+                },
+            };
+
+            if let Err(e) = result {
+                return e;
+            }
+        }
+    }
+
+    fn handle_event(event: Event<'_>) -> Result<(), ReasonCode> {
+        match event {
+            // Only received through the client while the session opens, without properties
+            Event::Message(topic, payload) => {
+                Self::handle_publish(&InboundPublish {
+                    topic,
+                    packet_id: None,
+                    response_topic: None,
+                    correlation_data: None,
+                    payload,
+                });
+                Ok(())
+            }
+            Event::Puback(packet_id) => {
+                if !IN_FLIGHT.lock(|in_flight| in_flight.borrow_mut().acknowledge(packet_id)) {
+                    warn!("MqttWorker: PUBACK for unknown packet {}, ignoring", packet_id);
+                }
+                Ok(())
+            }
+            Event::Disconnect(reason) => Err(reason),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads a PUBLISH packet whose fixed header starts with `first_byte`, and
+    /// acknowledges it if it's QoS 1.
+    async fn receive_publish<T: MqttTransport>(
+        connection: &mut SharedConnection<'_, T>,
+        first_byte: u8,
+        buffer: &mut [u8],
+    ) -> Result<(), ReasonCode> {
+        let length = connection.read_packet(buffer).await?;
+        let truncated = length > buffer.len();
+        let Some(publish) = decode_publish(first_byte, &buffer[..length.min(buffer.len())]) else {
+            if truncated {
+                warn!("MqttWorker: Message too large, dropping");
+                MqttCounters::increment(&COUNTERS.messages_dropped);
+                return Ok(());
+            }
+            warn!("MqttWorker: Malformed PUBLISH packet");
+            return Err(ReasonCode::MalformedPacket);
+        };
+
+        if let Some(packet_id) = publish.packet_id {
+            connection.write_puback(packet_id).await?;
+        }
+
+        if truncated {
+            warn!("MqttWorker: Message on {} too large, dropping", publish.topic);
+            MqttCounters::increment(&COUNTERS.messages_dropped);
+        } else {
+            Self::handle_publish(&publish);
+        }
+        Ok(())
+    }
+}
+
+/// Byte stream carrying the MQTT session: plain TCP, or TLS on top of it.
+trait MqttTransport: Read + Write {
+    /// Closes the stream, as gracefully as the broker allows.
+    async fn shutdown(self);
+}
+
+impl MqttTransport for TcpSocket<'_> {
+    async fn shutdown(mut self) {
+        self.close();
+        let _ = self.flush().await;
+        self.abort();
+    }
+}
+
+#[cfg(feature = "tls")]
+impl MqttTransport for TlsConnection<'_, TcpSocket<'_>, Aes128GcmSha256> {
+    async fn shutdown(self) {
+        let socket = match self.close().await {
+            Ok(socket) | Err((socket, _)) => socket,
+        };
+        socket.shutdown().await;
+    }
+}
+
+/// The hardware RNG produces true random numbers while the WiFi radio is on,
+/// which is always the case while talking to the broker.
+#[cfg(feature = "tls")]
+struct RadioRng(Rng);
+
+#[cfg(feature = "tls")]
+impl rand_core::RngCore for RadioRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.random()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        ((self.0.random() as u64) << 32) | self.0.random() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.read(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tls")]
+impl rand_core::CryptoRng for RadioRng {}
+
+/// Connection to the broker that can be waited on for incoming data without
+/// consuming it, so the worker knows when a packet is pending and of which type
+/// before reading it or leaving it to the client. The byte read while waiting
+/// is handed back on the next read.
+struct PeekableConnection<T: MqttTransport> {
+    _transport: T,
+    _peeked: Option<u8>,
+}
+
+impl<T: MqttTransport> PeekableConnection<T> {
+    fn new(transport: T) -> Self {
+        Self {
+            _transport: transport,
+            _peeked: None,
+        }
+    }
+}
+
+/// Handle on a `PeekableConnection` given to the MQTT client, while the worker
+/// keeps another one to wait for incoming data.
+struct SharedConnection<'c, T: MqttTransport> {
+    _connection: &'c Mutex<NoopRawMutex, PeekableConnection<T>>,
+}
+
+impl<'c, T: MqttTransport> SharedConnection<'c, T> {
+    fn new(connection: &'c Mutex<NoopRawMutex, PeekableConnection<T>>) -> Self {
+        Self {
+            _connection: connection,
+        }
+    }
+
+    /// Waits until at least one byte can be read and returns it, without
+    /// consuming it. Cancel safe.
+    async fn wait_readable(&self) -> Result<u8, ErrorKind> {
+        let mut connection = self._connection.lock().await;
+        if let Some(byte) = connection._peeked {
+            return Ok(byte);
+        }
+
+        let mut byte = [0_u8; 1];
+        match connection._transport.read(&mut byte).await.map_err(|e| e.kind())? {
+            0 => Err(ErrorKind::ConnectionReset),
+            _ => {
+                connection._peeked = Some(byte[0]);
+                Ok(byte[0])
+            }
+        }
+    }
+
+    /// Reads a whole packet and returns the length of what follows its fixed
+    /// header, which is copied into `buffer`. What doesn't fit is discarded.
+    async fn read_packet(&mut self, buffer: &mut [u8]) -> Result<usize, ReasonCode> {
+        let mut byte = [0_u8; 1];
+        self.read_exact(&mut byte).await.map_err(|_| ReasonCode::NetworkError)?;
+
+        let mut length = 0;
+        let mut shift = 0;
+        loop {
+            self.read_exact(&mut byte).await.map_err(|_| ReasonCode::NetworkError)?;
+            length |= ((byte[0] & 0x7F) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+            if shift > 21 {
+                return Err(ReasonCode::MalformedPacket);
+            }
+        }
+
+        let kept = length.min(buffer.len());
+        self.read_exact(&mut buffer[..kept]).await.map_err(|_| ReasonCode::NetworkError)?;
+        let mut discarded = [0_u8; 64];
+        let mut remaining = length - kept;
+        while remaining > 0 {
+            let chunk = remaining.min(discarded.len());
+            self.read_exact(&mut discarded[..chunk]).await.map_err(|_| ReasonCode::NetworkError)?;
+            remaining -= chunk;
+        }
+        Ok(length)
+    }
+
+    /// Acknowledges a received QoS 1 PUBLISH packet.
+    async fn write_puback(&mut self, packet_id: u16) -> Result<(), ReasonCode> {
+        let [high, low] = packet_id.to_be_bytes();
+        self.write_all(&[PUBACK_PACKET_TYPE, 2, high, low]).await.map_err(|_| ReasonCode::NetworkError)?;
+        self.flush().await.map_err(|_| ReasonCode::NetworkError)
+    }
+
+    /// Writes `message` as an MQTT v5 PUBLISH packet, with the DUP flag if
+    /// `duplicate`. The client of rust-mqtt can't attach properties to a
+    /// PUBLISH, so the packet is encoded here; the client still parses the
+    /// PUBACK that follows.
+    async fn write_publish(
+        &mut self,
+        message: &MqttMessage,
+        packet_id: u16,
+        duplicate: bool,
+    ) -> Result<(), PublishError> {
+        let Some(header) = encode_publish_header(message, packet_id, duplicate) else {
+            warn!("MqttWorker: PUBLISH header for {} too large, dropping", message.topic.as_str());
+            return Err(PublishError::HeaderTooLarge);
+        };
+
+        self.write_all(&header).await.map_err(|_| PublishError::Network)?;
+        self.write_all(message.content.as_bytes()).await.map_err(|_| PublishError::Network)?;
+        self.flush().await.map_err(|_| PublishError::Network)?;
+        MqttCounters::increment(&COUNTERS.messages_sent);
+        Ok(())
+    }
+}
+
+impl<T: MqttTransport> ErrorType for SharedConnection<'_, T> {
+    type Error = T::Error;
+}
+
+impl<T: MqttTransport> Read for SharedConnection<'_, T> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut connection = self._connection.lock().await;
+        match (connection._peeked, buf.first_mut()) {
+            (Some(byte), Some(first)) => {
+                *first = byte;
+                connection._peeked = None;
+                Ok(1)
+            }
+            _ => connection._transport.read(buf).await,
+        }
+    }
+}
+
+impl<T: MqttTransport> Write for SharedConnection<'_, T> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self._connection.lock().await._transport.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self._connection.lock().await._transport.flush().await
+    }
+}
@@ -0,0 +1,131 @@
+use embedded_hal::digital::{OutputPin, PinState};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputPolarity {
+    /// The load is switched on by driving the pin high (e.g. MOSFET boards).
+    ActiveHigh,
+    /// The load is switched on by driving the pin low (e.g. common relay boards).
+    ActiveLow,
+}
+
+impl OutputPolarity {
+    /// Parses `active_high` or `active_low`, as used in the build environment.
+    pub fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("active_high") {
+            Some(OutputPolarity::ActiveHigh)
+        } else if value.eq_ignore_ascii_case("active_low") {
+            Some(OutputPolarity::ActiveLow)
+        } else {
+            None
+        }
+    }
+
+    /// Level that keeps the load off. Pins should be created at this level so
+    /// nothing switches on while the firmware boots.
+    pub fn inactive_level(&self) -> PinState {
+        match self {
+            OutputPolarity::ActiveHigh => PinState::Low,
+            OutputPolarity::ActiveLow => PinState::High,
+        }
+    }
+}
+
+/// An output pin driving a load such as a pump or a valve, hiding whether the
+/// load is switched on by a high or a low level.
+pub struct SwitchedOutput<P: OutputPin> {
+    _pin: P,
+    _polarity: OutputPolarity,
+}
+
+impl<P: OutputPin> SwitchedOutput<P> {
+    /// Wraps `pin` and immediately drives it to the inactive level.
+    pub fn new(pin: P, polarity: OutputPolarity) -> Self {
+        let mut output = SwitchedOutput {
+            _pin: pin,
+            _polarity: polarity,
+        };
+        output.deactivate();
+        output
+    }
+
+    pub fn activate(&mut self) {
+        let _ = match self._polarity {
+            OutputPolarity::ActiveHigh => self._pin.set_high(),
+            OutputPolarity::ActiveLow => self._pin.set_low(),
+        };
+    }
+
+    pub fn deactivate(&mut self) {
+        let _ = match self._polarity {
+            OutputPolarity::ActiveHigh => self._pin.set_low(),
+            OutputPolarity::ActiveLow => self._pin.set_high(),
+        };
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use core::cell::Cell;
+    use core::convert::Infallible;
+
+    use embedded_hal::digital::{ErrorType, OutputPin, PinState};
+
+    use super::{OutputPolarity, SwitchedOutput};
+
+    pub(crate) const POLARITIES: [OutputPolarity; 2] =
+        [OutputPolarity::ActiveHigh, OutputPolarity::ActiveLow];
+
+    /// Pin recording the level it was last driven to, `None` until it's driven.
+    pub(crate) struct MockPin<'a>(pub(crate) &'a Cell<Option<PinState>>);
+
+    impl ErrorType for MockPin<'_> {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for MockPin<'_> {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0.set(Some(PinState::Low));
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0.set(Some(PinState::High));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn inactive_level_is_the_opposite_of_the_active_one() {
+        assert_eq!(OutputPolarity::ActiveHigh.inactive_level(), PinState::Low);
+        assert_eq!(OutputPolarity::ActiveLow.inactive_level(), PinState::High);
+    }
+
+    #[test]
+    fn new_drives_the_pin_to_the_inactive_level() {
+        for polarity in POLARITIES {
+            let level = Cell::new(None);
+            let _output = SwitchedOutput::new(MockPin(&level), polarity);
+            assert_eq!(level.get(), Some(polarity.inactive_level()));
+        }
+    }
+
+    #[test]
+    fn activate_and_deactivate_follow_the_polarity() {
+        for polarity in POLARITIES {
+            let level = Cell::new(None);
+            let mut output = SwitchedOutput::new(MockPin(&level), polarity);
+
+            output.activate();
+            assert_eq!(level.get(), Some(!polarity.inactive_level()));
+            output.deactivate();
+            assert_eq!(level.get(), Some(polarity.inactive_level()));
+        }
+    }
+
+    #[test]
+    fn parse_accepts_both_polarities_in_any_case() {
+        assert_eq!(OutputPolarity::parse("active_high"), Some(OutputPolarity::ActiveHigh));
+        assert_eq!(OutputPolarity::parse("ACTIVE_LOW"), Some(OutputPolarity::ActiveLow));
+        assert_eq!(OutputPolarity::parse("high"), None);
+    }
+}
@@ -1,37 +1,749 @@
-use esp_hal::gpio::{Flex};
-use esp_hal::peripherals::{GPIO27};
+use core::cell::Cell;
 
+use embedded_hal::digital::OutputPin;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
 
-pub struct PumpFacade<'lifetime> {
-    _pump_gpio: Flex<'lifetime>,
-    _is_on: bool
+use crate::clock;
+use crate::flow::FlowMeter;
+use crate::output::{OutputPolarity, SwitchedOutput};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PumpCommand {
+    On { duration_s: Option<u32>, volume_l: Option<f32> },
+    Off,
+    Toggle,
+}
+
+#[derive(Debug)]
+pub enum PumpCommandError {
+    EmptyPayload,
+    UnknownState,
+    InvalidJson,
+    InvalidDuration,
+    InvalidVolume,
 }
 
-impl <'lifetime> PumpFacade<'lifetime> {
+#[derive(Deserialize)]
+struct PumpCommandPayload<'a> {
+    state: &'a str,
+    duration_s: Option<u32>,
+    volume_l: Option<f32>,
+}
+
+impl PumpCommand {
+    /// Parses a pump command payload. Accepts the plain `ON`, `OFF` and `TOGGLE`
+    /// strings sent by Home Assistant switches, `OPEN` and `CLOSE` sent by valves,
+    /// or a JSON object such as
+    /// `{"state":"ON","duration_s":30}` or `{"state":"ON","volume_l":2.0}`.
+    pub fn parse(payload: &str) -> Result<Self, PumpCommandError> {
+        let payload = payload.trim();
+        if payload.is_empty() {
+            return Err(PumpCommandError::EmptyPayload);
+        }
+
+        if payload.starts_with('{') {
+            let (command, _) = serde_json_core::from_str::<PumpCommandPayload>(payload)
+                .map_err(|_| PumpCommandError::InvalidJson)?;
+            return Self::from_state(command.state, command.duration_s, command.volume_l);
+        }
+
+        Self::from_state(payload, None, None)
+    }
+
+    fn from_state(
+        state: &str,
+        duration_s: Option<u32>,
+        volume_l: Option<f32>,
+    ) -> Result<Self, PumpCommandError> {
+        if state.eq_ignore_ascii_case("ON") || state.eq_ignore_ascii_case("OPEN") {
+            if duration_s == Some(0) {
+                return Err(PumpCommandError::InvalidDuration);
+            }
+            if volume_l.is_some_and(|volume| !volume.is_finite() || volume <= 0.0) {
+                return Err(PumpCommandError::InvalidVolume);
+            }
+            Ok(PumpCommand::On { duration_s, volume_l })
+        } else if duration_s.is_some() {
+            // A duration or volume only makes sense when starting the pump
+            Err(PumpCommandError::InvalidDuration)
+        } else if volume_l.is_some() {
+            Err(PumpCommandError::InvalidVolume)
+        } else if state.eq_ignore_ascii_case("OFF") || state.eq_ignore_ascii_case("CLOSE") {
+            Ok(PumpCommand::Off)
+        } else if state.eq_ignore_ascii_case("TOGGLE") {
+            Ok(PumpCommand::Toggle)
+        } else {
+            Err(PumpCommandError::UnknownState)
+        }
+    }
+}
+
+/// Longest continuous run the firmware allows, whatever was requested. Enforced
+/// by `PumpFacade` itself so a lost "off" command can't keep the pump running.
+pub const MAX_CONTINUOUS_RUNTIME: Duration = Duration::from_secs(15 * 60);
+
+/// Cutoff applied to every run, lowered from the controller settings.
+static MAX_RUNTIME: Mutex<CriticalSectionRawMutex, Cell<Duration>> =
+    Mutex::new(Cell::new(MAX_CONTINUOUS_RUNTIME));
+
+pub fn max_runtime() -> Duration {
+    MAX_RUNTIME.lock(|max_runtime| max_runtime.get())
+}
+
+/// Sets the continuous runtime after which the pump is stopped with a fault,
+/// capped to `MAX_CONTINUOUS_RUNTIME`.
+pub fn set_max_runtime(max_runtime: Duration) {
+    MAX_RUNTIME.lock(|current| current.set(max_runtime.min(MAX_CONTINUOUS_RUNTIME)));
+}
+
+/// How long the pump may run without a single flow pulse before it's considered
+/// dry or clogged. Only enforced when a flow meter is fitted.
+const NO_FLOW_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a running pump has to be checked while its flow is monitored.
+const FLOW_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PumpFault {
+    MaxRuntimeExceeded,
+    NoFlow,
+    TankEmpty,
+}
+
+impl PumpFault {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PumpFault::MaxRuntimeExceeded => "max_runtime_exceeded",
+            PumpFault::NoFlow => "no_flow",
+            PumpFault::TankEmpty => "tank_empty",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PumpRejection {
+    CoolingDown,
+    HourlyBudgetExhausted,
+    DailyBudgetExhausted,
+    NoFlowMeter,
+    TankEmpty,
+}
+
+impl PumpRejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PumpRejection::CoolingDown => "cooling_down",
+            PumpRejection::HourlyBudgetExhausted => "hourly_budget_exhausted",
+            PumpRejection::DailyBudgetExhausted => "daily_budget_exhausted",
+            PumpRejection::NoFlowMeter => "no_flow_meter",
+            PumpRejection::TankEmpty => "tank_empty",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PumpStopReason {
+    RunCompleted,
+    VolumeDelivered,
+    BudgetExhausted(PumpRejection),
+    Fault(PumpFault),
+}
+
+#[derive(Clone, Copy)]
+pub struct PumpBudgetConfig {
+    pub max_on_time_per_hour: Duration,
+    pub max_on_time_per_day: Duration,
+    pub cooldown: Duration,
+}
+
+impl PumpBudgetConfig {
     pub fn new(
-        pump_pin: GPIO27<'static>,
+        max_on_time_per_hour: Duration,
+        max_on_time_per_day: Duration,
+        cooldown: Duration,
     ) -> Self {
-        let mut pump_gpio = Flex::new(pump_pin);
-        pump_gpio.set_input_enable(true);
-        pump_gpio.set_output_enable(true);
+        Self {
+            max_on_time_per_hour,
+            max_on_time_per_day,
+            cooldown,
+        }
+    }
 
+    /// Reads `PUMP_MAX_ON_TIME_PER_HOUR_S`, `PUMP_MAX_ON_TIME_PER_DAY_S` and
+    /// `PUMP_COOLDOWN_S`, each falling back to its default when not set.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let seconds = |value: Option<&str>, default: Duration| {
+            value
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(default)
+        };
+        Self::new(
+            seconds(option_env!("PUMP_MAX_ON_TIME_PER_HOUR_S"), default.max_on_time_per_hour),
+            seconds(option_env!("PUMP_MAX_ON_TIME_PER_DAY_S"), default.max_on_time_per_day),
+            seconds(option_env!("PUMP_COOLDOWN_S"), default.cooldown),
+        )
+    }
+}
+
+impl Default for PumpBudgetConfig {
+    fn default() -> Self {
+        Self::new(
+            Duration::from_secs(20 * 60),
+            Duration::from_secs(2 * 60 * 60),
+            Duration::from_secs(60),
+        )
+    }
+}
+
+/// Lifetime counters of the pump, persisted across reboots.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct PumpStatistics {
+    pub total_on_time_s: u64,
+    pub cycles: u32,
+    /// Unix time at which the last run started, if the clock was synchronised.
+    #[serde(default)]
+    pub last_run_start_s: Option<u64>,
+    pub last_run_duration_s: u32,
+    pub energy_wh: f32,
+}
+
+/// Pump on-time accumulated over a rolling window made of `N` fixed-size buckets.
+struct RuntimeWindow<const N: usize> {
+    _bucket_length_s: u64,
+    _buckets: [u64; N],
+    _current_bucket: u64,
+}
+
+impl<const N: usize> RuntimeWindow<N> {
+    const fn new(bucket_length_s: u64) -> Self {
+        Self {
+            _bucket_length_s: bucket_length_s,
+            _buckets: [0; N],
+            _current_bucket: 0,
+        }
+    }
+
+    fn add(&mut self, now: Instant, seconds: u64) {
+        self.advance(now);
+        self._buckets[(self._current_bucket % N as u64) as usize] += seconds;
+    }
+
+    fn total(&mut self, now: Instant) -> Duration {
+        self.advance(now);
+        Duration::from_secs(self._buckets.iter().sum())
+    }
+
+    /// Clears the buckets that fell out of the window since the last call.
+    fn advance(&mut self, now: Instant) {
+        let bucket = now.as_secs() / self._bucket_length_s;
+        let expired = bucket.saturating_sub(self._current_bucket).min(N as u64);
+        for offset in 1..=expired {
+            self._buckets[((self._current_bucket + offset) % N as u64) as usize] = 0;
+        }
+        self._current_bucket = self._current_bucket.max(bucket);
+    }
+}
+
+pub struct PumpFacade<P: OutputPin> {
+    _pump_output: SwitchedOutput<P>,
+    _budget_config: PumpBudgetConfig,
+    _flow_meter: Option<FlowMeter>,
+    _power_w: f32,
+    _statistics: PumpStatistics,
+    _is_on: bool,
+    _tank_empty: bool,
+    _on_since: Option<Instant>,
+    _run_until: Option<Instant>,
+    _volume_until_pulses: Option<u32>,
+    _last_flow: Option<(u32, Instant)>,
+    _budget_until: Option<(Instant, PumpRejection)>,
+    _cooldown_until: Option<Instant>,
+    _hourly_runtime: RuntimeWindow<60>,
+    _daily_runtime: RuntimeWindow<24>,
+    _fault: Option<PumpFault>,
+}
+
+impl <P: OutputPin> PumpFacade<P> {
+    /// Wraps `pump_pin`, switching the pump off right away. `pump_pin` should
+    /// already be at `polarity.inactive_level()` so the pump never runs at boot.
+    /// With a `flow_meter` runs can be limited by volume and a dry or clogged
+    /// pump is stopped. `power_w` is only used to estimate energy consumption.
+    pub fn new(
+        pump_pin: P,
+        polarity: OutputPolarity,
+        budget_config: PumpBudgetConfig,
+        flow_meter: Option<FlowMeter>,
+        power_w: f32,
+    ) -> Self {
         PumpFacade {
-            _pump_gpio: pump_gpio,
-            _is_on: false
+            _pump_output: SwitchedOutput::new(pump_pin, polarity),
+            _budget_config: budget_config,
+            _flow_meter: flow_meter,
+            _power_w: power_w,
+            _statistics: PumpStatistics::default(),
+            _is_on: false,
+            _tank_empty: false,
+            _on_since: None,
+            _run_until: None,
+            _volume_until_pulses: None,
+            _last_flow: None,
+            _budget_until: None,
+            _cooldown_until: None,
+            _hourly_runtime: RuntimeWindow::new(60),
+            _daily_runtime: RuntimeWindow::new(60 * 60),
+            _fault: None,
         }
     }
 
-    pub fn turn_on(&mut self) {
-        self._pump_gpio.set_low();
-        self._is_on = true;
+    /// Turns the pump on until `turn_off` is called, the runtime budget is used up
+    /// or `max_runtime` elapses. Calling it while already running keeps
+    /// the original start time, so repeated commands can't extend the safety cutoff.
+    pub fn turn_on(&mut self) -> Result<(), PumpRejection> {
+        self.start()?;
+        self._run_until = None;
+        self._volume_until_pulses = None;
+        Ok(())
+    }
+
+    /// Turns the pump on for `duration`, after which `check_deadline` stops it.
+    pub fn turn_on_for(&mut self, duration: Duration) -> Result<(), PumpRejection> {
+        self.start()?;
+        self._run_until = Some(Instant::now() + duration);
+        self._volume_until_pulses = None;
+        Ok(())
+    }
+
+    /// Turns the pump on until the flow meter measured `litres`, after which
+    /// `check_deadline` stops it. The usual time limits still apply.
+    pub fn turn_on_for_volume(&mut self, litres: f32) -> Result<(), PumpRejection> {
+        let flow_meter = self._flow_meter.ok_or(PumpRejection::NoFlowMeter)?;
+        self.start()?;
+        self._run_until = None;
+        self._volume_until_pulses =
+            Some(flow_meter.pulses().wrapping_add(flow_meter.litres_to_pulses(litres)));
+        Ok(())
+    }
+
+    pub fn statistics(&self) -> PumpStatistics {
+        self._statistics
+    }
+
+    /// Continues counting from statistics saved before a reboot.
+    pub fn restore_statistics(&mut self, statistics: PumpStatistics) {
+        self._statistics = statistics;
+    }
+
+    pub fn flow_meter(&self) -> Option<FlowMeter> {
+        self._flow_meter
     }
 
     pub fn turn_off(&mut self) {
-        self._pump_gpio.set_high();
+        self._pump_output.deactivate();
         self._is_on = false;
+
+        if let Some(on_since) = self._on_since.take() {
+            let now = Instant::now();
+            let runtime_s = (now - on_since).as_secs();
+            self._hourly_runtime.add(now, runtime_s);
+            self._daily_runtime.add(now, runtime_s);
+            self._cooldown_until = Some(now + self._budget_config.cooldown);
+
+            let runtime_ms = (now - on_since).as_millis();
+            self._statistics.total_on_time_s += runtime_s;
+            self._statistics.last_run_duration_s = runtime_s as u32;
+            self._statistics.energy_wh += self._power_w * runtime_ms as f32 / 3_600_000.0;
+        }
+        self._run_until = None;
+        self._volume_until_pulses = None;
+        self._last_flow = None;
+        self._budget_until = None;
     }
 
     pub fn is_on(&self) -> bool {
         self._is_on
     }
-}
\ No newline at end of file
+
+    pub fn fault(&self) -> Option<PumpFault> {
+        self._fault
+    }
+
+    /// Locks the pump out while the reservoir is empty, stopping it if it's running.
+    pub fn set_tank_empty(&mut self, tank_empty: bool) -> Option<PumpStopReason> {
+        self._tank_empty = tank_empty;
+        if tank_empty && self._is_on {
+            return Some(self.stop_with_fault(PumpFault::TankEmpty));
+        }
+        None
+    }
+
+    /// Latest instant at which the current run ends: when it's timed, or when the
+    /// runtime budget or the maximum runtime would stop it.
+    pub fn cutoff(&self) -> Option<Instant> {
+        let mut cutoff = self._on_since? + max_runtime();
+        if let Some((budget_until, _)) = self._budget_until {
+            cutoff = cutoff.min(budget_until);
+        }
+        if let Some(run_until) = self._run_until {
+            cutoff = cutoff.min(run_until);
+        }
+        Some(cutoff)
+    }
+
+    /// Earliest instant at which `check_deadline` has to be called to stop the pump.
+    pub fn deadline(&self) -> Option<Instant> {
+        let mut deadline = self.cutoff()?;
+        if self._last_flow.is_some() {
+            deadline = deadline.min(Instant::now() + FLOW_CHECK_INTERVAL);
+        }
+        Some(deadline)
+    }
+
+    /// Stops the pump if its timed run is over, its volume was delivered, the
+    /// runtime budget is used up, the maximum continuous runtime was reached or no
+    /// water is flowing, returning why it was stopped.
+    pub fn check_deadline(&mut self) -> Option<PumpStopReason> {
+        let on_since = self._on_since?;
+        let now = Instant::now();
+
+        if now >= on_since + max_runtime() {
+            return Some(self.stop_with_fault(PumpFault::MaxRuntimeExceeded));
+        }
+
+        if let (Some(flow_meter), Some((last_pulses, last_change))) =
+            (self._flow_meter, self._last_flow)
+        {
+            let pulses = flow_meter.pulses();
+            if pulses != last_pulses {
+                self._last_flow = Some((pulses, now));
+            } else if now >= last_change + NO_FLOW_TIMEOUT {
+                return Some(self.stop_with_fault(PumpFault::NoFlow));
+            }
+
+            // Counts wrap around, so compare the distance to the target instead
+            if let Some(target) = self._volume_until_pulses {
+                if target.wrapping_sub(pulses) as i32 <= 0 {
+                    self.turn_off();
+                    return Some(PumpStopReason::VolumeDelivered);
+                }
+            }
+        }
+
+        if self._run_until.is_some_and(|run_until| now >= run_until) {
+            self.turn_off();
+            return Some(PumpStopReason::RunCompleted);
+        }
+
+        if let Some((budget_until, rejection)) = self._budget_until {
+            if now >= budget_until {
+                self.turn_off();
+                return Some(PumpStopReason::BudgetExhausted(rejection));
+            }
+        }
+
+        None
+    }
+
+    fn stop_with_fault(&mut self, fault: PumpFault) -> PumpStopReason {
+        self.turn_off();
+        self._fault = Some(fault);
+        PumpStopReason::Fault(fault)
+    }
+
+    fn start(&mut self) -> Result<(), PumpRejection> {
+        if self._tank_empty {
+            return Err(PumpRejection::TankEmpty);
+        }
+        if self._on_since.is_none() {
+            let now = Instant::now();
+            if self._cooldown_until.is_some_and(|cooldown_until| now < cooldown_until) {
+                return Err(PumpRejection::CoolingDown);
+            }
+            self._budget_until = Some(self.remaining_budget(now)?);
+            self._on_since = Some(now);
+            self._statistics.cycles += 1;
+            self._statistics.last_run_start_s = clock::unix_time();
+            self._last_flow = self._flow_meter.map(|flow_meter| (flow_meter.pulses(), now));
+        }
+
+        self._pump_output.activate();
+        self._is_on = true;
+        self._fault = None;
+        Ok(())
+    }
+
+    /// Instant at which the tighter of the hourly and daily budgets runs out if the
+    /// pump is started at `now`.
+    fn remaining_budget(&mut self, now: Instant) -> Result<(Instant, PumpRejection), PumpRejection> {
+        let hourly_left = self._budget_config.max_on_time_per_hour
+            .checked_sub(self._hourly_runtime.total(now))
+            .filter(|left| left.as_secs() > 0)
+            .ok_or(PumpRejection::HourlyBudgetExhausted)?;
+        let daily_left = self._budget_config.max_on_time_per_day
+            .checked_sub(self._daily_runtime.total(now))
+            .filter(|left| left.as_secs() > 0)
+            .ok_or(PumpRejection::DailyBudgetExhausted)?;
+
+        if hourly_left <= daily_left {
+            Ok((now + hourly_left, PumpRejection::HourlyBudgetExhausted))
+        } else {
+            Ok((now + daily_left, PumpRejection::DailyBudgetExhausted))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use embassy_time::MockDriver;
+    use embedded_hal::digital::PinState;
+
+    use super::*;
+    use crate::flow::{add_pulses, FlowMeterConfig};
+    use crate::output::tests::{MockPin, POLARITIES};
+
+    fn pump(level: &Cell<Option<PinState>>, polarity: OutputPolarity) -> PumpFacade<MockPin<'_>> {
+        PumpFacade::new(MockPin(level), polarity, PumpBudgetConfig::default(), None, 5.0)
+    }
+
+    fn budgeted_pump(level: &Cell<Option<PinState>>, hourly_s: u64, daily_s: u64, cooldown_s: u64) -> PumpFacade<MockPin<'_>> {
+        let budget_config = PumpBudgetConfig::new(
+            Duration::from_secs(hourly_s),
+            Duration::from_secs(daily_s),
+            Duration::from_secs(cooldown_s),
+        );
+        PumpFacade::new(MockPin(level), OutputPolarity::ActiveHigh, budget_config, None, 5.0)
+    }
+
+    fn metered_pump(level: &Cell<Option<PinState>>) -> PumpFacade<MockPin<'_>> {
+        let flow_meter = FlowMeter::new(FlowMeterConfig::default());
+        PumpFacade::new(
+            MockPin(level),
+            OutputPolarity::ActiveHigh,
+            PumpBudgetConfig::default(),
+            Some(flow_meter),
+            5.0,
+        )
+    }
+
+    #[test]
+    fn runtime_window_forgets_the_buckets_that_left_it() {
+        let mut window: RuntimeWindow<3> = RuntimeWindow::new(10);
+        window.add(Instant::from_secs(5), 4);
+        window.add(Instant::from_secs(15), 2);
+        window.add(Instant::from_secs(18), 1);
+        assert_eq!(window.total(Instant::from_secs(29)), Duration::from_secs(7));
+        // The first bucket is out once the fourth one starts
+        assert_eq!(window.total(Instant::from_secs(30)), Duration::from_secs(3));
+        assert_eq!(window.total(Instant::from_secs(100)), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn remaining_budget_is_the_tighter_of_hourly_and_daily() {
+        let level = Cell::new(None);
+        let now = Instant::now();
+        assert_eq!(
+            budgeted_pump(&level, 60, 3600, 0).remaining_budget(now),
+            Ok((now + Duration::from_secs(60), PumpRejection::HourlyBudgetExhausted))
+        );
+        assert_eq!(
+            budgeted_pump(&level, 60, 30, 0).remaining_budget(now),
+            Ok((now + Duration::from_secs(30), PumpRejection::DailyBudgetExhausted))
+        );
+    }
+
+    #[test]
+    fn cooldown_refuses_to_restart_the_pump() {
+        let level = Cell::new(None);
+        let mut pump = budgeted_pump(&level, 3600, 3600, 60);
+
+        assert_eq!(pump.turn_on(), Ok(()));
+        MockDriver::get().advance(Duration::from_secs(5));
+        pump.turn_off();
+        assert_eq!(pump.turn_on(), Err(PumpRejection::CoolingDown));
+        assert!(!pump.is_on());
+
+        MockDriver::get().advance(Duration::from_secs(60));
+        assert_eq!(pump.turn_on(), Ok(()));
+    }
+
+    #[test]
+    fn hourly_budget_stops_the_run_and_refuses_the_next_one() {
+        let level = Cell::new(None);
+        let mut pump = budgeted_pump(&level, 60, 3600, 0);
+
+        assert_eq!(pump.turn_on(), Ok(()));
+        assert_eq!(pump.cutoff(), Some(Instant::now() + Duration::from_secs(60)));
+        MockDriver::get().advance(Duration::from_secs(59));
+        assert_eq!(pump.check_deadline(), None);
+
+        MockDriver::get().advance(Duration::from_secs(1));
+        assert_eq!(
+            pump.check_deadline(),
+            Some(PumpStopReason::BudgetExhausted(PumpRejection::HourlyBudgetExhausted))
+        );
+        assert!(!pump.is_on());
+        assert_eq!(level.get(), Some(PinState::Low));
+        assert_eq!(pump.turn_on(), Err(PumpRejection::HourlyBudgetExhausted));
+
+        MockDriver::get().advance(Duration::from_secs(60 * 60));
+        assert_eq!(pump.turn_on(), Ok(()));
+    }
+
+    #[test]
+    fn daily_budget_stops_the_run_and_refuses_the_next_one() {
+        let level = Cell::new(None);
+        let mut pump = budgeted_pump(&level, 60, 90, 0);
+
+        assert_eq!(pump.turn_on(), Ok(()));
+        MockDriver::get().advance(Duration::from_secs(60));
+        assert_eq!(
+            pump.check_deadline(),
+            Some(PumpStopReason::BudgetExhausted(PumpRejection::HourlyBudgetExhausted))
+        );
+
+        // A new hour, but only 30 s left for the day
+        MockDriver::get().advance(Duration::from_secs(60 * 60));
+        assert_eq!(pump.turn_on(), Ok(()));
+        MockDriver::get().advance(Duration::from_secs(30));
+        assert_eq!(
+            pump.check_deadline(),
+            Some(PumpStopReason::BudgetExhausted(PumpRejection::DailyBudgetExhausted))
+        );
+        assert!(!pump.is_on());
+        assert_eq!(pump.turn_on(), Err(PumpRejection::DailyBudgetExhausted));
+    }
+
+    #[test]
+    fn pump_is_off_at_boot() {
+        for polarity in POLARITIES {
+            let level = Cell::new(None);
+            let pump = pump(&level, polarity);
+            assert!(!pump.is_on());
+            assert_eq!(level.get(), Some(polarity.inactive_level()));
+        }
+    }
+
+    #[test]
+    fn turn_on_and_off_drive_the_pin() {
+        for polarity in POLARITIES {
+            let level = Cell::new(None);
+            let mut pump = pump(&level, polarity);
+
+            assert_eq!(pump.turn_on(), Ok(()));
+            assert!(pump.is_on());
+            assert_eq!(level.get(), Some(!polarity.inactive_level()));
+
+            pump.turn_off();
+            assert!(!pump.is_on());
+            assert_eq!(level.get(), Some(polarity.inactive_level()));
+        }
+    }
+
+    #[test]
+    fn max_runtime_stops_the_pump_with_a_fault() {
+        for polarity in POLARITIES {
+            let level = Cell::new(None);
+            let mut pump = pump(&level, polarity);
+
+            assert_eq!(pump.turn_on(), Ok(()));
+            assert_eq!(pump.check_deadline(), None);
+
+            MockDriver::get().advance(MAX_CONTINUOUS_RUNTIME);
+            assert_eq!(
+                pump.check_deadline(),
+                Some(PumpStopReason::Fault(PumpFault::MaxRuntimeExceeded))
+            );
+            assert!(!pump.is_on());
+            assert_eq!(pump.fault(), Some(PumpFault::MaxRuntimeExceeded));
+            assert_eq!(level.get(), Some(polarity.inactive_level()));
+        }
+    }
+
+    #[test]
+    fn empty_tank_refuses_to_start_the_pump() {
+        for polarity in POLARITIES {
+            let level = Cell::new(None);
+            let mut pump = pump(&level, polarity);
+
+            assert_eq!(pump.set_tank_empty(true), None);
+            assert_eq!(pump.turn_on(), Err(PumpRejection::TankEmpty));
+            assert_eq!(pump.turn_on_for(Duration::from_secs(10)), Err(PumpRejection::TankEmpty));
+            assert!(!pump.is_on());
+            assert_eq!(level.get(), Some(polarity.inactive_level()));
+        }
+    }
+
+    #[test]
+    fn emptying_tank_stops_a_running_pump() {
+        for polarity in POLARITIES {
+            let level = Cell::new(None);
+            let mut pump = pump(&level, polarity);
+
+            assert_eq!(pump.turn_on(), Ok(()));
+            assert_eq!(
+                pump.set_tank_empty(true),
+                Some(PumpStopReason::Fault(PumpFault::TankEmpty))
+            );
+            assert!(!pump.is_on());
+            assert_eq!(level.get(), Some(polarity.inactive_level()));
+        }
+    }
+
+    #[test]
+    fn volume_run_needs_a_flow_meter() {
+        let level = Cell::new(None);
+        let mut pump = pump(&level, OutputPolarity::ActiveHigh);
+        assert_eq!(pump.turn_on_for_volume(1.0), Err(PumpRejection::NoFlowMeter));
+        assert!(!pump.is_on());
+    }
+
+    #[test]
+    fn volume_run_stops_once_the_volume_was_delivered() {
+        let level = Cell::new(None);
+        let mut pump = metered_pump(&level);
+
+        assert_eq!(pump.turn_on_for_volume(1.0), Ok(()));
+        add_pulses(449);
+        MockDriver::get().advance(FLOW_CHECK_INTERVAL);
+        assert_eq!(pump.check_deadline(), None);
+        assert!(pump.is_on());
+
+        add_pulses(1);
+        MockDriver::get().advance(FLOW_CHECK_INTERVAL);
+        assert_eq!(pump.check_deadline(), Some(PumpStopReason::VolumeDelivered));
+        assert!(!pump.is_on());
+        assert_eq!(pump.fault(), None);
+        assert_eq!(level.get(), Some(PinState::Low));
+    }
+
+    #[test]
+    fn flowing_water_keeps_a_volume_run_going() {
+        let level = Cell::new(None);
+        let mut pump = metered_pump(&level);
+
+        assert_eq!(pump.turn_on_for_volume(10.0), Ok(()));
+        for _ in 0..3 {
+            add_pulses(100);
+            MockDriver::get().advance(NO_FLOW_TIMEOUT - Duration::from_secs(1));
+            assert_eq!(pump.check_deadline(), None);
+        }
+        assert!(pump.is_on());
+    }
+
+    #[test]
+    fn volume_run_without_flow_stops_with_a_fault() {
+        let level = Cell::new(None);
+        let mut pump = metered_pump(&level);
+
+        assert_eq!(pump.turn_on_for_volume(1.0), Ok(()));
+        MockDriver::get().advance(NO_FLOW_TIMEOUT);
+        assert_eq!(pump.check_deadline(), Some(PumpStopReason::Fault(PumpFault::NoFlow)));
+        assert!(!pump.is_on());
+        assert_eq!(pump.fault(), Some(PumpFault::NoFlow));
+    }
+}
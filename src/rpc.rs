@@ -0,0 +1,394 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_time::{with_timeout, Duration};
+use heapless::String;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::controller::{
+    ControllerCall, ControllerRequest, ControllerSetting, ControllerSettings, CONTROLLER_REPLIES,
+    CONTROLLER_REQUESTS,
+};
+use crate::diagnostics;
+use crate::logs::{self, MAX_LOG_LINE};
+use crate::mqtt::{MqttFacade, MqttFacadeConfig, MqttMessage};
+use crate::pump::PumpCommand;
+use crate::sensors::{self, CalibrationPoint, SoilMoistureCalibration};
+use crate::storage::{StorageFacade, StorageSlot};
+use crate::zones::{ZoneRequest, ZONE_REQUESTS};
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+/// Leaves room in a 1 KiB response for the envelope and the correlation data.
+const MAX_RESULT: usize = 896;
+/// Time given to the controller task to answer a request.
+const CONTROLLER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Id of the next request sent to the controller task.
+static NEXT_CONTROLLER_CALL_ID: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RpcMethod {
+    GetConfig,
+    SetConfig,
+    Calibrate,
+    Reboot,
+    RunZone,
+    GetLogs,
+}
+
+impl RpcMethod {
+    pub const ALL: [RpcMethod; 6] = [
+        RpcMethod::GetConfig,
+        RpcMethod::SetConfig,
+        RpcMethod::Calibrate,
+        RpcMethod::Reboot,
+        RpcMethod::RunZone,
+        RpcMethod::GetLogs,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RpcMethod::GetConfig => "get_config",
+            RpcMethod::SetConfig => "set_config",
+            RpcMethod::Calibrate => "calibrate",
+            RpcMethod::Reboot => "reboot",
+            RpcMethod::RunZone => "run_zone",
+            RpcMethod::GetLogs => "get_logs",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|method| method.as_str() == value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RpcError {
+    InvalidRequest,
+    UnknownMethod,
+    /// A parameter is missing or invalid, the one named is the culprit.
+    InvalidParams(&'static str),
+    /// The request was valid but refused, for the given reason.
+    Rejected(&'static str),
+    Busy,
+    Timeout,
+    /// The result didn't fit in a response.
+    Internal,
+}
+
+impl RpcError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            RpcError::InvalidRequest => "invalid_request",
+            RpcError::UnknownMethod => "unknown_method",
+            RpcError::InvalidParams(_) => "invalid_params",
+            RpcError::Rejected(_) => "rejected",
+            RpcError::Busy => "busy",
+            RpcError::Timeout => "timeout",
+            RpcError::Internal => "internal",
+        }
+    }
+
+    pub fn detail(&self) -> Option<&'static str> {
+        match self {
+            RpcError::InvalidParams(detail) | RpcError::Rejected(detail) => Some(detail),
+            _ => None,
+        }
+    }
+}
+
+/// A call sent as JSON to the request topic, for example
+/// `{"method":"run_zone","params":{"zone":0,"duration_s":30}}`.
+///
+/// The answer goes to the MQTT v5 Response Topic of the request, and carries its
+/// Correlation Data both as the property and in its payload. Clients that can't
+/// set properties may put `response_topic` and `correlation_data` in the payload
+/// instead, the properties win when both are given. Without a response topic the
+/// call is still made, but not answered.
+#[derive(Debug, Deserialize)]
+pub struct RpcRequest<'a> {
+    pub method: &'a str,
+    #[serde(borrow, default)]
+    pub params: RpcParams<'a>,
+    /// Fallback for the MQTT v5 Response Topic.
+    #[serde(borrow, default)]
+    pub response_topic: Option<&'a str>,
+    /// Fallback for the MQTT v5 Correlation Data.
+    #[serde(borrow, default)]
+    pub correlation_data: Option<&'a str>,
+}
+
+impl<'a> RpcRequest<'a> {
+    pub fn parse(payload: &'a str) -> Result<Self, RpcError> {
+        serde_json_core::from_str(payload)
+            .map(|(request, _)| request)
+            .map_err(|_| RpcError::InvalidRequest)
+    }
+}
+
+/// Parameters of every method, each one only reads those it needs.
+#[derive(Debug, Default, Deserialize)]
+pub struct RpcParams<'a> {
+    /// `set_config`: a controller setting, as in its MQTT command topic.
+    #[serde(borrow)]
+    pub key: Option<&'a str>,
+    #[serde(borrow)]
+    pub value: Option<&'a str>,
+    /// `calibrate`: `dry` or `wet`.
+    #[serde(borrow)]
+    pub point: Option<&'a str>,
+    /// `calibrate`: raw reading for the point, the latest one if not given.
+    pub raw_value: Option<u16>,
+    /// `run_zone`
+    pub zone: Option<usize>,
+    pub duration_s: Option<u32>,
+    pub volume_l: Option<f32>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeviceConfig {
+    controller: ControllerSettings,
+    soil_moisture_calibration: SoilMoistureCalibration,
+}
+
+#[derive(Clone)]
+pub struct RpcFacadeConfig {
+    pub request_topic: String<128>,
+}
+
+impl RpcFacadeConfig {
+    pub fn new(request_topic: &str) -> Self {
+        let mut topic = String::new();
+        topic.push_str(request_topic).expect("Topic too long");
+        Self {
+            request_topic: topic,
+        }
+    }
+}
+
+/// Management API for tooling: answers the calls sent to the request topic.
+pub struct RpcFacade {
+    _config: RpcFacadeConfig,
+    _mqtt_facade: MqttFacade,
+    _storage: StorageFacade,
+}
+
+impl RpcFacade {
+    pub fn new(config: RpcFacadeConfig, mqtt_facade_config: MqttFacadeConfig) -> Self {
+        Self {
+            _config: config,
+            _mqtt_facade: MqttFacade::new(mqtt_facade_config),
+            _storage: StorageFacade::new(),
+        }
+    }
+
+    pub async fn run(&mut self) -> ! {
+        let mut requests = self
+            ._mqtt_facade
+            .subscribe(self._config.request_topic.as_str())
+            .expect("Too many MQTT subscriptions");
+
+        loop {
+            let message = requests.receive().await;
+            let request = match RpcRequest::parse(message.content.as_str()) {
+                Ok(request) => request,
+                Err(e) => {
+                    warn!("Rpc: Invalid request {:?}: {:?}", message.content, e);
+                    continue;
+                }
+            };
+            info!("Rpc: Calling {}", request.method);
+
+            let mut result: String<MAX_RESULT> = String::new();
+            let method = RpcMethod::parse(request.method).ok_or(RpcError::UnknownMethod);
+            let outcome = match method {
+                Ok(method) => self.call(method, &request.params, &mut result).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = outcome {
+                warn!("Rpc: {} failed: {:?}", request.method, e);
+            }
+
+            let response_topic = message.response_topic.as_deref().or(request.response_topic);
+            let correlation_data = message.correlation_data.as_deref().or(request.correlation_data);
+            if let Some(response_topic) = response_topic {
+                match Self::response(response_topic, correlation_data, outcome.map(|()| &result)) {
+                    Some(response) => self._mqtt_facade.send_message(response),
+                    None => warn!("Rpc: Response to {} too large, dropping", request.method),
+                }
+            }
+
+            if outcome.is_ok() && method == Ok(RpcMethod::Reboot) {
+                diagnostics::reboot().await;
+            }
+        }
+    }
+
+    /// Runs `method`, writing its result as a JSON value into `result`.
+    async fn call(
+        &mut self,
+        method: RpcMethod,
+        params: &RpcParams<'_>,
+        result: &mut String<MAX_RESULT>,
+    ) -> Result<(), RpcError> {
+        match method {
+            RpcMethod::GetConfig => {
+                let config = DeviceConfig {
+                    controller: Self::controller_request(ControllerRequest::Get).await?,
+                    soil_moisture_calibration: sensors::soil_moisture_calibration(),
+                };
+                write_json(result, &config)
+            }
+            RpcMethod::SetConfig => {
+                let key = params.key.ok_or(RpcError::InvalidParams("key"))?;
+                let setting =
+                    ControllerSetting::from_key(key).ok_or(RpcError::InvalidParams("key"))?;
+                let mut value = String::new();
+                value
+                    .push_str(params.value.ok_or(RpcError::InvalidParams("value"))?)
+                    .map_err(|_| RpcError::InvalidParams("value"))?;
+
+                let settings =
+                    Self::controller_request(ControllerRequest::Set { setting, value }).await?;
+                write_json(result, &settings)
+            }
+            RpcMethod::Calibrate => {
+                let point = params
+                    .point
+                    .and_then(CalibrationPoint::parse)
+                    .ok_or(RpcError::InvalidParams("point"))?;
+                let calibration = sensors::calibrate_soil_moisture(point, params.raw_value)
+                    .map_err(|e| RpcError::Rejected(e.as_str()))?;
+
+                if let Err(e) = self._storage.save(StorageSlot::SoilMoistureCalibration, &calibration) {
+                    warn!("Rpc: Failed to save the calibration: {:?}", e);
+                }
+                write_json(result, &calibration)
+            }
+            RpcMethod::Reboot => result.push_str("null").map_err(|_| RpcError::Internal),
+            RpcMethod::RunZone => {
+                let zone = params.zone.ok_or(RpcError::InvalidParams("zone"))?;
+                let command = PumpCommand::On {
+                    duration_s: params.duration_s,
+                    volume_l: params.volume_l,
+                };
+                // The zones task applies the pump safety limits, and reports a
+                // rejection like for any other command
+                ZONE_REQUESTS
+                    .try_send(ZoneRequest::new(zone, command))
+                    .map_err(|_| RpcError::Busy)?;
+                write!(result, r#"{{"zone":{},"queued":true}}"#, zone).map_err(|_| RpcError::Internal)
+            }
+            RpcMethod::GetLogs => {
+                // The ring can hold more than a response once quoted and
+                // escaped, so the oldest lines are dropped until the rest fits
+                let logs = logs::recent_logs();
+                for skipped in 0..=logs.len() {
+                    result.clear();
+                    if write_json_lines(result, logs.iter().skip(skipped)).is_ok() {
+                        if skipped > 0 {
+                            // Not a warning, which would land in the ring itself
+                            info!("Rpc: Dropped the {} oldest log lines from the response", skipped);
+                        }
+                        return Ok(());
+                    }
+                }
+                Err(RpcError::Internal)
+            }
+        }
+    }
+
+    async fn controller_request(request: ControllerRequest) -> Result<ControllerSettings, RpcError> {
+        let id = NEXT_CONTROLLER_CALL_ID.fetch_add(1, Ordering::Relaxed);
+        CONTROLLER_REQUESTS
+            .try_send(ControllerCall { id, request })
+            .map_err(|_| RpcError::Busy)?;
+
+        let reply = async {
+            loop {
+                let reply = CONTROLLER_REPLIES.receive().await;
+                if reply.id == id {
+                    return reply.result;
+                }
+                // The answer to an earlier request that timed out
+                warn!("Rpc: Discarded the late reply to controller request {}", reply.id);
+            }
+        };
+        with_timeout(CONTROLLER_TIMEOUT, reply)
+            .await
+            .map_err(|_| RpcError::Timeout)?
+            .map_err(|e| RpcError::Rejected(e.as_str()))
+    }
+
+    /// `{"correlation_data":...,"result":...}` on success and
+    /// `{"correlation_data":...,"error":{"code":...,"detail":...}}` on failure.
+    fn response(
+        response_topic: &str,
+        correlation_data: Option<&str>,
+        outcome: Result<&String<MAX_RESULT>, RpcError>,
+    ) -> Option<MqttMessage> {
+        let mut message_buffer: String<1024> = String::new();
+
+        message_buffer.push('{').ok()?;
+        if let Some(correlation_data) = correlation_data {
+            message_buffer.push_str(r#""correlation_data":"#).ok()?;
+            write_json_string(&mut message_buffer, correlation_data).ok()?;
+            message_buffer.push(',').ok()?;
+        }
+        match outcome {
+            Ok(result) => write!(&mut message_buffer, r#""result":{}}}"#, result).ok()?,
+            Err(e) => {
+                write!(&mut message_buffer, r#""error":{{"code":"{}""#, e.code()).ok()?;
+                if let Some(detail) = e.detail() {
+                    write!(&mut message_buffer, r#","detail":"{}""#, detail).ok()?;
+                }
+                message_buffer.push_str("}}").ok()?;
+            }
+        }
+
+        let message = MqttMessage::new(response_topic, message_buffer.as_str())?
+            .with_content_type(JSON_CONTENT_TYPE);
+        match correlation_data {
+            Some(correlation_data) => message.with_correlation_data(correlation_data),
+            None => Some(message),
+        }
+    }
+}
+
+fn write_json<T: Serialize>(result: &mut String<MAX_RESULT>, value: &T) -> Result<(), RpcError> {
+    let mut buffer = [0_u8; MAX_RESULT];
+    let length = serde_json_core::to_slice(value, &mut buffer).map_err(|_| RpcError::Internal)?;
+    let json = core::str::from_utf8(&buffer[..length]).map_err(|_| RpcError::Internal)?;
+    result.push_str(json).map_err(|_| RpcError::Internal)
+}
+
+/// Writes `lines` as a JSON array of strings.
+fn write_json_lines<'a>(
+    out: &mut impl Write,
+    lines: impl Iterator<Item = &'a String<MAX_LOG_LINE>>,
+) -> fmt::Result {
+    out.write_char('[')?;
+    for (index, line) in lines.enumerate() {
+        if index > 0 {
+            out.write_char(',')?;
+        }
+        write_json_string(out, line)?;
+    }
+    out.write_char(']')
+}
+
+/// Writes `value` as a quoted JSON string, escaping it as needed.
+fn write_json_string(out: &mut impl Write, value: &str) -> fmt::Result {
+    out.write_char('"')?;
+    for character in value.chars() {
+        match character {
+            '"' => out.write_str(r#"\""#)?,
+            '\\' => out.write_str(r"\\")?,
+            '\n' => out.write_str(r"\n")?,
+            character if character.is_control() => write!(out, "\\u{:04x}", character as u32)?,
+            character => out.write_char(character)?,
+        }
+    }
+    out.write_char('"')
+}
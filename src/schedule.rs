@@ -0,0 +1,307 @@
+use core::fmt;
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{TimeZoneConfig, Weekday};
+use crate::pump::MAX_CONTINUOUS_RUNTIME;
+
+pub const MAX_SCHEDULES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScheduleError {
+    InvalidDays,
+    InvalidTime,
+    InvalidDuration,
+    InvalidZone,
+    UnknownSlot,
+}
+
+/// Set of weekdays, one bit per day starting with Monday.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Weekdays(u8);
+
+impl Weekdays {
+    const EVERY_DAY: Weekdays = Weekdays(0b111_1111);
+
+    pub fn contains(&self, weekday: Weekday) -> bool {
+        self.0 & (1 << weekday as u8) != 0
+    }
+
+    /// Parses `daily` or a comma separated list such as `mon,wed,fri`.
+    pub fn parse(value: &str) -> Result<Self, ScheduleError> {
+        if value.eq_ignore_ascii_case("daily") {
+            return Ok(Self::EVERY_DAY);
+        }
+
+        let mut days = 0;
+        for day in value.split(',') {
+            let weekday = Weekday::parse(day.trim()).ok_or(ScheduleError::InvalidDays)?;
+            days |= 1 << weekday as u8;
+        }
+        Ok(Weekdays(days))
+    }
+}
+
+impl fmt::Display for Weekdays {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Self::EVERY_DAY {
+            return f.write_str("daily");
+        }
+
+        let mut separator = "";
+        for weekday in Weekday::ALL.into_iter().filter(|weekday| self.contains(*weekday)) {
+            write!(f, "{}{}", separator, weekday.as_str())?;
+            separator = ",";
+        }
+        Ok(())
+    }
+}
+
+/// A timed run of one zone, repeated on the given days at a local time.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ScheduleEntry {
+    pub days: Weekdays,
+    pub hour: u8,
+    pub minute: u8,
+    pub duration_s: u32,
+    pub zone: usize,
+}
+
+impl ScheduleEntry {
+    /// Parses `<days> <HH:MM> <seconds>s [zone <n>]`, for example
+    /// `daily 06:30 45s` or `mon,wed,fri 19:00 20s zone 1`.
+    pub fn parse(value: &str) -> Result<Self, ScheduleError> {
+        let mut tokens = value.split_whitespace();
+        let days = Weekdays::parse(tokens.next().ok_or(ScheduleError::InvalidDays)?)?;
+
+        let (hour, minute) = tokens
+            .next()
+            .and_then(|time| time.split_once(':'))
+            .ok_or(ScheduleError::InvalidTime)?;
+        let hour: u8 = hour.parse().map_err(|_| ScheduleError::InvalidTime)?;
+        let minute: u8 = minute.parse().map_err(|_| ScheduleError::InvalidTime)?;
+        if hour > 23 || minute > 59 {
+            return Err(ScheduleError::InvalidTime);
+        }
+
+        let duration_s: u32 = tokens
+            .next()
+            .and_then(|duration| duration.strip_suffix('s'))
+            .and_then(|duration| duration.parse().ok())
+            .ok_or(ScheduleError::InvalidDuration)?;
+        if duration_s == 0 || duration_s as u64 > MAX_CONTINUOUS_RUNTIME.as_secs() {
+            return Err(ScheduleError::InvalidDuration);
+        }
+
+        let zone = match (tokens.next(), tokens.next()) {
+            (None, _) => 0,
+            (Some("zone"), Some(zone)) => zone.parse().map_err(|_| ScheduleError::InvalidZone)?,
+            _ => return Err(ScheduleError::InvalidZone),
+        };
+        if tokens.next().is_some() {
+            return Err(ScheduleError::InvalidZone);
+        }
+
+        Ok(Self {
+            days,
+            hour,
+            minute,
+            duration_s,
+            zone,
+        })
+    }
+}
+
+/// Same format as accepted by `ScheduleEntry::parse`.
+impl fmt::Display for ScheduleEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:02}:{:02} {}s zone {}",
+            self.days, self.hour, self.minute, self.duration_s, self.zone
+        )
+    }
+}
+
+/// Every schedule slot, as persisted in storage.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ScheduleTable {
+    pub entries: [Option<ScheduleEntry>; MAX_SCHEDULES],
+}
+
+/// Finds the schedule entries due at the current local time.
+pub struct ScheduleEngine {
+    _table: ScheduleTable,
+    _time_zone: TimeZoneConfig,
+    _last_checked_minute: Option<u64>,
+}
+
+impl ScheduleEngine {
+    pub fn new(table: ScheduleTable, time_zone: TimeZoneConfig) -> Self {
+        Self {
+            _table: table,
+            _time_zone: time_zone,
+            _last_checked_minute: None,
+        }
+    }
+
+    pub fn table(&self) -> &ScheduleTable {
+        &self._table
+    }
+
+    pub fn set_entry(
+        &mut self,
+        slot: usize,
+        entry: Option<ScheduleEntry>,
+    ) -> Result<(), ScheduleError> {
+        *self._table.entries.get_mut(slot).ok_or(ScheduleError::UnknownSlot)? = entry;
+        Ok(())
+    }
+
+    /// Returns the entries starting in the minute of `unix_time`. Each minute is
+    /// only reported once, so this can be polled several times per minute.
+    /// Entries in the hour repeated when daylight saving time ends only run the
+    /// first time round, and those in the hour skipped when it starts don't run.
+    pub fn due_entries(&mut self, unix_time: u64) -> Vec<ScheduleEntry, MAX_SCHEDULES> {
+        let mut due_entries = Vec::new();
+        let minute = unix_time / 60;
+        if self._last_checked_minute == Some(minute) {
+            return due_entries;
+        }
+        self._last_checked_minute = Some(minute);
+        if self._time_zone.is_repeated_hour(unix_time) {
+            return due_entries;
+        }
+
+        let now = self._time_zone.to_local(unix_time);
+        for entry in self._table.entries.iter().flatten() {
+            if entry.days.contains(now.weekday) && entry.hour == now.hour && entry.minute == now.minute {
+                let _ = due_entries.push(*entry);
+            }
+        }
+        due_entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::String;
+
+    use super::*;
+    use crate::clock::DstRule;
+
+    fn to_string(entry: &ScheduleEntry) -> String<64> {
+        let mut text = String::new();
+        fmt::write(&mut text, format_args!("{}", entry)).unwrap();
+        text
+    }
+
+    fn engine(time_zone: TimeZoneConfig, entries: &[&str]) -> ScheduleEngine {
+        let mut table = ScheduleTable::default();
+        for (slot, entry) in entries.iter().enumerate() {
+            table.entries[slot] = Some(ScheduleEntry::parse(entry).unwrap());
+        }
+        ScheduleEngine::new(table, time_zone)
+    }
+
+    #[test]
+    fn entry_is_parsed() {
+        assert_eq!(
+            ScheduleEntry::parse("mon,wed,fri 19:05 20s zone 1"),
+            Ok(ScheduleEntry {
+                days: Weekdays(0b001_0101),
+                hour: 19,
+                minute: 5,
+                duration_s: 20,
+                zone: 1,
+            })
+        );
+        assert_eq!(
+            ScheduleEntry::parse("daily 06:30 45s"),
+            Ok(ScheduleEntry {
+                days: Weekdays::EVERY_DAY,
+                hour: 6,
+                minute: 30,
+                duration_s: 45,
+                zone: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_entries_are_refused() {
+        assert_eq!(ScheduleEntry::parse(""), Err(ScheduleError::InvalidDays));
+        assert_eq!(ScheduleEntry::parse("someday 06:30 45s"), Err(ScheduleError::InvalidDays));
+        assert_eq!(ScheduleEntry::parse("daily 24:00 45s"), Err(ScheduleError::InvalidTime));
+        assert_eq!(ScheduleEntry::parse("daily 06:60 45s"), Err(ScheduleError::InvalidTime));
+        assert_eq!(ScheduleEntry::parse("daily 0630 45s"), Err(ScheduleError::InvalidTime));
+        assert_eq!(ScheduleEntry::parse("daily 06:30 45"), Err(ScheduleError::InvalidDuration));
+        assert_eq!(ScheduleEntry::parse("daily 06:30 0s"), Err(ScheduleError::InvalidDuration));
+        assert_eq!(
+            ScheduleEntry::parse("daily 06:30 100000s"),
+            Err(ScheduleError::InvalidDuration)
+        );
+        assert_eq!(ScheduleEntry::parse("daily 06:30 45s zone"), Err(ScheduleError::InvalidZone));
+        assert_eq!(ScheduleEntry::parse("daily 06:30 45s zone x"), Err(ScheduleError::InvalidZone));
+        assert_eq!(
+            ScheduleEntry::parse("daily 06:30 45s zone 1 now"),
+            Err(ScheduleError::InvalidZone)
+        );
+    }
+
+    #[test]
+    fn display_is_parsed_back() {
+        for value in [
+            "daily 06:30 45s zone 0",
+            "mon,wed,fri 19:05 20s zone 1",
+            "sun 00:00 1s zone 3",
+        ] {
+            let entry = ScheduleEntry::parse(value).unwrap();
+            assert_eq!(to_string(&entry), value);
+            assert_eq!(ScheduleEntry::parse(&to_string(&entry)), Ok(entry));
+        }
+    }
+
+    #[test]
+    fn due_entries_are_reported_once_per_minute() {
+        // Monday 2025-06-02T05:30:00Z
+        let mut engine = engine(
+            TimeZoneConfig::default(),
+            &["daily 05:30 45s", "mon 05:30 10s zone 1", "tue 05:30 10s"],
+        );
+        let due_entries = engine.due_entries(1_748_842_200);
+        assert_eq!(due_entries.len(), 2);
+        assert_eq!(due_entries[1].zone, 1);
+        assert!(engine.due_entries(1_748_842_200 + 59).is_empty());
+        assert!(engine.due_entries(1_748_842_200 + 60).is_empty());
+    }
+
+    #[test]
+    fn eu_entries_follow_summer_time() {
+        let mut engine = engine(TimeZoneConfig::new(60, DstRule::Eu), &["daily 06:30 45s"]);
+        // 2025-03-29T06:30:00 CET, then 2025-03-30T06:30:00 CEST
+        assert_eq!(engine.due_entries(1_743_309_000 - 23 * 3600).len(), 1);
+        assert!(engine.due_entries(1_743_309_000 - 3600).is_empty());
+        assert_eq!(engine.due_entries(1_743_309_000).len(), 1);
+    }
+
+    #[test]
+    fn us_entries_follow_daylight_saving_time() {
+        let mut engine = engine(TimeZoneConfig::new(-300, DstRule::Us), &["daily 03:30 45s"]);
+        // 2025-03-09T03:30:00 EDT, an hour after the switch
+        assert!(engine.due_entries(1_741_505_400 - 3600).is_empty());
+        assert_eq!(engine.due_entries(1_741_505_400).len(), 1);
+    }
+
+    #[test]
+    fn repeated_hour_runs_entries_once() {
+        let mut engine = engine(TimeZoneConfig::new(-300, DstRule::Us), &["daily 01:30 45s"]);
+        // 2025-11-02T01:30:00 EDT, then 01:30:00 EST an hour later
+        assert_eq!(engine.due_entries(1_762_061_400).len(), 1);
+        assert!(engine.due_entries(1_762_061_400 + 3600).is_empty());
+        // The next day runs as usual
+        assert_eq!(engine.due_entries(1_762_061_400 + 25 * 3600).len(), 1);
+    }
+}
@@ -1,22 +1,167 @@
-use embassy_time::{Duration, Timer};
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+#[cfg(target_os = "none")]
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+#[cfg(target_os = "none")]
 use esp_hal::analog::adc::{Adc, AdcConfig, AdcPin, Attenuation};
+#[cfg(target_os = "none")]
 use esp_hal::peripherals::{GPIO33, GPIO35, ADC1};
-use esp_hal::gpio::{Flex, InputConfig, OutputConfig, DriveMode, Pull};
+#[cfg(target_os = "none")]
+use esp_hal::gpio::{AnyPin, Flex, Input, InputConfig, InputPin, Level, Output, OutputConfig, OutputPin, DriveMode, Pull};
+#[cfg(target_os = "none")]
 use esp_hal::Blocking;
+#[cfg(target_os = "none")]
 use esp_hal::delay::Delay;
 
+#[cfg(target_os = "none")]
 use log::{info, warn};
 
+#[cfg(target_os = "none")]
 use embedded_dht_rs::dht22::Dht22;
+use serde::{Deserialize, Serialize};
 
 const SOIL_MOISTURE_MIN_VALUE: u16 = 900;
 const SOIL_MOISTURE_MAX_VALUE: u16 = 3500;
 
+/// Longest an HC-SR04 echo can take (about 5 m there and back).
+#[cfg(target_os = "none")]
+const ECHO_TIMEOUT: Duration = Duration::from_millis(30);
+
+/// Latest reservoir state, so the pump can be locked out without waiting for the
+/// task that owns the sensors. Starts empty when a tank sensor is fitted, until
+/// it is first read.
+static TANK_EMPTY: AtomicBool = AtomicBool::new(false);
+static TANK_EMPTY_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+static SOIL_MOISTURE_CALIBRATION: Mutex<CriticalSectionRawMutex, Cell<SoilMoistureCalibration>> =
+    Mutex::new(Cell::new(SoilMoistureCalibration::DEFAULT));
+static SOIL_MOISTURE_RAW_VALUE: Mutex<CriticalSectionRawMutex, Cell<Option<u16>>> =
+    Mutex::new(Cell::new(None));
+
+pub fn is_tank_empty() -> bool {
+    TANK_EMPTY.load(Ordering::Relaxed)
+}
+
+/// Waits until the reservoir state differs from the previous reading.
+pub async fn wait_tank_empty_changed() {
+    TANK_EMPTY_CHANGED.wait().await
+}
+
+pub fn soil_moisture_calibration() -> SoilMoistureCalibration {
+    SOIL_MOISTURE_CALIBRATION.lock(|calibration| calibration.get())
+}
+
+pub fn set_soil_moisture_calibration(calibration: SoilMoistureCalibration) {
+    SOIL_MOISTURE_CALIBRATION.lock(|current| current.set(calibration));
+}
+
+/// Latest raw ADC reading of the soil moisture sensor, used for calibration.
+pub fn soil_moisture_raw_value() -> Option<u16> {
+    SOIL_MOISTURE_RAW_VALUE.lock(|raw_value| raw_value.get())
+}
+
+/// Moves one point of the soil moisture calibration to `raw_value`, or to the
+/// latest reading if not given, returning the calibration now in effect.
+pub fn calibrate_soil_moisture(
+    point: CalibrationPoint,
+    raw_value: Option<u16>,
+) -> Result<SoilMoistureCalibration, CalibrationError> {
+    let raw_value = raw_value
+        .or_else(soil_moisture_raw_value)
+        .ok_or(CalibrationError::NoReading)?;
+    let current = soil_moisture_calibration();
+    let calibration = match point {
+        CalibrationPoint::Dry => SoilMoistureCalibration::new(raw_value, current.wet_value),
+        CalibrationPoint::Wet => SoilMoistureCalibration::new(current.dry_value, raw_value),
+    }
+    .ok_or(CalibrationError::DryEqualsWet)?;
+
+    set_soil_moisture_calibration(calibration);
+    Ok(calibration)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalibrationPoint {
+    Dry,
+    Wet,
+}
+
+impl CalibrationPoint {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "dry" => Some(CalibrationPoint::Dry),
+            "wet" => Some(CalibrationPoint::Wet),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalibrationError {
+    NoReading,
+    DryEqualsWet,
+}
+
+impl CalibrationError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CalibrationError::NoReading => "no_reading",
+            CalibrationError::DryEqualsWet => "dry_equals_wet",
+        }
+    }
+}
+
+/// Raw ADC readings of the soil moisture sensor in dry and in saturated soil,
+/// mapped to 0% and 100%.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SoilMoistureCalibration {
+    pub dry_value: u16,
+    pub wet_value: u16,
+}
+
+impl SoilMoistureCalibration {
+    const DEFAULT: SoilMoistureCalibration = SoilMoistureCalibration {
+        dry_value: SOIL_MOISTURE_MIN_VALUE,
+        wet_value: SOIL_MOISTURE_MAX_VALUE,
+    };
+
+    /// Returns `None` if both points are equal, which can't be interpolated.
+    pub fn new(dry_value: u16, wet_value: u16) -> Option<Self> {
+        if dry_value == wet_value {
+            return None;
+        }
+        Some(Self {
+            dry_value,
+            wet_value,
+        })
+    }
+
+    pub fn to_percent(&self, raw_value: u16) -> f32 {
+        let percent = (raw_value as f32 - self.dry_value as f32)
+            / (self.wet_value as f32 - self.dry_value as f32)
+            * 100.0;
+        percent.clamp(0.0, 100.0)
+    }
+}
+
+impl Default for SoilMoistureCalibration {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
 pub struct SensorsValues {
     pub soil_moisture_sensor_value: f32,
     pub temperature: f32,
     pub humidity: f32,
+    pub tank_empty: bool,
+    pub tank_level: Option<f32>,
 }
 
 impl SensorsValues {
@@ -24,26 +169,109 @@ impl SensorsValues {
         soil_moisture_sensor_value: f32,
         temperature: f32,
         humidity: f32,
+        tank_empty: bool,
+        tank_level: Option<f32>,
     ) -> Self {
         SensorsValues {
             soil_moisture_sensor_value,
             temperature,
             humidity,
+            tank_empty,
+            tank_level,
         }
     }
 }
 
+#[derive(Clone, Copy)]
+pub struct TankLevelConfig {
+    /// Distance from the sensor to the water surface when the tank is empty.
+    pub empty_distance_cm: f32,
+    /// Distance from the sensor to the water surface when the tank is full.
+    pub full_distance_cm: f32,
+    /// Level at or below which the tank counts as empty.
+    pub empty_level_percent: f32,
+}
+
+impl TankLevelConfig {
+    pub fn new(empty_distance_cm: f32, full_distance_cm: f32, empty_level_percent: f32) -> Self {
+        Self {
+            empty_distance_cm,
+            full_distance_cm,
+            empty_level_percent,
+        }
+    }
+}
+
+/// HC-SR04 ultrasonic sensor mounted above the water surface.
+#[cfg(target_os = "none")]
+pub struct TankLevelSensor<'lifetime> {
+    _config: TankLevelConfig,
+    _trigger: Output<'lifetime>,
+    _echo: Input<'lifetime>,
+}
+
+#[cfg(target_os = "none")]
+impl<'lifetime> TankLevelSensor<'lifetime> {
+    pub fn new(
+        config: TankLevelConfig,
+        trigger_pin: impl OutputPin + 'lifetime,
+        echo_pin: impl InputPin + 'lifetime,
+    ) -> Self {
+        TankLevelSensor {
+            _config: config,
+            _trigger: Output::new(trigger_pin, Level::Low, OutputConfig::default()),
+            _echo: Input::new(echo_pin, InputConfig::default()),
+        }
+    }
+
+    pub fn config(&self) -> TankLevelConfig {
+        self._config
+    }
+
+    pub async fn read_distance_cm(&mut self) -> Option<f32> {
+        // A 10us trigger pulse starts a measurement
+        self._trigger.set_high();
+        Delay::new().delay_micros(10);
+        self._trigger.set_low();
+
+        with_timeout(ECHO_TIMEOUT, self._echo.wait_for_high()).await.ok()?;
+        let echo_start = Instant::now();
+        with_timeout(ECHO_TIMEOUT, self._echo.wait_for_low()).await.ok()?;
+        let echo_us = (Instant::now() - echo_start).as_micros();
+
+        // Sound travels 1cm there and back in about 58us
+        Some(echo_us as f32 / 58.0)
+    }
+
+    pub async fn read_level_percent(&mut self) -> Option<f32> {
+        let distance_cm = self.read_distance_cm().await?;
+        let range_cm = self._config.empty_distance_cm - self._config.full_distance_cm;
+        if range_cm <= 0.0 {
+            return None;
+        }
+
+        let level = (self._config.empty_distance_cm - distance_cm) / range_cm * 100.0;
+        Some(level.clamp(0.0, 100.0))
+    }
+}
+
+#[cfg(target_os = "none")]
 pub struct SensorsFacade<'lifetime> {
     _soil_moisture_sensor_adc: Adc<'lifetime, ADC1<'static>, Blocking>,
     _soil_moisture_sensor_adc_pin: AdcPin<GPIO35<'static>, ADC1<'static>>,
     _dht22_sensor: Dht22<Flex<'lifetime>, Delay>,
+    _float_switch: Option<Input<'lifetime>>,
+    _tank_level_sensor: Option<TankLevelSensor<'lifetime>>,
 }
 
+#[cfg(target_os = "none")]
 impl<'lifetime> SensorsFacade<'lifetime> {
     pub fn new(
         soil_moisture_sensor_pin_peripheral: GPIO35<'static>,
         soil_moisture_sensor_adc_peripheral: ADC1<'static>,
         dht_pin_peripheral: GPIO33<'static>,
+        float_switch_pin: Option<AnyPin<'static>>,
+        tank_level_sensor: Option<TankLevelSensor<'lifetime>>,
     ) -> Self {
         // Initialize soil moisture sensor
         let mut soil_moisture_sensor_adc_config = AdcConfig::new();
@@ -70,14 +298,37 @@ impl<'lifetime> SensorsFacade<'lifetime> {
         let delay: Delay = Delay::new();
         let dht22_sensor: Dht22<Flex<'_>, Delay> = Dht22::new(dht22_pin, delay);
 
+        // Initialize reservoir float switch. It closes to ground while there is
+        // water, so a broken wire also reads as an empty tank.
+        let float_switch = float_switch_pin
+            .map(|pin| Input::new(pin, InputConfig::default().with_pull(Pull::Up)));
+
+        if float_switch.is_some() || tank_level_sensor.is_some() {
+            TANK_EMPTY.store(true, Ordering::Relaxed);
+        }
+
         SensorsFacade {
             _soil_moisture_sensor_adc: soil_moisture_sensor_adc,
             _soil_moisture_sensor_adc_pin: soil_moisture_sensor_adc_pin,
             _dht22_sensor: dht22_sensor,
+            _float_switch: float_switch,
+            _tank_level_sensor: tank_level_sensor,
         }
     }
 
+    pub fn has_float_switch(&self) -> bool {
+        self._float_switch.is_some()
+    }
+
+    pub fn has_tank_level_sensor(&self) -> bool {
+        self._tank_level_sensor.is_some()
+    }
+
     pub async fn read_values(&mut self) -> SensorsValues {
+        // The reservoir is checked first, and again while another sensor keeps
+        // failing, so the pump lockout never waits for the other readings
+        let (mut tank_empty, mut tank_level) = self.check_tank().await;
+
         let soil_moisture_sensor_value: u16;
         loop {
             info!("Sensors: Reading value");
@@ -91,18 +342,13 @@ impl<'lifetime> SensorsFacade<'lifetime> {
                     warn!("Sensors: Soil Moisture read error: {:?}", e);
                 }
             }
+            (tank_empty, tank_level) = self.check_tank().await;
             Timer::after(Duration::from_millis(100)).await;
         }
 
-        let mut soil_moisture_percent_value: f32 = 
-            (soil_moisture_sensor_value as f32 - SOIL_MOISTURE_MIN_VALUE as f32) 
-            / (SOIL_MOISTURE_MAX_VALUE as f32)
-            * 100.0;
-        if soil_moisture_percent_value < 0.0 {
-            soil_moisture_percent_value = 0.0;
-        } else if soil_moisture_percent_value > 100.0 {
-            soil_moisture_percent_value = 1.0;
-        }
+        SOIL_MOISTURE_RAW_VALUE.lock(|raw_value| raw_value.set(Some(soil_moisture_sensor_value)));
+        let soil_moisture_percent_value =
+            soil_moisture_calibration().to_percent(soil_moisture_sensor_value);
 
         let temperature: f32;
         let humidity: f32;
@@ -119,13 +365,44 @@ impl<'lifetime> SensorsFacade<'lifetime> {
                     warn!("Sensors: DHT22 read error: {:?}", e);
                 }
             }
+            (tank_empty, tank_level) = self.check_tank().await;
             Timer::after(Duration::from_millis(100)).await;
         }
-        
+
         return SensorsValues::new(
             soil_moisture_percent_value,
             temperature,
             humidity,
+            tank_empty,
+            tank_level,
         );
     }
+
+    /// Reads the float switch and the level sensor, updating `is_tank_empty`.
+    /// A level that can't be read counts as an empty tank, so a faulty sensor
+    /// can't let the pump run dry.
+    async fn check_tank(&mut self) -> (bool, Option<f32>) {
+        let float_switch_empty = self._float_switch.as_ref().is_some_and(|input| input.is_high());
+        let mut tank_level: Option<f32> = None;
+        let mut tank_level_empty = false;
+        if let Some(tank_level_sensor) = self._tank_level_sensor.as_mut() {
+            tank_level = tank_level_sensor.read_level_percent().await;
+            match tank_level {
+                Some(level) => {
+                    info!("Sensors: Tank level: {}%", level);
+                    tank_level_empty = level <= tank_level_sensor.config().empty_level_percent;
+                }
+                None => {
+                    warn!("Sensors: Tank level read error, assuming the tank is empty");
+                    tank_level_empty = true;
+                }
+            }
+        }
+
+        let tank_empty = float_switch_empty || tank_level_empty;
+        if TANK_EMPTY.swap(tank_empty, Ordering::Relaxed) != tank_empty {
+            TANK_EMPTY_CHANGED.signal(());
+        }
+        (tank_empty, tank_level)
+    }
 }
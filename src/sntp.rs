@@ -0,0 +1,138 @@
+use embassy_net::dns::DnsQueryType;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::Stack;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use log::{info, warn};
+
+use crate::clock;
+
+const NTP_PORT: u16 = 123;
+const NTP_PACKET_SIZE: usize = 48;
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970).
+const NTP_UNIX_EPOCH_OFFSET: u64 = 2_208_988_800;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum SntpError {
+    DnsFailed,
+    SocketError,
+    Timeout,
+    InvalidResponse,
+}
+
+#[derive(Clone, Copy)]
+pub struct SntpFacadeConfig {
+    pub server: &'static str,
+    pub sync_interval: Duration,
+}
+
+impl SntpFacadeConfig {
+    pub fn new(server: &'static str, sync_interval: Duration) -> Self {
+        Self {
+            server,
+            sync_interval,
+        }
+    }
+
+    /// Uses `SNTP_SERVER` if set, `pool.ntp.org` otherwise.
+    pub fn from_env() -> Self {
+        Self {
+            server: option_env!("SNTP_SERVER").unwrap_or("pool.ntp.org"),
+            sync_interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Keeps the wall clock in `clock` synchronised with an NTP server.
+pub struct SntpFacade<'lifetime> {
+    _config: SntpFacadeConfig,
+    _stack: Stack<'lifetime>,
+}
+
+impl<'lifetime> SntpFacade<'lifetime> {
+    pub fn new(config: SntpFacadeConfig, stack: Stack<'lifetime>) -> Self {
+        Self {
+            _config: config,
+            _stack: stack,
+        }
+    }
+
+    pub async fn run(&mut self) -> ! {
+        loop {
+            self._stack.wait_config_up().await;
+
+            match self.sync().await {
+                Ok(unix_time) => {
+                    info!("SNTP: Clock synchronised, Unix time is {}", unix_time);
+                    Timer::after(self._config.sync_interval).await;
+                }
+                Err(e) => {
+                    warn!("SNTP: Synchronisation with {} failed: {:?}", self._config.server, e);
+                    Timer::after(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Queries the server once and sets the clock, returning the new Unix time.
+    pub async fn sync(&mut self) -> Result<u64, SntpError> {
+        let server = self
+            ._stack
+            .dns_query(self._config.server, DnsQueryType::A)
+            .await
+            .map_err(|_| SntpError::DnsFailed)?
+            .first()
+            .copied()
+            .ok_or(SntpError::DnsFailed)?;
+
+        let mut rx_meta = [PacketMetadata::EMPTY; 1];
+        let mut rx_buffer = [0_u8; NTP_PACKET_SIZE];
+        let mut tx_meta = [PacketMetadata::EMPTY; 1];
+        let mut tx_buffer = [0_u8; NTP_PACKET_SIZE];
+        let mut socket = UdpSocket::new(
+            self._stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        socket.bind(0).map_err(|_| SntpError::SocketError)?;
+
+        // LI = 0, VN = 4, Mode = 3 (client), everything else zeroed
+        let mut packet = [0_u8; NTP_PACKET_SIZE];
+        packet[0] = 0x23;
+        let sent_at = Instant::now();
+        socket
+            .send_to(&packet, (server, NTP_PORT))
+            .await
+            .map_err(|_| SntpError::SocketError)?;
+
+        let (length, _) = with_timeout(RESPONSE_TIMEOUT, socket.recv_from(&mut packet))
+            .await
+            .map_err(|_| SntpError::Timeout)?
+            .map_err(|_| SntpError::SocketError)?;
+        let received_at = Instant::now();
+
+        let mode = packet[0] & 0x07;
+        let stratum = packet[1];
+        if length < NTP_PACKET_SIZE || mode != 4 || stratum == 0 {
+            return Err(SntpError::InvalidResponse);
+        }
+
+        // Transmit timestamp, 32.32 fixed point seconds since 1900
+        let seconds = u32::from_be_bytes([packet[40], packet[41], packet[42], packet[43]]) as u64;
+        let fraction = u32::from_be_bytes([packet[44], packet[45], packet[46], packet[47]]) as u64;
+        let unix_time_ms = seconds
+            .checked_sub(NTP_UNIX_EPOCH_OFFSET)
+            .ok_or(SntpError::InvalidResponse)?
+            * 1000
+            + ((fraction * 1000) >> 32);
+
+        // The server answered roughly halfway through the round trip
+        let round_trip = received_at - sent_at;
+        clock::set_unix_time_ms(unix_time_ms, sent_at + round_trip / 2);
+
+        Ok(unix_time_ms / 1000)
+    }
+}
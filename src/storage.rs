@@ -0,0 +1,105 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::FlashStorage;
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Start of the region used for settings. This is the `nvs` partition of the
+/// default partition table, which is free since the firmware doesn't use ESP-IDF.
+const STORAGE_BASE_OFFSET: u32 = 0x9000;
+const STORAGE_SECTOR_SIZE: u32 = 4096;
+
+const RECORD_MAGIC: u32 = 0x5753_4331;
+const RECORD_HEADER_SIZE: usize = 12;
+const MAX_RECORD_SIZE: usize = 1024;
+
+static FLASH: Mutex<CriticalSectionRawMutex, RefCell<Option<FlashStorage>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Each slot owns one flash sector holding a single JSON record.
+#[derive(Debug, Clone, Copy)]
+pub enum StorageSlot {
+    PumpStatistics = 0,
+    ControllerSettings = 1,
+    Schedules = 2,
+    SoilMoistureCalibration = 3,
+    MqttBroker = 4,
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    SerializationFailed,
+    RecordTooLarge,
+    FlashError,
+}
+
+pub struct StorageFacade;
+
+impl StorageFacade {
+    pub const fn new() -> Self {
+        Self
+    }
+
+    /// Reads the record stored in `slot`, if there is a valid one.
+    pub fn load<T: DeserializeOwned>(&self, slot: StorageSlot) -> Option<T> {
+        let mut buffer = [0_u8; RECORD_HEADER_SIZE + MAX_RECORD_SIZE];
+        let offset = Self::slot_offset(slot);
+
+        Self::with_flash(|flash| flash.read(offset, &mut buffer[..RECORD_HEADER_SIZE])).ok()?;
+        let magic = u32::from_le_bytes(buffer[0..4].try_into().ok()?);
+        let length = u32::from_le_bytes(buffer[4..8].try_into().ok()?) as usize;
+        let checksum = u32::from_le_bytes(buffer[8..12].try_into().ok()?);
+        if magic != RECORD_MAGIC || length > MAX_RECORD_SIZE {
+            return None;
+        }
+
+        let payload = &mut buffer[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + length];
+        Self::with_flash(|flash| flash.read(offset + RECORD_HEADER_SIZE as u32, payload)).ok()?;
+        if Self::checksum(payload) != checksum {
+            warn!("Storage: Corrupted record in slot {:?}, ignoring it", slot);
+            return None;
+        }
+
+        serde_json_core::from_slice(payload).ok().map(|(value, _)| value)
+    }
+
+    pub fn save<T: Serialize>(&self, slot: StorageSlot, value: &T) -> Result<(), StorageError> {
+        let mut buffer = [0_u8; RECORD_HEADER_SIZE + MAX_RECORD_SIZE];
+        let length = serde_json_core::to_slice(value, &mut buffer[RECORD_HEADER_SIZE..])
+            .map_err(|_| StorageError::SerializationFailed)?;
+        if length > MAX_RECORD_SIZE {
+            return Err(StorageError::RecordTooLarge);
+        }
+
+        let checksum = Self::checksum(&buffer[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + length]);
+        buffer[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        buffer[4..8].copy_from_slice(&(length as u32).to_le_bytes());
+        buffer[8..12].copy_from_slice(&checksum.to_le_bytes());
+
+        // `Storage::write` erases the sector as needed
+        Self::with_flash(|flash| {
+            flash.write(Self::slot_offset(slot), &buffer[..RECORD_HEADER_SIZE + length])
+        })
+        .map_err(|_| StorageError::FlashError)
+    }
+
+    fn slot_offset(slot: StorageSlot) -> u32 {
+        STORAGE_BASE_OFFSET + slot as u32 * STORAGE_SECTOR_SIZE
+    }
+
+    fn with_flash<R>(f: impl FnOnce(&mut FlashStorage) -> R) -> R {
+        FLASH.lock(|flash| {
+            let mut flash = flash.borrow_mut();
+            f(flash.get_or_insert_with(FlashStorage::new))
+        })
+    }
+
+    /// FNV-1a, enough to tell a valid record from erased or torn flash.
+    fn checksum(data: &[u8]) -> u32 {
+        data.iter().fold(0x811c_9dc5, |hash, byte| {
+            (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+        })
+    }
+}
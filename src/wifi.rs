@@ -68,6 +68,11 @@ impl <'lifetime> WiFiFacade<'lifetime> {
         return (facade, stack, runner)
     }
 
+    /// Signal strength of the access point, in dBm.
+    pub fn rssi(&self) -> Option<i32> {
+        self._wifi_controller.rssi().ok()
+    }
+
     pub async fn connect(&mut self) -> Result<(), WiFiError> {
         self.configure().unwrap();
         self.connect_to_wifi().await.unwrap();
@@ -0,0 +1,445 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::OutputPin;
+use heapless::Vec;
+
+use crate::output::{OutputPolarity, SwitchedOutput};
+use crate::pump::{PumpCommand, PumpFacade, PumpRejection, PumpStopReason};
+
+pub const MAX_ZONES: usize = 4;
+
+/// Commands for the zones coming from on-device logic rather than MQTT. They go
+/// through the same `ZoneManager` so every safety limit still applies.
+pub static ZONE_REQUESTS: Channel<CriticalSectionRawMutex, ZoneRequest, 4> = Channel::new();
+
+/// Raised to close every zone, e.g. when watering is switched off.
+pub static STOP_ALL_ZONES: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Time given to a valve to open before the pump starts, and to the pump to spin
+/// down before a valve closes, so the pump never pushes against a closed line.
+const VALVE_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZoneError {
+    UnknownZone,
+    TooManyActiveZones,
+    Pump(PumpRejection),
+}
+
+impl ZoneError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ZoneError::UnknownZone => "unknown_zone",
+            ZoneError::TooManyActiveZones => "too_many_active_zones",
+            ZoneError::Pump(rejection) => rejection.as_str(),
+        }
+    }
+}
+
+/// State of a zone valve, including the time it takes to settle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZoneState {
+    Opening,
+    Open,
+    Closing,
+    Closed,
+}
+
+impl ZoneState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ZoneState::Opening => "opening",
+            ZoneState::Open => "open",
+            ZoneState::Closing => "closing",
+            ZoneState::Closed => "closed",
+        }
+    }
+
+    /// Whether water is still let through, which is the case while closing.
+    pub fn is_open(&self) -> bool {
+        matches!(self, ZoneState::Open | ZoneState::Closing)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZoneRequest {
+    pub zone: usize,
+    pub command: PumpCommand,
+}
+
+impl ZoneRequest {
+    pub fn new(zone: usize, command: PumpCommand) -> Self {
+        Self { zone, command }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZoneEvent {
+    RunCompleted(usize),
+    PumpStopped(PumpStopReason),
+}
+
+#[derive(Clone, Copy)]
+pub struct ZoneManagerConfig {
+    pub max_active_zones: usize,
+}
+
+impl ZoneManagerConfig {
+    pub fn new(max_active_zones: usize) -> Self {
+        Self { max_active_zones }
+    }
+
+    /// Reads `MAX_ACTIVE_ZONES`, one zone at a time by default so the pump
+    /// pressure isn't shared.
+    pub fn from_env() -> Self {
+        Self::new(
+            option_env!("MAX_ACTIVE_ZONES")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(1),
+        )
+    }
+}
+
+pub struct Valve<V: OutputPin> {
+    _valve_output: SwitchedOutput<V>,
+}
+
+impl<V: OutputPin> Valve<V> {
+    /// Wraps `valve_pin`, closing the valve right away.
+    pub fn new(valve_pin: V, polarity: OutputPolarity) -> Self {
+        Valve {
+            _valve_output: SwitchedOutput::new(valve_pin, polarity),
+        }
+    }
+
+    pub fn open(&mut self) {
+        self._valve_output.activate();
+    }
+
+    pub fn close(&mut self) {
+        self._valve_output.deactivate();
+    }
+}
+
+struct Zone<V: OutputPin> {
+    _valve: Valve<V>,
+    _is_on: bool,
+    _run_until: Option<Instant>,
+}
+
+/// Drives one pump feeding several beds through solenoid valves. Valves are
+/// always opened before the pump starts and the pump is always stopped before
+/// the last open valve closes.
+pub struct ZoneManager<P: OutputPin, V: OutputPin> {
+    _config: ZoneManagerConfig,
+    _pump: PumpFacade<P>,
+    _zones: Vec<Zone<V>, MAX_ZONES>,
+}
+
+impl<P: OutputPin, V: OutputPin> ZoneManager<P, V> {
+    pub fn new(
+        config: ZoneManagerConfig,
+        pump: PumpFacade<P>,
+        valves: Vec<Valve<V>, MAX_ZONES>,
+    ) -> Self {
+        let mut zones = Vec::new();
+        for valve in valves {
+            let _ = zones.push(Zone {
+                _valve: valve,
+                _is_on: false,
+                _run_until: None,
+            });
+        }
+
+        ZoneManager {
+            _config: config,
+            _pump: pump,
+            _zones: zones,
+        }
+    }
+
+    pub fn zone_count(&self) -> usize {
+        self._zones.len()
+    }
+
+    pub fn is_zone_on(&self, zone: usize) -> bool {
+        self._zones.get(zone).is_some_and(|zone| zone._is_on)
+    }
+
+    /// `Open` or `Closed`: the valves settle while `start_zone` and `stop_zone`
+    /// are running.
+    pub fn zone_state(&self, zone: usize) -> ZoneState {
+        if self.is_zone_on(zone) {
+            ZoneState::Open
+        } else {
+            ZoneState::Closed
+        }
+    }
+
+    /// Time left before `zone` is closed, by its timed run or by the pump
+    /// limits. `None` if the zone is closed.
+    pub fn remaining_run_time(&self, zone: usize) -> Option<Duration> {
+        let zone_entry = self._zones.get(zone).filter(|zone| zone._is_on)?;
+        let run_until = zone_entry._run_until.into_iter().chain(self._pump.cutoff()).min()?;
+        Some(run_until.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn active_zones(&self) -> usize {
+        self._zones.iter().filter(|zone| zone._is_on).count()
+    }
+
+    pub fn pump(&self) -> &PumpFacade<P> {
+        &self._pump
+    }
+
+    /// Opens `zone` and starts the pump, optionally stopping again after `duration`
+    /// or once `volume_l` litres went through the flow meter. The flow meter sits
+    /// on the shared pump line, so reaching the volume stops every open zone.
+    /// Starting a zone that is already running only updates its limits.
+    pub async fn start_zone(
+        &mut self,
+        zone: usize,
+        duration: Option<Duration>,
+        volume_l: Option<f32>,
+    ) -> Result<(), ZoneError> {
+        let run_until = duration.map(|duration| Instant::now() + duration);
+        let active_zones = self.active_zones();
+        let max_active_zones = self._config.max_active_zones;
+        let zone_entry = self._zones.get_mut(zone).ok_or(ZoneError::UnknownZone)?;
+        let was_on = zone_entry._is_on;
+
+        if !was_on {
+            if active_zones >= max_active_zones {
+                return Err(ZoneError::TooManyActiveZones);
+            }
+            if volume_l.is_some() && self._pump.flow_meter().is_none() {
+                return Err(ZoneError::Pump(PumpRejection::NoFlowMeter));
+            }
+
+            zone_entry._valve.open();
+            if !self._pump.is_on() {
+                Timer::after(VALVE_SETTLE_TIME).await;
+            }
+        }
+
+        let result = match volume_l {
+            Some(volume_l) => self._pump.turn_on_for_volume(volume_l),
+            None if self._pump.is_on() => Ok(()),
+            None => self._pump.turn_on(),
+        };
+        if let Err(rejection) = result {
+            if !was_on {
+                self._zones[zone]._valve.close();
+            }
+            return Err(ZoneError::Pump(rejection));
+        }
+
+        let zone_entry = &mut self._zones[zone];
+        zone_entry._is_on = true;
+        zone_entry._run_until = run_until;
+        Ok(())
+    }
+
+    pub async fn stop_zone(&mut self, zone: usize) -> Result<(), ZoneError> {
+        let zone_entry = self._zones.get(zone).ok_or(ZoneError::UnknownZone)?;
+        if !zone_entry._is_on {
+            return Ok(());
+        }
+
+        if self.active_zones() == 1 && self._pump.is_on() {
+            self._pump.turn_off();
+            Timer::after(VALVE_SETTLE_TIME).await;
+        }
+
+        let zone_entry = &mut self._zones[zone];
+        zone_entry._valve.close();
+        zone_entry._is_on = false;
+        zone_entry._run_until = None;
+        Ok(())
+    }
+
+    pub async fn stop_all(&mut self) {
+        if self._pump.is_on() {
+            self._pump.turn_off();
+            Timer::after(VALVE_SETTLE_TIME).await;
+        }
+
+        for zone in self._zones.iter_mut() {
+            zone._valve.close();
+            zone._is_on = false;
+            zone._run_until = None;
+        }
+    }
+
+    /// Forwards the reservoir state to the pump, closing every zone if the pump had
+    /// to be stopped because the tank ran dry.
+    pub async fn set_tank_empty(&mut self, tank_empty: bool) -> Option<ZoneEvent> {
+        let reason = self._pump.set_tank_empty(tank_empty)?;
+        Timer::after(VALVE_SETTLE_TIME).await;
+        self.stop_all().await;
+        Some(ZoneEvent::PumpStopped(reason))
+    }
+
+    /// Earliest instant at which `check_deadlines` has to be called.
+    pub fn deadline(&self) -> Option<Instant> {
+        self._zones
+            .iter()
+            .filter_map(|zone| zone._run_until)
+            .chain(self._pump.deadline())
+            .min()
+    }
+
+    /// Stops whatever is due: every zone when the pump hit one of its own limits,
+    /// otherwise the first zone whose timed run is over. Call it until it returns
+    /// `None` to handle every pending event.
+    pub async fn check_deadlines(&mut self) -> Option<ZoneEvent> {
+        if let Some(reason) = self._pump.check_deadline() {
+            Timer::after(VALVE_SETTLE_TIME).await;
+            self.stop_all().await;
+            return Some(ZoneEvent::PumpStopped(reason));
+        }
+
+        let now = Instant::now();
+        let zone = self._zones.iter().position(|zone| {
+            zone._run_until.is_some_and(|run_until| now >= run_until)
+        })?;
+        self.stop_zone(zone).await.ok()?;
+        Some(ZoneEvent::RunCompleted(zone))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+
+    use embassy_time::MockDriver;
+    use embedded_hal::digital::PinState;
+
+    use super::*;
+    use crate::output::tests::MockPin;
+    use crate::pump::PumpBudgetConfig;
+
+    const ACTIVE: Option<PinState> = Some(PinState::High);
+    const INACTIVE: Option<PinState> = Some(PinState::Low);
+
+    fn zone_manager<'a>(
+        max_active_zones: usize,
+        pump_level: &'a Cell<Option<PinState>>,
+        valve_levels: &'a [Cell<Option<PinState>>],
+    ) -> ZoneManager<MockPin<'a>, MockPin<'a>> {
+        let polarity = OutputPolarity::ActiveHigh;
+        let pump = PumpFacade::new(MockPin(pump_level), polarity, PumpBudgetConfig::default(), None, 5.0);
+        let mut valves = Vec::new();
+        for level in valve_levels {
+            let _ = valves.push(Valve::new(MockPin(level), polarity));
+        }
+        ZoneManager::new(ZoneManagerConfig::new(max_active_zones), pump, valves)
+    }
+
+    fn poll<F: Future>(future: core::pin::Pin<&mut F>) -> Poll<F::Output> {
+        future.poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    /// Polls `future` to completion, letting the valves settle whenever it waits.
+    fn run<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        loop {
+            if let Poll::Ready(output) = poll(future.as_mut()) {
+                return output;
+            }
+            MockDriver::get().advance(VALVE_SETTLE_TIME);
+        }
+    }
+
+    #[test]
+    fn valve_opens_before_the_pump_starts() {
+        let pump_level = Cell::new(None);
+        let valve_levels = [Cell::new(None), Cell::new(None)];
+        let mut zone_manager = zone_manager(1, &pump_level, &valve_levels);
+
+        let mut start = pin!(zone_manager.start_zone(0, None, None));
+        assert!(poll(start.as_mut()).is_pending());
+        assert_eq!(valve_levels[0].get(), ACTIVE);
+        assert_eq!(pump_level.get(), INACTIVE);
+
+        MockDriver::get().advance(VALVE_SETTLE_TIME);
+        assert_eq!(poll(start.as_mut()), Poll::Ready(Ok(())));
+        assert_eq!(pump_level.get(), ACTIVE);
+        assert_eq!(valve_levels[1].get(), INACTIVE);
+    }
+
+    #[test]
+    fn pump_stops_before_the_last_valve_closes() {
+        let pump_level = Cell::new(None);
+        let valve_levels = [Cell::new(None), Cell::new(None)];
+        let mut zone_manager = zone_manager(2, &pump_level, &valve_levels);
+        assert_eq!(run(zone_manager.start_zone(0, None, None)), Ok(()));
+        assert_eq!(run(zone_manager.start_zone(1, None, None)), Ok(()));
+
+        // Another zone is still open, so the pump keeps running
+        assert_eq!(run(zone_manager.stop_zone(0)), Ok(()));
+        assert_eq!(valve_levels[0].get(), INACTIVE);
+        assert_eq!(pump_level.get(), ACTIVE);
+
+        let mut stop = pin!(zone_manager.stop_zone(1));
+        assert!(poll(stop.as_mut()).is_pending());
+        assert_eq!(pump_level.get(), INACTIVE);
+        assert_eq!(valve_levels[1].get(), ACTIVE);
+
+        MockDriver::get().advance(VALVE_SETTLE_TIME);
+        assert_eq!(poll(stop.as_mut()), Poll::Ready(Ok(())));
+        assert_eq!(valve_levels[1].get(), INACTIVE);
+    }
+
+    #[test]
+    fn zones_beyond_the_active_limit_are_refused() {
+        let pump_level = Cell::new(None);
+        let valve_levels = [Cell::new(None), Cell::new(None)];
+        let mut zone_manager = zone_manager(1, &pump_level, &valve_levels);
+
+        assert_eq!(run(zone_manager.start_zone(0, None, None)), Ok(()));
+        assert_eq!(
+            run(zone_manager.start_zone(1, None, None)),
+            Err(ZoneError::TooManyActiveZones)
+        );
+        assert_eq!(valve_levels[1].get(), INACTIVE);
+        assert!(!zone_manager.is_zone_on(1));
+        // Starting the open zone again only updates its limits
+        assert_eq!(run(zone_manager.start_zone(0, Some(Duration::from_secs(10)), None)), Ok(()));
+        assert_eq!(run(zone_manager.start_zone(4, None, None)), Err(ZoneError::UnknownZone));
+    }
+
+    #[test]
+    fn valve_closes_again_when_the_pump_refuses_to_start() {
+        let pump_level = Cell::new(None);
+        let valve_levels = [Cell::new(None)];
+        let mut zone_manager = zone_manager(1, &pump_level, &valve_levels);
+        assert_eq!(run(zone_manager.set_tank_empty(true)), None);
+
+        assert_eq!(
+            run(zone_manager.start_zone(0, None, None)),
+            Err(ZoneError::Pump(PumpRejection::TankEmpty))
+        );
+        assert_eq!(valve_levels[0].get(), INACTIVE);
+        assert_eq!(pump_level.get(), INACTIVE);
+        assert_eq!(zone_manager.active_zones(), 0);
+    }
+
+    #[test]
+    fn timed_run_closes_its_zone() {
+        let pump_level = Cell::new(None);
+        let valve_levels = [Cell::new(None)];
+        let mut zone_manager = zone_manager(1, &pump_level, &valve_levels);
+
+        assert_eq!(run(zone_manager.start_zone(0, Some(Duration::from_secs(30)), None)), Ok(()));
+        assert_eq!(run(zone_manager.check_deadlines()), None);
+
+        MockDriver::get().advance(Duration::from_secs(30));
+        assert_eq!(run(zone_manager.check_deadlines()), Some(ZoneEvent::RunCompleted(0)));
+        assert_eq!(pump_level.get(), INACTIVE);
+        assert_eq!(valve_levels[0].get(), INACTIVE);
+    }
+}